@@ -0,0 +1,532 @@
+use crate::cluster;
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+
+/// Client for Core Lightning's `clnrest` plugin, authenticated with a rune.
+#[derive(Clone)]
+pub struct ClnClient {
+    pub host: String,
+    pub cert_path: String,
+    pub rune: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OfferClnRequest {
+    /// `any` or an amount such as `1000msat`.
+    pub amount: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub single_use: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OfferResponse {
+    pub offer_id: String,
+    pub active: bool,
+    pub single_use: bool,
+    pub bolt12: String,
+    pub used: bool,
+    /// Only returned by `offer`.
+    #[serde(default)]
+    pub created: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListOffersClnRequest {
+    pub offer_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListOffersResponse {
+    pub offers: Vec<OfferResponse>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListInvoicesClnRequest {
+    pub offer_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListInvoicesResponse {
+    pub invoices: Vec<ClnInvoice>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClnInvoice {
+    pub payment_hash: String,
+    pub status: String,
+    pub amount_received_msat: Option<u64>,
+    pub paid_at: Option<u64>,
+    pub local_offer_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvoiceClnRequest {
+    /// `any` or an amount in millisatoshis.
+    pub amount_msat: serde_json::Value,
+    pub label: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cltv: Option<u64>,
+    pub exposeprivatechannels: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InvoiceResponse {
+    pub payment_hash: String,
+    pub bolt11: String,
+    pub payment_secret: String,
+    #[serde(default)]
+    pub created_index: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchInvoiceClnRequest {
+    pub offer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FetchInvoiceResponse {
+    pub invoice: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DecodeClnRequest {
+    pub string: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DecodeResponse {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub amount_msat: Option<u64>,
+    pub invoice_amount_msat: Option<u64>,
+    #[serde(default)]
+    pub offer_issuer_id: Option<String>,
+    /// Set instead of `offer_issuer_id` by older releases.
+    #[serde(default)]
+    pub offer_node_id: Option<String>,
+}
+
+impl DecodeResponse {
+    /// Amount of a decoded BOLT11 or BOLT12 invoice, zero when unset.
+    pub fn amount_msat(&self) -> u64 {
+        self.invoice_amount_msat
+            .or(self.amount_msat)
+            .unwrap_or_default()
+    }
+
+    pub fn issuer_id(&self) -> Option<String> {
+        self.offer_issuer_id.clone().or(self.offer_node_id.clone())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PayClnRequest {
+    pub bolt11: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxfeepercent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exemptfee: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PayResponse {
+    /// Empty or missing until the payment completes.
+    #[serde(default)]
+    pub payment_preimage: String,
+    pub payment_hash: String,
+    pub amount_msat: u64,
+    pub amount_sent_msat: u64,
+    pub status: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClnError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl OfferResponse {
+    pub fn to_cluster(self, pubkey: &str) -> cluster::ClusterOffer {
+        cluster::ClusterOffer {
+            pubkey: pubkey.to_string(),
+            offer_id: self.offer_id,
+            bolt12: self.bolt12,
+            active: self.active,
+            single_use: self.single_use,
+        }
+    }
+}
+
+impl InvoiceClnRequest {
+    /// Maps an invoice request onto `invoice`. CLN only commits to a
+    /// description it is given, so `description_hash` can't be honored, and
+    /// it has no AMP invoices.
+    pub fn from_cluster(
+        req: cluster::ClusterAddInvoice,
+        label: String,
+    ) -> Result<InvoiceClnRequest> {
+        if req.description_hash.is_some() {
+            return Err(anyhow::anyhow!(
+                "description_hash is not supported on CLN nodes"
+            ));
+        }
+        if req.is_amp {
+            return Err(anyhow::anyhow!(
+                "AMP invoices are not supported on CLN nodes"
+            ));
+        }
+
+        let amount_msat = match req.value_msat.unwrap_or(req.value * 1000) {
+            0 => serde_json::Value::from("any"),
+            msat => serde_json::Value::from(msat),
+        };
+
+        Ok(InvoiceClnRequest {
+            amount_msat,
+            label,
+            description: req.memo,
+            expiry: (req.expiry > 0).then_some(req.expiry as u64),
+            preimage: req.r_preimage,
+            cltv: req.cltv_expiry,
+            exposeprivatechannels: req.private,
+            fallbacks: req.fallback_addr.into_iter().collect(),
+        })
+    }
+}
+
+impl InvoiceResponse {
+    pub fn to_cluster(self) -> crate::lnd::AddInvoiceResponse {
+        crate::lnd::AddInvoiceResponse {
+            r_hash: self.payment_hash,
+            payment_request: self.bolt11,
+            add_index: self.created_index.to_string(),
+            payment_addr: self.payment_secret,
+        }
+    }
+}
+
+impl ListInvoicesResponse {
+    /// The paid invoices, as payments against the offer they were issued
+    /// for.
+    pub fn to_offer_payments(self, pubkey: &str) -> Vec<cluster::ClusterOfferPayment> {
+        self.invoices
+            .into_iter()
+            .filter(|invoice| invoice.status == "paid")
+            .filter_map(|invoice| {
+                Some(cluster::ClusterOfferPayment {
+                    pubkey: pubkey.to_string(),
+                    offer_id: invoice.local_offer_id?,
+                    payment_hash: invoice.payment_hash,
+                    amount_msat: invoice.amount_received_msat.unwrap_or_default(),
+                    paid_at: invoice.paid_at.unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl PayResponse {
+    /// A `pending` payment has neither an error nor a preimage, as it may
+    /// still complete.
+    pub fn to_cluster(self, pubkey: String) -> cluster::ClusterPayPaymentRequestRes {
+        let payment_error = match self.status.as_str() {
+            "complete" | "pending" => None,
+            status => Some(format!("payment {}", status)),
+        };
+        let payment_preimage = Some(self.payment_preimage).filter(|preimage| !preimage.is_empty());
+
+        cluster::ClusterPayPaymentRequestRes {
+            pubkey,
+            payment_error,
+            payment_preimage,
+            payment_route: None,
+            payment_hash: Some(self.payment_hash),
+        }
+    }
+}
+
+impl ClnClient {
+    pub fn new(host: String, cert_path: String, rune: String) -> ClnClient {
+        Self {
+            host,
+            cert_path,
+            rune,
+        }
+    }
+
+    pub async fn offer(&self, req: OfferClnRequest) -> Result<OfferResponse> {
+        let url = format!("{}/v1/offer", self.host);
+        let response = ClnClient::post(self, &url, &req).await?;
+
+        ClnClient::parse(response).await
+    }
+
+    pub async fn list_offers(&self, offer_id: &str) -> Result<ListOffersResponse> {
+        let url = format!("{}/v1/listoffers", self.host);
+        let req = ListOffersClnRequest {
+            offer_id: offer_id.to_string(),
+        };
+        let response = ClnClient::post(self, &url, &req).await?;
+
+        ClnClient::parse(response).await
+    }
+
+    /// Invoices the node issued in response to requests against the offer.
+    pub async fn list_offer_invoices(&self, offer_id: &str) -> Result<ListInvoicesResponse> {
+        let url = format!("{}/v1/listinvoices", self.host);
+        let req = ListInvoicesClnRequest {
+            offer_id: offer_id.to_string(),
+        };
+        let response = ClnClient::post(self, &url, &req).await?;
+
+        ClnClient::parse(response).await
+    }
+
+    pub async fn invoice(&self, req: InvoiceClnRequest) -> Result<InvoiceResponse> {
+        let url = format!("{}/v1/invoice", self.host);
+        let response = ClnClient::post(self, &url, &req).await?;
+
+        ClnClient::parse(response).await
+    }
+
+    pub async fn fetch_invoice(&self, req: FetchInvoiceClnRequest) -> Result<FetchInvoiceResponse> {
+        let url = format!("{}/v1/fetchinvoice", self.host);
+        let response = ClnClient::post(self, &url, &req).await?;
+
+        ClnClient::parse(response).await
+    }
+
+    pub async fn decode(&self, string: &str) -> Result<DecodeResponse> {
+        let url = format!("{}/v1/decode", self.host);
+        let req = DecodeClnRequest {
+            string: string.to_string(),
+        };
+        let response = ClnClient::post(self, &url, &req).await?;
+
+        ClnClient::parse(response).await
+    }
+
+    /// Pays a BOLT11 or BOLT12 invoice. Errors reported by `pay` are
+    /// returned as `Ok(Err(..))` so callers can surface them as payment
+    /// errors rather than request failures.
+    pub async fn pay(
+        &self,
+        req: PayClnRequest,
+    ) -> Result<std::result::Result<PayResponse, ClnError>> {
+        let url = format!("{}/v1/pay", self.host);
+        let response = ClnClient::post(self, &url, &req).await?;
+
+        if response.status().is_success() {
+            return Ok(Ok(ClnClient::parse(response).await?));
+        }
+
+        let error = response
+            .json::<ClnError>()
+            .await
+            .context("Failed to parse JSON response from CLN API")?;
+        Ok(Err(error))
+    }
+
+    async fn parse<T: serde::de::DeserializeOwned>(response: Response) -> Result<T> {
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("CLN request failed ({}): {}", status, body));
+        }
+
+        response
+            .json::<T>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from CLN API")
+    }
+
+    async fn post<T: serde::Serialize>(&self, url: &str, body: &T) -> Result<Response> {
+        let mut headers = HeaderMap::new();
+        headers.insert("Rune", HeaderValue::from_str(&self.rune)?);
+
+        let mut buf = Vec::new();
+        fs::File::open(&self.cert_path)?.read_to_end(&mut buf)?;
+        let cert = reqwest::Certificate::from_pem(&buf)?;
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .add_root_certificate(cert)
+            .build()?;
+
+        let resp = client.post(url).json(body).send().await?;
+
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DecodeResponse, InvoiceClnRequest, InvoiceResponse, ListInvoicesResponse,
+        ListOffersResponse, PayResponse,
+    };
+    use crate::cluster::ClusterAddInvoice;
+
+    #[test]
+    fn test_pay_response_to_cluster() {
+        let res: PayResponse = serde_json::from_str(
+            r#"{
+                "payment_preimage": "0707070707070707070707070707070707070707070707070707070707070707",
+                "payment_hash": "b1fd4e7e5e0e1f6d0e6b6c4c0f7b9d1e2f3a4b5c6d7e8f90a1b2c3d4e5f60718",
+                "amount_msat": 100000,
+                "amount_sent_msat": 100010,
+                "status": "complete"
+            }"#,
+        )
+        .unwrap();
+        let payment = res.to_cluster("02ab".to_string());
+        assert_eq!(payment.pubkey, "02ab");
+        assert!(payment.payment_error.is_none());
+
+        let res: PayResponse = serde_json::from_str(
+            r#"{
+                "payment_preimage": "",
+                "payment_hash": "b1fd4e7e5e0e1f6d0e6b6c4c0f7b9d1e2f3a4b5c6d7e8f90a1b2c3d4e5f60718",
+                "amount_msat": 100000,
+                "amount_sent_msat": 100010,
+                "status": "pending"
+            }"#,
+        )
+        .unwrap();
+        let payment = res.to_cluster("02ab".to_string());
+        assert!(payment.payment_error.is_none());
+        assert!(payment.payment_preimage.is_none());
+
+        let res: PayResponse = serde_json::from_str(
+            r#"{
+                "payment_hash": "b1fd4e7e5e0e1f6d0e6b6c4c0f7b9d1e2f3a4b5c6d7e8f90a1b2c3d4e5f60718",
+                "amount_msat": 100000,
+                "amount_sent_msat": 0,
+                "status": "failed"
+            }"#,
+        )
+        .unwrap();
+        let payment = res.to_cluster("02ab".to_string());
+        assert_eq!(payment.payment_error.as_deref(), Some("payment failed"));
+        assert!(payment.payment_preimage.is_none());
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let offer: DecodeResponse = serde_json::from_str(
+            r#"{"type": "bolt12 offer", "offer_amount_msat": 5000, "offer_issuer_id": "02ab"}"#,
+        )
+        .unwrap();
+        assert_eq!(offer.issuer_id().as_deref(), Some("02ab"));
+
+        let invoice: DecodeResponse = serde_json::from_str(
+            r#"{"type": "bolt12 invoice", "invoice_amount_msat": 5000, "offer_node_id": "02cd"}"#,
+        )
+        .unwrap();
+        assert_eq!(invoice.amount_msat(), 5000);
+        assert_eq!(invoice.issuer_id().as_deref(), Some("02cd"));
+    }
+
+    #[test]
+    fn test_list_offers_and_invoices() {
+        let offers: ListOffersResponse = serde_json::from_str(
+            r#"{"offers": [{
+                "offer_id": "aa11",
+                "active": true,
+                "single_use": false,
+                "bolt12": "lno1qgsq",
+                "used": true
+            }]}"#,
+        )
+        .unwrap();
+        let offer = offers.offers.into_iter().next().unwrap().to_cluster("02ab");
+        assert_eq!(offer.pubkey, "02ab");
+        assert_eq!(offer.offer_id, "aa11");
+
+        let invoices: ListInvoicesResponse = serde_json::from_str(
+            r#"{"invoices": [
+                {"payment_hash": "01", "status": "paid", "amount_received_msat": 5000,
+                 "paid_at": 1700000000, "local_offer_id": "aa11"},
+                {"payment_hash": "02", "status": "unpaid", "local_offer_id": "aa11"},
+                {"payment_hash": "03", "status": "paid", "amount_received_msat": 7000,
+                 "paid_at": 1700000100}
+            ]}"#,
+        )
+        .unwrap();
+        let payments = invoices.to_offer_payments("02ab");
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].pubkey, "02ab");
+        assert_eq!(payments[0].payment_hash, "01");
+        assert_eq!(payments[0].amount_msat, 5000);
+    }
+
+    #[test]
+    fn test_invoice_request_from_cluster() {
+        let req = ClusterAddInvoice {
+            memo: String::from("coffee"),
+            value: 2,
+            expiry: 600,
+            cltv_expiry: Some(80),
+            private: true,
+            ..Default::default()
+        };
+        let body =
+            serde_json::to_value(InvoiceClnRequest::from_cluster(req, "l1".to_string()).unwrap())
+                .unwrap();
+        assert_eq!(body["amount_msat"], 2000);
+        assert_eq!(body["label"], "l1");
+        assert_eq!(body["description"], "coffee");
+        assert_eq!(body["expiry"], 600);
+        assert_eq!(body["cltv"], 80);
+        assert_eq!(body["exposeprivatechannels"], true);
+        assert!(body.get("preimage").is_none());
+        assert!(body.get("fallbacks").is_none());
+
+        let amountless = ClusterAddInvoice::default();
+        let body = serde_json::to_value(
+            InvoiceClnRequest::from_cluster(amountless, "l2".to_string()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(body["amount_msat"], "any");
+        assert!(body.get("expiry").is_none());
+
+        let hashed = ClusterAddInvoice {
+            description_hash: Some("ab".repeat(32)),
+            ..Default::default()
+        };
+        assert!(InvoiceClnRequest::from_cluster(hashed, "l3".to_string()).is_err());
+
+        let amp = ClusterAddInvoice {
+            is_amp: true,
+            ..Default::default()
+        };
+        assert!(InvoiceClnRequest::from_cluster(amp, "l4".to_string()).is_err());
+
+        let res: InvoiceResponse = serde_json::from_str(
+            r#"{"payment_hash": "ab", "bolt11": "lnbc1", "payment_secret": "cd", "expires_at": 1, "created_index": 7}"#,
+        )
+        .unwrap();
+        let invoice = res.to_cluster();
+        assert_eq!(invoice.r_hash, "ab");
+        assert_eq!(invoice.payment_addr, "cd");
+        assert_eq!(invoice.add_index, "7");
+    }
+}
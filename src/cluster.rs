@@ -1,11 +1,41 @@
+use crate::cln::{
+    ClnClient, FetchInvoiceClnRequest, InvoiceClnRequest, OfferClnRequest, PayClnRequest,
+};
+use crate::consolidation::{
+    plan_consolidation, ConsolidationPlan, ConsolidationPolicy, ConsolidationReport, SweepPlan,
+};
+use crate::deposits::{self, DepositWatcher};
+use crate::destinations::{self, DestinationPolicy, DestinationRejection};
+use crate::fees::{ClnFeeLimit, FeePolicy};
+use crate::limits::{SpendLimitError, SpendLimits, SpendReservation, SpendScope, SpendStatus};
 use crate::lnd::Route;
-use crate::lnd::{AddInvoiceResponse, FeeLimit, LndClient, LndSendPaymentSyncReq};
+use crate::lnd::{
+    txid_from_bytes, AddInvoiceResponse, ChannelStatusUpdate, ConnectPeerLndRequest,
+    FundPsbtLndRequest, LightningAddress, ListInvoicesLndRequest, LndClient, LndOutPoint,
+    LndSendPaymentSyncReq, LndStream, OpenChannelLndRequest, PublishOutcome,
+    PublishTransactionLndRequest, ReleaseOutputLndRequest, SendCoinsLndRequest, SendManyLndRequest,
+    TxTemplate,
+};
+use crate::lnurl::LnurlPayClient;
+use crate::onchain_batch::OnchainBatcher;
+use crate::rebalance::{
+    self, plan_circular_rebalances, plan_rebalances, AutoRebalancePolicy, CircularRebalancePlan,
+    CircularRebalancePolicy, RebalanceError, RebalancePlan,
+};
+use crate::webhook;
 use anyhow::Result;
+use lightning_invoice::Bolt11Invoice;
 use redis::aio::Connection;
 use core::fmt;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
 extern crate redis;
 use redis::{AsyncCommands, FromRedisValue};
 
@@ -15,6 +45,16 @@ pub struct Cluster {
     pub inv_exp_sec: i64,
     pub addr_exp_sec: i64,
     pub utxo_exp_sec: i64,
+    /// How long channel listings are cached, 60 seconds by default.
+    pub channel_exp_sec: i64,
+    pub events: Option<ClusterEventSender>,
+    /// Pay invoices from the node with the cheapest route instead of a
+    /// random node when no pubkey is given.
+    pub route_by_fee: bool,
+    pub spend_limits: SpendLimits,
+    pub destination_policy: DestinationPolicy,
+    /// Default fee limit for payments, 1% with a 10 sat floor.
+    pub fee_policy: FeePolicy,
 }
 
 #[derive(Clone)]
@@ -30,7 +70,7 @@ pub struct Node {
 #[derive(Clone)]
 pub enum NodeClient {
     Lnd(LndClient),
-    CLightning,
+    CLightning(ClnClient),
     Eclair,
     Other,
 }
@@ -48,12 +88,118 @@ pub enum NodeLightningImpl {
     Other,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct ClusterAddInvoice {
     pub pubkey: Option<String>,
     pub memo: String,
     pub value: i64,
     pub expiry: i64,
+    pub callback_url: Option<String>,
+    /// Amount in millisatoshis, mutually exclusive with `value`.
+    pub value_msat: Option<i64>,
+    /// Hex encoded SHA256 of the description, used instead of `memo`.
+    pub description_hash: Option<String>,
+    /// Include route hints for private channels.
+    pub private: bool,
+    pub cltv_expiry: Option<u64>,
+    pub is_amp: bool,
+    /// Hex encoded preimage, generated by the node when not set.
+    pub r_preimage: Option<String>,
+    pub fallback_addr: Option<String>,
+}
+
+impl ClusterAddInvoice {
+    /// Checks the options that every backend must agree on before the
+    /// request is dispatched to a node. CLN nodes additionally reject
+    /// `description_hash` and `is_amp`.
+    pub fn validate(&self) -> Result<()> {
+        if self.value < 0 || self.value_msat.unwrap_or(0) < 0 {
+            return Err(anyhow::anyhow!("Invoice amount can not be negative"));
+        }
+
+        if !self.memo.is_empty() && self.description_hash.is_some() {
+            return Err(anyhow::anyhow!(
+                "Set either memo or description_hash, not both"
+            ));
+        }
+
+        if self.value != 0 && self.value_msat.is_some() {
+            return Err(anyhow::anyhow!("Set either value or value_msat, not both"));
+        }
+
+        for (name, field) in [
+            ("description_hash", &self.description_hash),
+            ("r_preimage", &self.r_preimage),
+        ] {
+            if let Some(field) = field {
+                match hex::decode(field) {
+                    Ok(bytes) if bytes.len() == 32 => {}
+                    _ => return Err(anyhow::anyhow!("{} must be 32 hex encoded bytes", name)),
+                }
+            }
+        }
+
+        if self.is_amp && self.r_preimage.is_some() {
+            return Err(anyhow::anyhow!("AMP invoices can not use a fixed preimage"));
+        }
+
+        Ok(())
+    }
+}
+
+/// A hold invoice for a caller supplied payment hash. The invoice stays in
+/// `ClusterInvoiceState::Accepted` once paid until it is settled or canceled.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ClusterAddHoldInvoice {
+    pub pubkey: Option<String>,
+    pub hash: String,
+    pub memo: String,
+    pub value: i64,
+    pub expiry: i64,
+    pub callback_url: Option<String>,
+    /// Amount in millisatoshis, mutually exclusive with `value`.
+    pub value_msat: Option<i64>,
+    /// Hex encoded SHA256 of the description, used instead of `memo`.
+    pub description_hash: Option<String>,
+    pub cltv_expiry: Option<u64>,
+    /// Include route hints for private channels.
+    pub private: bool,
+}
+
+impl ClusterAddHoldInvoice {
+    /// Checks the request the same way as `ClusterAddInvoice::validate`,
+    /// along with the caller supplied payment hash.
+    pub fn validate(&self) -> Result<()> {
+        if self.value < 0 || self.value_msat.unwrap_or(0) < 0 {
+            return Err(anyhow::anyhow!("Invoice amount can not be negative"));
+        }
+
+        if !self.memo.is_empty() && self.description_hash.is_some() {
+            return Err(anyhow::anyhow!(
+                "Set either memo or description_hash, not both"
+            ));
+        }
+
+        if self.value != 0 && self.value_msat.is_some() {
+            return Err(anyhow::anyhow!("Set either value or value_msat, not both"));
+        }
+
+        for (name, field) in [
+            ("hash", Some(&self.hash)),
+            ("description_hash", self.description_hash.as_ref()),
+        ] {
+            if let Some(field) = field {
+                match hex::decode(field) {
+                    Ok(bytes) if bytes.len() == 32 => {}
+                    _ => return Err(anyhow::anyhow!("{} must be 32 hex encoded bytes", name)),
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -69,13 +215,101 @@ pub struct ClusterLookupInvoice {
     pub expiry: String,
     pub amt_paid_sat: String,
     pub state: ClusterInvoiceState,
+    #[serde(default)]
+    pub creation_date: String,
+    #[serde(default)]
+    pub add_index: String,
+    #[serde(default)]
+    pub value_msat: String,
+}
+
+impl ClusterLookupInvoice {
+    /// Ordering key used to merge invoices from several nodes.
+    fn sort_key(&self) -> (u64, String, u64) {
+        (
+            self.creation_date.parse().unwrap_or(0),
+            self.pubkey.clone(),
+            self.add_index.parse().unwrap_or(0),
+        )
+    }
+}
+
+/// Filters for `Cluster::list_invoices`. Dates are unix timestamps and
+/// ranges are inclusive.
+#[derive(Debug, Clone, Default)]
+pub struct ClusterListInvoices {
+    pub pubkey: Option<String>,
+    pub state: Option<ClusterInvoiceState>,
+    pub pending_only: bool,
+    pub creation_date_start: Option<u64>,
+    pub creation_date_end: Option<u64>,
+    pub settle_date_start: Option<u64>,
+    pub settle_date_end: Option<u64>,
+    pub limit: usize,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+}
+
+impl ClusterListInvoices {
+    fn matches(&self, invoice: &ClusterLookupInvoice) -> bool {
+        if let Some(state) = &self.state {
+            if state != &invoice.state {
+                return false;
+            }
+        }
+
+        if self.settle_date_start.is_some() || self.settle_date_end.is_some() {
+            let settle_date = invoice.settle_date.parse::<u64>().unwrap_or(0);
+            if settle_date == 0
+                || settle_date < self.settle_date_start.unwrap_or(0)
+                || settle_date > self.settle_date_end.unwrap_or(u64::MAX)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterInvoicePage {
+    pub invoices: Vec<ClusterLookupInvoice>,
+    /// `None` once every node has been read to the end.
+    pub next_cursor: Option<String>,
+}
+
+/// Per node `index_offset`s, encoded as URL safe base64 JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ClusterInvoiceCursor {
+    pub offsets: BTreeMap<String, u64>,
+}
+
+impl ClusterInvoiceCursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_string(self).unwrap();
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    pub fn decode(cursor: &str) -> Result<ClusterInvoiceCursor> {
+        let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// One node's slice of a `list_invoices` call.
+#[derive(Debug, Clone)]
+pub struct NodeInvoicePage {
+    pub pubkey: String,
+    pub invoices: Vec<ClusterLookupInvoice>,
+    pub exhausted: bool,
 }
 
 impl FromRedisValue for ClusterLookupInvoice {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         match v {
             redis::Value::Okay => {
-                return Ok(ClusterLookupInvoice {
+                Ok(ClusterLookupInvoice {
                     pubkey: "".to_string(),
                     memo: "".to_string(),
                     r_preimage: "".to_string(),
@@ -87,15 +321,18 @@ impl FromRedisValue for ClusterLookupInvoice {
                     expiry: "".to_string(),
                     amt_paid_sat: "".to_string(),
                     state: ClusterInvoiceState::Open,
+                    creation_date: "".to_string(),
+                    add_index: "".to_string(),
+                    value_msat: "".to_string(),
                 })
             },
             redis::Value::Data(data) => {
                 let json = String::from_utf8(data.to_vec()).unwrap();
                 let invoice: ClusterLookupInvoice = serde_json::from_str(&json).unwrap();
-                return Ok(invoice)
+                Ok(invoice)
             },
             _ => panic!("Invalid redis value"),
-        };
+        }
     }
 }
 
@@ -108,6 +345,222 @@ pub struct ClusterPayPaymentRequestRes {
     pub payment_hash: Option<String>,
 }
 
+/// Context on `Cluster::pay_invoice` errors raised before the payment was
+/// handed to a node, so nothing was sent. Any other error leaves the outcome
+/// unknown. The underlying error, such as a `SpendLimitError`, can still be
+/// downcast to.
+#[derive(Debug)]
+pub struct PaymentNotSent(String);
+
+impl fmt::Display for PaymentNotSent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl PaymentNotSent {
+    fn wrap(error: anyhow::Error) -> anyhow::Error {
+        let message = error.to_string();
+        error.context(PaymentNotSent(message))
+    }
+
+    /// Whether `error` is known to have been raised before sending.
+    pub fn is(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<PaymentNotSent>().is_some()
+    }
+}
+
+/// The cost of reaching a destination from one node. `error` is set when
+/// the node found no route.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterRouteFeeEstimate {
+    pub pubkey: String,
+    pub fee_msat: Option<u64>,
+    pub success_prob: f64,
+    pub time_lock: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl ClusterRouteFeeEstimate {
+    pub fn reachable(&self) -> bool {
+        self.error.is_none() && self.fee_msat.is_some()
+    }
+}
+
+/// Invoice and payment state changes published to `Cluster::subscribe` receivers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    InvoiceAccepted(ClusterLookupInvoice),
+    InvoiceSettled(ClusterLookupInvoice),
+    PaymentSucceeded(ClusterPayPaymentRequestRes),
+    PaymentFailed(ClusterPayPaymentRequestRes),
+    SpendLimitWarning(SpendStatus),
+    /// First sighting of a deposit, usually unconfirmed.
+    DepositDetected(ClusterAddressDeposit),
+    /// The deposit reached one of the watcher's confirmation thresholds.
+    DepositConfirmed {
+        deposit: ClusterAddressDeposit,
+        threshold: i32,
+    },
+    /// The deposit's block was reorganized out. Thresholds already reached
+    /// are reported again once it confirms anew.
+    DepositReorged(ClusterAddressDeposit),
+    /// The deposit disappeared from the wallet before reaching every
+    /// threshold, because a conflicting transaction replaced it.
+    DepositDoubleSpent(ClusterAddressDeposit),
+}
+
+impl ClusterEvent {
+    /// The payment hash the event refers to, used to find per-invoice webhooks.
+    pub fn payment_hash(&self) -> Option<&str> {
+        match self {
+            ClusterEvent::InvoiceAccepted(invoice) | ClusterEvent::InvoiceSettled(invoice) => {
+                Some(&invoice.r_hash)
+            }
+            ClusterEvent::PaymentSucceeded(payment) | ClusterEvent::PaymentFailed(payment) => {
+                payment.payment_hash.as_deref()
+            }
+            ClusterEvent::SpendLimitWarning(_)
+            | ClusterEvent::DepositDetected(_)
+            | ClusterEvent::DepositConfirmed { .. }
+            | ClusterEvent::DepositReorged(_)
+            | ClusterEvent::DepositDoubleSpent(_) => None,
+        }
+    }
+}
+
+/// Sends cluster events to every receiver returned by `Cluster::subscribe`.
+#[derive(Clone, Default)]
+pub struct ClusterEventSender {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<ClusterEvent>>>>,
+}
+
+impl ClusterEventSender {
+    pub fn subscribe(&self) -> UnboundedReceiver<ClusterEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends the event to every open receiver, dropping closed ones. Fails
+    /// when no receiver is left.
+    pub fn send(&self, event: ClusterEvent) -> Result<()> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        if subscribers.is_empty() {
+            return Err(anyhow::anyhow!("Every event receiver was dropped"));
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let subscribers = self.subscribers.lock().unwrap();
+        subscribers.iter().all(|subscriber| subscriber.is_closed())
+    }
+}
+
+/// TLV record carrying the keysend preimage.
+pub const KEYSEND_PREIMAGE_RECORD: u64 = 5482373484;
+
+/// A spontaneous payment. `custom_records` maps TLV types to hex encoded
+/// values, for example `7629169` for podcast boostagrams.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClusterKeysend {
+    pub dest: String,
+    pub amount: u64,
+    pub custom_records: BTreeMap<u64, String>,
+}
+
+impl ClusterKeysend {
+    pub fn validate(&self) -> Result<()> {
+        match hex::decode(&self.dest) {
+            Ok(dest) if dest.len() == 33 => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Keysend destination must be a 33 byte hex pubkey"
+                ))
+            }
+        }
+
+        if self.amount == 0 {
+            return Err(anyhow::anyhow!("Keysend amount must be greater than zero"));
+        }
+
+        for (key, value) in &self.custom_records {
+            if *key < 65536 || *key == KEYSEND_PREIMAGE_RECORD {
+                return Err(anyhow::anyhow!("Custom record {} is reserved", key));
+            }
+            hex::decode(value)?;
+        }
+
+        Ok(())
+    }
+
+    /// The TLV records to send, keyed by decimal type with base64 values as
+    /// LND expects, including the preimage record.
+    pub fn dest_custom_records(&self, preimage: &[u8; 32]) -> Result<BTreeMap<String, String>> {
+        let mut records = BTreeMap::new();
+        for (key, value) in &self.custom_records {
+            records.insert(key.to_string(), base64::encode(hex::decode(value)?));
+        }
+        records.insert(
+            KEYSEND_PREIMAGE_RECORD.to_string(),
+            base64::encode(preimage),
+        );
+
+        Ok(records)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClusterCreateOffer {
+    /// Fixed amount in millisatoshis, or any amount when `None`.
+    pub amount_msat: Option<u64>,
+    pub description: String,
+    pub label: Option<String>,
+    pub single_use: bool,
+}
+
+/// A BOLT12 offer and the node that issued it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterOffer {
+    pub pubkey: String,
+    pub offer_id: String,
+    pub bolt12: String,
+    pub active: bool,
+    pub single_use: bool,
+}
+
+/// A paid invoice the issuing node created for a request against one of
+/// its offers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterOfferPayment {
+    pub pubkey: String,
+    pub offer_id: String,
+    pub payment_hash: String,
+    pub amount_msat: u64,
+    pub paid_at: u64,
+}
+
+impl FromRedisValue for ClusterOffer {
+    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
+        match v {
+            redis::Value::Data(data) => serde_json::from_slice(data).map_err(|e| {
+                redis::RedisError::from((
+                    redis::ErrorKind::TypeError,
+                    "Invalid cached offer",
+                    e.to_string(),
+                ))
+            }),
+            _ => Err(redis::RedisError::from((
+                redis::ErrorKind::TypeError,
+                "Invalid redis value",
+            ))),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone, Serialize)]
 pub struct ClusterUtxos {
     pub utxos: Vec<ClusterUtxo>,
@@ -117,377 +570,3169 @@ impl FromRedisValue for ClusterUtxos {
     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
         match v {
             redis::Value::Okay => {
-                return Ok(ClusterUtxos {
+                Ok(ClusterUtxos {
                     utxos: vec![],
                 })
             },
             redis::Value::Data(data) => {
                 let json = String::from_utf8(data.to_vec()).unwrap();
                 let utxos: ClusterUtxos = serde_json::from_str(&json).unwrap();
-                return Ok(utxos)
+                Ok(utxos)
             },
             _ => panic!("Invalid redis value"),
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct ClusterUtxo {
     pub pubkey: String,
     pub address: String,
     pub amount: u64,
     pub confirmations: u64,
+    #[serde(default)]
+    pub txid: String,
+    #[serde(default)]
+    pub output_index: u32,
+    #[serde(default)]
+    pub pk_script: String,
+    #[serde(default)]
+    pub address_type: Option<ClusterAddressType>,
+}
+
+impl ClusterUtxo {
+    /// `txid:vout`
+    pub fn outpoint(&self) -> String {
+        format!("{}:{}", self.txid, self.output_index)
+    }
 }
 
+/// Inputs for `Cluster::fund_psbt`: either a base64 `psbt` template to add
+/// inputs and change to, or `outputs` mapping addresses to sats.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum ClusterInvoiceState {
-    #[serde(rename = "OPEN")]
-    Open = 0,
-    #[serde(rename = "SETTLED")]
-    Settled = 1,
-    #[serde(rename = "CANCELED")]
-    Canceled = 2,
-    #[serde(rename = "ACCEPTED")]
-    Accepted = 3,
+pub struct ClusterFundPsbt {
+    pub psbt: Option<String>,
+    #[serde(default)]
+    pub outputs: BTreeMap<String, u64>,
+    pub fee: ClusterOnchainFee,
+    #[serde(default)]
+    pub min_confs: i32,
 }
 
-impl Node {
-    pub async fn lookup_invoice(self: &Self, r_hash: &str) -> Result<ClusterLookupInvoice> {
-        match &self.client {
-            NodeClient::Lnd(client) => {
-                let invoice = client.lookup_invoice(r_hash).await?;
-                Ok(invoice.to_cluster(&self.pubkey))
-            }
-            _ => {
-                panic!("We only support LND nodes at this time.")
-            }
+impl ClusterFundPsbt {
+    pub fn validate(&self) -> Result<()> {
+        if self.psbt.is_some() != self.outputs.is_empty() {
+            return Err(anyhow::anyhow!("Provide either a psbt or outputs"));
         }
+
+        self.fee.validate()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterPsbtState {
+    Funded,
+    Finalized,
+    Published,
+    /// Aborted, with the leased inputs released.
+    Released,
+}
+
+/// An input LND locked for a PSBT until `expiration` (unix seconds).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterUtxoLease {
+    pub id: String,
+    pub txid: String,
+    pub output_index: u32,
+    pub expiration: u64,
+}
+
+/// A PSBT flow tracked by the cluster. `psbt` is base64 and is replaced by
+/// the signed PSBT once finalized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterPsbt {
+    pub id: String,
+    pub pubkey: String,
+    pub psbt: String,
+    pub change_output_index: i32,
+    pub leases: Vec<ClusterUtxoLease>,
+    pub state: ClusterPsbtState,
+    /// Hex encoded transaction, once finalized.
+    pub raw_final_tx: Option<String>,
+}
+
+impl ClusterPsbt {
+    /// Whether LND already released the inputs on its own.
+    pub fn leases_expired(&self, now: u64) -> bool {
+        self.leases.iter().all(|lease| lease.expiration <= now)
     }
+}
 
-    pub async fn add_invoice(self: &Self, req: ClusterAddInvoice) -> Result<AddInvoiceResponse> {
-        match &self.client {
-            NodeClient::Lnd(client) => {
-                let invoice = client.add_invoice(req).await?;
+/// On-chain wallet balance in sats. `locked` is held by leased outputs and
+/// is part of `confirmed`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ClusterOnchainBalance {
+    pub confirmed: i64,
+    pub unconfirmed: i64,
+    pub locked: i64,
+    pub total: i64,
+}
 
-                let response = AddInvoiceResponse {
-                    r_hash: to_hex(&invoice.r_hash)?,
-                    payment_addr: to_hex(&invoice.payment_addr)?,
-                    ..invoice
-                };
-                Ok(response)
-            }
-            _ => {
-                panic!("We only support LND nodes at this time.")
-            }
-        }
+impl std::ops::AddAssign for ClusterOnchainBalance {
+    fn add_assign(&mut self, other: Self) {
+        self.confirmed += other.confirmed;
+        self.unconfirmed += other.unconfirmed;
+        self.locked += other.locked;
+        self.total += other.total;
     }
+}
 
-    pub async fn next_address(&self) -> Result<String> {
-        match &self.client {
-            NodeClient::Lnd(client) => {
-                let addr = client.new_address().await?;
-                Ok(addr.address)
-            }
-            _ => {
-                panic!("We only support LND nodes at this time.")
-            }
+/// On-chain balances per node pubkey and their sum.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ClusterOnchainBalances {
+    pub nodes: BTreeMap<String, ClusterOnchainBalance>,
+    pub total: ClusterOnchainBalance,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ClusterChannelFeePolicy {
+    pub base_fee_msat: i64,
+    pub fee_ppm: i64,
+}
+
+/// A channel of the node `pubkey` with `peer`. Amounts are in sats.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterChannel {
+    pub pubkey: String,
+    pub peer: String,
+    pub chan_id: String,
+    pub channel_point: String,
+    pub capacity: i64,
+    pub local_balance: i64,
+    pub remote_balance: i64,
+    pub active: bool,
+    pub private: bool,
+    pub fee_policy: Option<ClusterChannelFeePolicy>,
+}
+
+impl ClusterChannel {
+    /// Share of the balance on our side, `None` for an empty channel.
+    pub fn local_ratio(&self) -> Option<f64> {
+        local_ratio(self.local_balance, self.remote_balance)
+    }
+}
+
+/// Channel liquidity in sats. `outbound` and `inbound` count active
+/// channels only, the balances of inactive channels can't be used until
+/// the peer is back.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ClusterLiquidity {
+    pub channels: usize,
+    pub active_channels: usize,
+    pub capacity: i64,
+    pub outbound: i64,
+    pub inbound: i64,
+    pub inactive_outbound: i64,
+    pub inactive_inbound: i64,
+}
+
+impl ClusterLiquidity {
+    pub fn add_channel(&mut self, channel: &ClusterChannel) {
+        self.channels += 1;
+        self.capacity += channel.capacity;
+        if channel.active {
+            self.active_channels += 1;
+            self.outbound += channel.local_balance;
+            self.inbound += channel.remote_balance;
+        } else {
+            self.inactive_outbound += channel.local_balance;
+            self.inactive_inbound += channel.remote_balance;
         }
     }
 
-    pub async fn list_utxos(&self) -> Result<ClusterUtxos> {
-        match &self.client {
-            NodeClient::Lnd(client) => {
-                let utxos = client.list_unspent().await?;
-                let cluster_utxos = ClusterUtxos {
-                    utxos: utxos
-                        .utxos
-                        .into_iter()
-                        .map(|utxo| ClusterUtxo {
-                            pubkey: self.pubkey.clone(),
-                            address: utxo.address,
-                            amount: utxo.amount_sat.parse::<u64>().unwrap(),
-                            confirmations: utxo.confirmations.parse::<u64>().unwrap(),
-                        })
-                        .collect(),
-                };
-                Ok(cluster_utxos)
-            }
-            _ => {
-                panic!("We only support LND nodes at this time.")
-            }
+    /// Share of the active balance on our side, `None` without any.
+    pub fn local_ratio(&self) -> Option<f64> {
+        local_ratio(self.outbound, self.inbound)
+    }
+}
+
+impl std::ops::AddAssign for ClusterLiquidity {
+    fn add_assign(&mut self, other: Self) {
+        self.channels += other.channels;
+        self.active_channels += other.active_channels;
+        self.capacity += other.capacity;
+        self.outbound += other.outbound;
+        self.inbound += other.inbound;
+        self.inactive_outbound += other.inactive_outbound;
+        self.inactive_inbound += other.inactive_inbound;
+    }
+}
+
+/// Liquidity per node pubkey and its sum.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ClusterLiquidityReport {
+    pub nodes: BTreeMap<String, ClusterLiquidity>,
+    pub total: ClusterLiquidity,
+}
+
+impl ClusterLiquidityReport {
+    pub fn from_channels(channels: &[ClusterChannel]) -> ClusterLiquidityReport {
+        let mut report = ClusterLiquidityReport::default();
+        for channel in channels {
+            report
+                .nodes
+                .entry(channel.pubkey.clone())
+                .or_default()
+                .add_channel(channel);
+            report.total.add_channel(channel);
         }
+        report
     }
 }
 
-impl Cluster {
-    pub fn new(
-        nodes: Vec<Node>,
-        redis: redis::aio::Connection,
-        inv_exp_sec: i64,
-        addr_exp_sec: i64,
-        utxo_exp_sec: i64,
-    ) -> Cluster {
-        Self {
-            nodes,
-            cache: redis,
-            inv_exp_sec: inv_exp_sec,
-            addr_exp_sec: addr_exp_sec,
-            utxo_exp_sec: utxo_exp_sec,
+/// A payment from a node to itself, leaving through `outgoing_chan_id` and
+/// arriving through its channel with `last_hop_pubkey`. The fee is capped at
+/// `max_fee_ppm` of the amount.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterCircularRebalance {
+    pub outgoing_chan_id: String,
+    pub last_hop_pubkey: String,
+    pub amount_sat: u64,
+    pub max_fee_ppm: u64,
+}
+
+impl ClusterCircularRebalance {
+    pub fn validate(&self) -> Result<()> {
+        if self.amount_sat == 0 {
+            return Err(anyhow::anyhow!("amount must be positive"));
+        }
+        if self.max_fee_ppm == 0 {
+            return Err(anyhow::anyhow!("max_fee_ppm must be greater than 0"));
+        }
+        match hex::decode(&self.last_hop_pubkey) {
+            Ok(pubkey) if pubkey.len() == 33 => Ok(()),
+            _ => Err(anyhow::anyhow!(
+                "last_hop_pubkey must be a 33 byte hex pubkey"
+            )),
         }
     }
+}
 
-    pub async fn lookup_invoice(
-        &mut self,
-        r_hash: &str,
-        pubkey: Option<String>,
-    ) -> Result<ClusterLookupInvoice> {
-        let cached_invoice = self.cache.get(&r_hash.to_string()).await?;
+/// A completed payment moving liquidity from `from` to `to`. The preimage
+/// was checked against the invoice's payment hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterRebalance {
+    pub from: String,
+    pub to: String,
+    pub amount_sat: u64,
+    /// `None` when the payment errored but the invoice shows it settled.
+    pub fee_sat: Option<u64>,
+    pub payment_hash: String,
+    pub preimage: String,
+}
 
-        match cached_invoice {
-            Some(invoice) => {
-                eprintln!("cached");
-                Ok(invoice)
-            },
-            None => {
-                if let Some(pubkey) = pubkey {
-                    let node = self
-                        .nodes
-                        .iter()
-                        .find(|node| node.pubkey == pubkey)
-                        .unwrap();
-                    let invoice = node.lookup_invoice(r_hash).await?;
-                    let hexed_invoice = ClusterLookupInvoice {
+fn local_ratio(local: i64, remote: i64) -> Option<f64> {
+    match local + remote {
+        0 => None,
+        sum => Some(local as f64 / sum as f64),
+    }
+}
+
+/// How the on-chain fee is chosen: an explicit rate, or whatever the node's
+/// fee estimator expects to confirm within a number of blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterOnchainFee {
+    SatPerVbyte(u64),
+    TargetConf(i32),
+}
+
+impl ClusterOnchainFee {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ClusterOnchainFee::SatPerVbyte(0) => {
+                Err(anyhow::anyhow!("sat_per_vbyte must be greater than 0"))
+            }
+            ClusterOnchainFee::TargetConf(target_conf) if *target_conf < 1 => {
+                Err(anyhow::anyhow!("target_conf must be at least 1"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn target_conf(&self) -> Option<i32> {
+        match self {
+            ClusterOnchainFee::TargetConf(target_conf) => Some(*target_conf),
+            _ => None,
+        }
+    }
+
+    fn sat_per_vbyte(&self) -> Option<String> {
+        match self {
+            ClusterOnchainFee::SatPerVbyte(sat_per_vbyte) => Some(sat_per_vbyte.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// A channel of `amount` sats to open with `peer`, given as `pubkey` or
+/// `pubkey@host:port` to connect first when not already connected.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterOpenChannel {
+    pub peer: String,
+    pub amount: i64,
+    #[serde(default)]
+    pub push_sat: i64,
+    #[serde(default)]
+    pub private: bool,
+    pub fee: ClusterOnchainFee,
+}
+
+impl ClusterOpenChannel {
+    pub fn validate(&self) -> Result<()> {
+        if self.peer.is_empty() || self.amount <= 0 {
+            return Err(anyhow::anyhow!("peer and a positive amount are required"));
+        }
+        if self.push_sat < 0 || self.push_sat >= self.amount {
+            return Err(anyhow::anyhow!("push_sat must be less than the amount"));
+        }
+
+        self.fee.validate()
+    }
+}
+
+/// A channel open or close whose transaction was published. `txid` is the
+/// funding transaction when opening and the closing transaction when
+/// closing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterPendingChannel {
+    pub pubkey: String,
+    pub peer: String,
+    pub channel_point: String,
+    pub txid: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterChannelUpdate {
+    Opened {
+        channel_point: String,
+    },
+    Closed {
+        channel_point: String,
+        closing_txid: String,
+    },
+    /// The status stream from the node ended early. The transaction is
+    /// already published, so the open or close may still complete.
+    Interrupted {
+        channel_point: String,
+        error: String,
+    },
+}
+
+/// A pending channel open or close and the updates that follow it, ending
+/// with one `Opened`, `Closed` or `Interrupted` update.
+pub struct ClusterChannelOperation {
+    pub pending: ClusterPendingChannel,
+    pub updates: UnboundedReceiver<ClusterChannelUpdate>,
+}
+
+/// An on-chain payment of `amount` sats to `addr`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterSendOnchain {
+    pub addr: String,
+    pub amount: i64,
+    pub fee: ClusterOnchainFee,
+    pub label: Option<String>,
+}
+
+impl ClusterSendOnchain {
+    pub fn validate(&self) -> Result<()> {
+        if self.addr.is_empty() {
+            return Err(anyhow::anyhow!("addr is required"));
+        }
+
+        if self.amount <= 0 {
+            return Err(anyhow::anyhow!("amount must be greater than 0"));
+        }
+
+        self.fee.validate()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterOnchainTx {
+    pub pubkey: String,
+    pub txid: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterAddressType {
+    /// Native segwit v0, `bc1q...`.
+    #[default]
+    P2wkh,
+    /// Segwit v0 nested in P2SH, `3...` on mainnet.
+    NestedP2wkh,
+    /// Taproot, `bc1p...`.
+    P2tr,
+}
+
+impl ClusterAddressType {
+    /// LND `AddressType` value.
+    pub fn to_lnd(&self, reuse_unused: bool) -> u8 {
+        match (self, reuse_unused) {
+            (ClusterAddressType::P2wkh, false) => 0,
+            (ClusterAddressType::NestedP2wkh, false) => 1,
+            (ClusterAddressType::P2wkh, true) => 2,
+            (ClusterAddressType::NestedP2wkh, true) => 3,
+            (ClusterAddressType::P2tr, false) => 4,
+            (ClusterAddressType::P2tr, true) => 5,
+        }
+    }
+
+    /// From an LND `AddressType` name, as reported for UTXOs.
+    pub fn from_lnd(address_type: &str) -> Option<ClusterAddressType> {
+        match address_type {
+            "WITNESS_PUBKEY_HASH" | "UNUSED_WITNESS_PUBKEY_HASH" => Some(ClusterAddressType::P2wkh),
+            "NESTED_PUBKEY_HASH" | "UNUSED_NESTED_PUBKEY_HASH" => {
+                Some(ClusterAddressType::NestedP2wkh)
+            }
+            "TAPROOT_PUBKEY" | "UNUSED_TAPROOT_PUBKEY" => Some(ClusterAddressType::P2tr),
+            _ => None,
+        }
+    }
+
+    /// Whether `address` is of this type.
+    pub fn matches(&self, address: &str) -> bool {
+        match self {
+            ClusterAddressType::NestedP2wkh => {
+                (address.starts_with('3') || address.starts_with('2'))
+                    && bech32::segwit::decode(address).is_err()
+            }
+            ClusterAddressType::P2wkh | ClusterAddressType::P2tr => {
+                match bech32::segwit::decode(address) {
+                    Ok((_, version, program)) => match self {
+                        ClusterAddressType::P2wkh => {
+                            version == bech32::segwit::VERSION_0 && program.len() == 20
+                        }
+                        _ => version == bech32::segwit::VERSION_1 && program.len() == 32,
+                    },
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+}
+
+/// Options for `Cluster::next_address`. With `reuse_unused` the node
+/// returns its last address of the type that has not received funds,
+/// instead of deriving a new one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default)]
+pub struct ClusterNewAddress {
+    pub address_type: ClusterAddressType,
+    pub reuse_unused: bool,
+}
+
+/// An output paying an address issued by the cluster. `block_height` is 0
+/// while unconfirmed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterAddressDeposit {
+    pub pubkey: String,
+    pub address: String,
+    pub txid: String,
+    pub output_index: u32,
+    pub amount: i64,
+    pub confirmations: i32,
+    pub block_height: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClusterInvoiceState {
+    #[serde(rename = "OPEN")]
+    Open = 0,
+    #[serde(rename = "SETTLED")]
+    Settled = 1,
+    #[serde(rename = "CANCELED")]
+    Canceled = 2,
+    #[serde(rename = "ACCEPTED")]
+    Accepted = 3,
+}
+
+impl Node {
+    pub async fn lookup_invoice(&self, r_hash: &str) -> Result<ClusterLookupInvoice> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let invoice = client.lookup_invoice(r_hash).await?;
+                Ok(invoice.to_cluster(&self.pubkey))
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Looking up invoices")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn add_invoice(&self, req: ClusterAddInvoice) -> Result<AddInvoiceResponse> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let invoice = client.add_invoice(req).await?;
+
+                let response = AddInvoiceResponse {
+                    r_hash: to_hex(&invoice.r_hash)?,
+                    payment_addr: to_hex(&invoice.payment_addr)?,
+                    ..invoice
+                };
+                Ok(response)
+            }
+            NodeClient::CLightning(client) => {
+                let label = hex::encode(rand::random::<[u8; 16]>());
+                let req = InvoiceClnRequest::from_cluster(req, label)?;
+
+                Ok(client.invoice(req).await?.to_cluster())
+            }
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn add_hold_invoice(&self, req: ClusterAddHoldInvoice) -> Result<AddInvoiceResponse> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let r_hash = req.hash.to_lowercase();
+                let invoice = client.add_hold_invoice(req).await?;
+
+                Ok(AddInvoiceResponse {
+                    r_hash,
+                    payment_request: invoice.payment_request,
+                    add_index: invoice.add_index,
+                    payment_addr: to_hex(&invoice.payment_addr)?,
+                })
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Creating hold invoices")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn settle_invoice(&self, preimage: &str) -> Result<()> {
+        match &self.client {
+            NodeClient::Lnd(client) => client.settle_invoice(preimage).await,
+            NodeClient::CLightning(_) => Err(cln_unsupported("Settling invoices")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn cancel_invoice(&self, r_hash: &str) -> Result<()> {
+        match &self.client {
+            NodeClient::Lnd(client) => client.cancel_invoice(r_hash).await,
+            NodeClient::CLightning(_) => Err(cln_unsupported("Cancelling invoices")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn list_invoices(
+        &self,
+        req: &ClusterListInvoices,
+        index_offset: u64,
+        limit: usize,
+    ) -> Result<NodeInvoicePage> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let lnd_req = ListInvoicesLndRequest {
+                    index_offset,
+                    num_max_invoices: limit as u64,
+                    pending_only: req.pending_only,
+                    creation_date_start: req.creation_date_start,
+                    creation_date_end: req.creation_date_end,
+                };
+                let list = client.list_invoices(lnd_req).await?;
+                let exhausted = list.invoices.len() < limit;
+
+                let mut invoices = vec![];
+                for invoice in list.invoices {
+                    let invoice = invoice.to_cluster(&self.pubkey);
+                    invoices.push(ClusterLookupInvoice {
                         r_hash: to_hex(&invoice.r_hash)?,
                         r_preimage: to_hex(&invoice.r_preimage)?,
                         ..invoice
-                    };
-                    let json_string = serde_json::to_string(&hexed_invoice).unwrap();
+                    });
+                }
+
+                Ok(NodeInvoicePage {
+                    pubkey: self.pubkey.clone(),
+                    invoices,
+                    exhausted,
+                })
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Listing invoices")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn pay_invoice(
+        &self,
+        amount: u64,
+        payment_request: String,
+        fee_policy: &FeePolicy,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let amount_msat = payment_amount_msat(&payment_request, amount)?;
+
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let req = LndSendPaymentSyncReq {
+                    payment_request,
+                    amt: amount.to_string(),
+                    fee_limit: fee_policy.to_lnd(amount_msat),
+                    allow_self_payment: false,
+                    ..Default::default()
+                };
+                let payment = client.send_payment_sync(req).await?;
+                Ok(payment.to_cluster(self.pubkey.clone()))
+            }
+            NodeClient::CLightning(client) => {
+                let fee_limit = fee_policy.to_cln(amount_msat);
+                let amount_msat = if amount > 0 {
+                    Some(amount * 1000)
+                } else {
+                    None
+                };
+                self.cln_pay(client, payment_request, amount_msat, fee_limit)
+                    .await
+            }
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn keysend(
+        &self,
+        req: &ClusterKeysend,
+        fee_policy: &FeePolicy,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let preimage: [u8; 32] = rand::random();
+                let payment_hash = Sha256::digest(preimage);
+
+                let lnd_req = LndSendPaymentSyncReq {
+                    amt: req.amount.to_string(),
+                    fee_limit: fee_policy.to_lnd(req.amount * 1000),
+                    dest: Some(base64::encode(hex::decode(&req.dest)?)),
+                    dest_custom_records: Some(req.dest_custom_records(&preimage)?),
+                    payment_hash: Some(base64::encode(payment_hash)),
+                    ..Default::default()
+                };
+                let payment = client.send_payment_sync(lnd_req).await?;
+                Ok(payment.to_cluster(self.pubkey.clone()))
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Keysend")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Outbound lightning balance in millisatoshis.
+    pub async fn channel_balance(&self) -> Result<u64> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let balance = client.channel_balance().await?;
+                Ok(balance.local_balance.msat.parse::<u64>()?)
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Channel balances")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Whether the node can create, look up, list and watch invoices. CLN
+    /// nodes only create invoices, when picked by pubkey.
+    pub fn supports_invoices(&self) -> bool {
+        matches!(self.client, NodeClient::Lnd(_))
+    }
+
+    pub fn supports_keysend(&self) -> bool {
+        matches!(self.client, NodeClient::Lnd(_))
+    }
+
+    /// Whether the node's on-chain wallet can be used: addresses, deposits,
+    /// UTXOs, sends and PSBTs.
+    pub fn supports_wallet(&self) -> bool {
+        matches!(self.client, NodeClient::Lnd(_))
+    }
+
+    /// Whether the node's channels can be listed, opened, closed and
+    /// rebalanced.
+    pub fn supports_channels(&self) -> bool {
+        matches!(self.client, NodeClient::Lnd(_))
+    }
+
+    /// Whether the node can estimate routing fees with `estimate_route_fee`.
+    pub fn supports_route_estimates(&self) -> bool {
+        matches!(self.client, NodeClient::Lnd(_))
+    }
+
+    pub async fn estimate_route_fee(
+        &self,
+        dest: &str,
+        amount_msat: u64,
+    ) -> ClusterRouteFeeEstimate {
+        let mut estimate = ClusterRouteFeeEstimate {
+            pubkey: self.pubkey.clone(),
+            fee_msat: None,
+            success_prob: 0.0,
+            time_lock: None,
+            error: None,
+        };
+
+        match &self.client {
+            NodeClient::Lnd(client) => match client.query_routes(dest, amount_msat).await {
+                Ok(res) => match res.routes.first() {
+                    Some(route) => match route.total_fees_msat.parse::<u64>() {
+                        Ok(fee_msat) => {
+                            estimate.fee_msat = Some(fee_msat);
+                            estimate.success_prob = res.success_prob;
+                            estimate.time_lock = Some(route.total_time_lock);
+                        }
+                        Err(e) => estimate.error = Some(e.to_string()),
+                    },
+                    None => estimate.error = Some("No route found".to_string()),
+                },
+                Err(e) => estimate.error = Some(e.to_string()),
+            },
+            NodeClient::CLightning(_) => {
+                estimate.error = Some(cln_unsupported("Route estimates").to_string())
+            }
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+
+        estimate
+    }
+
+    /// Estimates the fee of paying a BOLT11 invoice by probing with it, which
+    /// follows the invoice's route hints.
+    pub async fn estimate_invoice_fee(&self, payment_request: &str) -> ClusterRouteFeeEstimate {
+        let error = match &self.client {
+            NodeClient::Lnd(client) => match client.estimate_route_fee(payment_request).await {
+                Ok(res) => return res.to_cluster(&self.pubkey),
+                Err(e) => e,
+            },
+            NodeClient::CLightning(_) => cln_unsupported("Route estimates"),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        };
+
+        ClusterRouteFeeEstimate {
+            pubkey: self.pubkey.clone(),
+            fee_msat: None,
+            success_prob: 0.0,
+            time_lock: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    /// Whether the node can create and pay BOLT12 offers.
+    pub fn supports_offers(&self) -> bool {
+        matches!(self.client, NodeClient::CLightning(_))
+    }
+
+    pub async fn create_offer(&self, req: ClusterCreateOffer) -> Result<ClusterOffer> {
+        match &self.client {
+            NodeClient::CLightning(client) => {
+                let cln_req = OfferClnRequest {
+                    amount: match req.amount_msat {
+                        Some(amount_msat) => format!("{}msat", amount_msat),
+                        None => "any".to_string(),
+                    },
+                    description: req.description,
+                    label: req.label,
+                    single_use: req.single_use,
+                };
+                let offer = client.offer(cln_req).await?;
+                Ok(offer.to_cluster(&self.pubkey))
+            }
+            NodeClient::Lnd(_) => Err(anyhow::anyhow!("LND does not support BOLT12 offers")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Fetches an invoice for the offer and pays it.
+    pub async fn pay_offer(
+        &self,
+        offer: &str,
+        amount_msat: Option<u64>,
+        fee_policy: &FeePolicy,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        match &self.client {
+            NodeClient::CLightning(client) => {
+                let invoice = client
+                    .fetch_invoice(FetchInvoiceClnRequest {
+                        offer: offer.to_string(),
+                        amount_msat,
+                    })
+                    .await?;
+                let invoice_amount_msat = client.decode(&invoice.invoice).await?.amount_msat();
+
+                let fee_limit = fee_policy.to_cln(invoice_amount_msat);
+                self.cln_pay(client, invoice.invoice, None, fee_limit).await
+            }
+            NodeClient::Lnd(_) => Err(anyhow::anyhow!("LND does not support BOLT12 offers")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// The offer with `offer_id` if this node issued it.
+    pub async fn lookup_offer(&self, offer_id: &str) -> Result<Option<ClusterOffer>> {
+        match &self.client {
+            NodeClient::CLightning(client) => {
+                let res = client.list_offers(offer_id).await?;
+                Ok(res
+                    .offers
+                    .into_iter()
+                    .next()
+                    .map(|offer| offer.to_cluster(&self.pubkey)))
+            }
+            NodeClient::Lnd(_) => Err(anyhow::anyhow!("LND does not support BOLT12 offers")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Payments this node received against one of its offers.
+    pub async fn offer_payments(&self, offer_id: &str) -> Result<Vec<ClusterOfferPayment>> {
+        match &self.client {
+            NodeClient::CLightning(client) => Ok(client
+                .list_offer_invoices(offer_id)
+                .await?
+                .to_offer_payments(&self.pubkey)),
+            NodeClient::Lnd(_) => Err(anyhow::anyhow!("LND does not support BOLT12 offers")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// The node id of the offer's issuer, `None` when the offer only names
+    /// blinded paths.
+    pub async fn offer_issuer(&self, offer: &str) -> Result<Option<String>> {
+        match &self.client {
+            NodeClient::CLightning(client) => Ok(client.decode(offer).await?.issuer_id()),
+            NodeClient::Lnd(_) => Err(anyhow::anyhow!("LND does not support BOLT12 offers")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    async fn cln_pay(
+        &self,
+        client: &ClnClient,
+        bolt11: String,
+        amount_msat: Option<u64>,
+        fee_limit: ClnFeeLimit,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let pay_req = PayClnRequest {
+            bolt11,
+            amount_msat,
+            maxfeepercent: Some(fee_limit.maxfeepercent),
+            exemptfee: Some(fee_limit.exemptfee_msat),
+        };
+
+        match client.pay(pay_req).await? {
+            Ok(payment) => Ok(payment.to_cluster(self.pubkey.clone())),
+            Err(error) => Ok(ClusterPayPaymentRequestRes {
+                pubkey: self.pubkey.clone(),
+                payment_error: Some(error.message),
+                payment_preimage: None,
+                payment_route: None,
+                payment_hash: None,
+            }),
+        }
+    }
+
+    pub async fn next_address(&self, req: ClusterNewAddress) -> Result<String> {
+        let addr = match &self.client {
+            NodeClient::Lnd(client) => {
+                let addr = client
+                    .new_address(req.address_type.to_lnd(req.reuse_unused))
+                    .await?;
+                addr.address
+            }
+            NodeClient::CLightning(_) => return Err(cln_unsupported("New addresses")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        };
+
+        if !req.address_type.matches(&addr) {
+            return Err(anyhow::anyhow!(
+                "Node returned {} which is not a {:?} address",
+                addr,
+                req.address_type
+            ));
+        }
+
+        Ok(addr)
+    }
+
+    /// Deposits to one of the node's addresses within the last
+    /// `ADDRESS_SCAN_BLOCKS` blocks, including unconfirmed ones.
+    pub async fn address_deposits(&self, address: &str) -> Result<Vec<ClusterAddressDeposit>> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let tip = client.get_info().await?.block_height;
+                let start_height = (tip - deposits::ADDRESS_SCAN_BLOCKS).max(0);
+                let res = client.get_transactions(start_height, -1).await?;
+                Ok(res
+                    .transactions
+                    .iter()
+                    .flat_map(|tx| tx.to_cluster_deposits(&self.pubkey, Some(address)))
+                    .collect())
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Deposit lookups")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Deposits to any of the node's addresses confirmed at or above
+    /// `start_height`, plus unconfirmed ones.
+    pub async fn wallet_deposits(&self, start_height: i32) -> Result<Vec<ClusterAddressDeposit>> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let res = client.get_transactions(start_height, -1).await?;
+                Ok(res
+                    .transactions
+                    .iter()
+                    .flat_map(|tx| tx.to_cluster_deposits(&self.pubkey, None))
+                    .collect())
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Deposit lookups")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn block_height(&self) -> Result<i32> {
+        match &self.client {
+            NodeClient::Lnd(client) => Ok(client.get_info().await?.block_height),
+            NodeClient::CLightning(_) => Err(cln_unsupported("Block height lookups")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn send_onchain(&self, req: &ClusterSendOnchain) -> Result<ClusterOnchainTx> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let lnd_req = SendCoinsLndRequest {
+                    addr: req.addr.clone(),
+                    amount: req.amount.to_string(),
+                    target_conf: req.fee.target_conf(),
+                    sat_per_vbyte: req.fee.sat_per_vbyte(),
+                    label: req.label.clone(),
+                    ..Default::default()
+                };
+                let res = client.send_coins(lnd_req).await?;
+                Ok(ClusterOnchainTx {
+                    pubkey: self.pubkey.clone(),
+                    txid: res.txid,
+                })
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("On-chain sends")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn fund_psbt(&self, req: &ClusterFundPsbt) -> Result<ClusterPsbt> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let raw = match req.psbt {
+                    Some(_) => None,
+                    None => Some(TxTemplate {
+                        inputs: vec![],
+                        outputs: req
+                            .outputs
+                            .iter()
+                            .map(|(addr, amount)| (addr.clone(), amount.to_string()))
+                            .collect(),
+                    }),
+                };
+                let lnd_req = FundPsbtLndRequest {
+                    psbt: req.psbt.clone(),
+                    raw,
+                    target_conf: req.fee.target_conf(),
+                    sat_per_vbyte: req.fee.sat_per_vbyte(),
+                    min_confs: req.min_confs,
+                    spend_unconfirmed: req.min_confs == 0,
+                };
+                let res = client.fund_psbt(lnd_req).await?;
+                res.to_cluster(hex::encode(rand::random::<[u8; 16]>()), &self.pubkey)
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("PSBTs")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Returns the signed PSBT and the hex encoded final transaction.
+    pub async fn finalize_psbt(&self, psbt: &str) -> Result<(String, String)> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let res = client.finalize_psbt(psbt).await?;
+                Ok((res.signed_psbt, to_hex(&res.raw_final_tx)?))
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("PSBTs")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn publish_transaction(
+        &self,
+        raw_tx: &str,
+        label: Option<String>,
+    ) -> Result<PublishOutcome> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let req = PublishTransactionLndRequest {
+                    tx_hex: crate::lnd::to_base64(raw_tx)?,
+                    label,
+                };
+                client.publish_transaction(req).await
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("PSBTs")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn release_leases(&self, leases: &[ClusterUtxoLease]) -> Result<()> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                for lease in leases {
+                    let req = ReleaseOutputLndRequest {
+                        id: crate::lnd::to_base64(&lease.id)?,
+                        outpoint: LndOutPoint {
+                            txid_str: lease.txid.clone(),
+                            output_index: lease.output_index,
+                        },
+                    };
+                    client.release_output(req).await?;
+                }
+                Ok(())
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("PSBTs")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Spends exactly `utxos` into a single output to `addr`, the fee
+    /// coming out of the swept amount.
+    pub async fn sweep_utxos(
+        &self,
+        utxos: &[ClusterUtxo],
+        addr: &str,
+        fee: ClusterOnchainFee,
+        label: Option<String>,
+    ) -> Result<ClusterOnchainTx> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let lnd_req = SendCoinsLndRequest {
+                    addr: addr.to_string(),
+                    amount: "0".to_string(),
+                    target_conf: fee.target_conf(),
+                    sat_per_vbyte: fee.sat_per_vbyte(),
+                    label,
+                    send_all: true,
+                    outpoints: utxos
+                        .iter()
+                        .map(|utxo| LndOutPoint {
+                            txid_str: utxo.txid.clone(),
+                            output_index: utxo.output_index,
+                        })
+                        .collect(),
+                };
+                let res = client.send_coins(lnd_req).await?;
+                Ok(ClusterOnchainTx {
+                    pubkey: self.pubkey.clone(),
+                    txid: res.txid,
+                })
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("UTXO sweeps")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Pays every `address -> sats` output in a single transaction.
+    pub async fn send_onchain_many(
+        &self,
+        outputs: &BTreeMap<String, i64>,
+        fee: ClusterOnchainFee,
+        label: Option<String>,
+    ) -> Result<ClusterOnchainTx> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let lnd_req = SendManyLndRequest {
+                    addr_to_amount: outputs
+                        .iter()
+                        .map(|(addr, amount)| (addr.clone(), amount.to_string()))
+                        .collect(),
+                    target_conf: fee.target_conf(),
+                    sat_per_vbyte: fee.sat_per_vbyte(),
+                    label,
+                };
+                let res = client.send_many(lnd_req).await?;
+                Ok(ClusterOnchainTx {
+                    pubkey: self.pubkey.clone(),
+                    txid: res.txid,
+                })
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Batched on-chain sends")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn list_utxos(&self) -> Result<ClusterUtxos> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let utxos = client.list_unspent().await?;
+                utxos.to_cluster(self.pubkey.clone())
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Listing UTXOs")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn list_channels(&self) -> Result<Vec<ClusterChannel>> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let (channels, fees) =
+                    futures::future::try_join(client.list_channels(), client.fee_report()).await?;
+                channels.to_cluster(&self.pubkey, fees)
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Listing channels")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    async fn add_rebalance_invoice(
+        &self,
+        amount_sat: u64,
+        memo: String,
+    ) -> Result<RebalanceInvoice> {
+        let preimage: [u8; 32] = rand::random();
+        let invoice = self
+            .add_invoice(ClusterAddInvoice {
+                pubkey: Some(self.pubkey.clone()),
+                memo,
+                value: amount_sat as i64,
+                expiry: 600,
+                r_preimage: Some(hex::encode(preimage)),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(RebalanceInvoice {
+            payment_request: invoice.payment_request,
+            payment_hash: hex::encode(Sha256::digest(preimage)),
+            preimage,
+        })
+    }
+
+    /// Checks that paying this node's rebalance invoice returned its
+    /// preimage. Otherwise the invoice is looked up, as the payment may have
+    /// settled despite an error, and canceled when it did not so a late
+    /// payment can't settle it. Returns the payment when it is available,
+    /// `None` when only the invoice shows it settled, or a `RebalanceError`.
+    async fn verify_rebalance(
+        &self,
+        invoice: &RebalanceInvoice,
+        payment: Result<ClusterPayPaymentRequestRes>,
+    ) -> Result<Option<ClusterPayPaymentRequestRes>> {
+        let error = match payment {
+            Ok(payment) if payment_preimage_matches(&payment, &invoice.preimage) => {
+                return Ok(Some(payment))
+            }
+            Ok(payment) => payment
+                .payment_error
+                .unwrap_or_else(|| "Payment did not return the invoice's preimage".to_string()),
+            Err(e) => e.to_string(),
+        };
+
+        if let Ok(lookup) = self.lookup_invoice(&invoice.payment_hash).await {
+            if lookup.state == ClusterInvoiceState::Settled {
+                return Ok(None);
+            }
+        }
+
+        match self.cancel_invoice(&invoice.payment_hash).await {
+            Ok(()) => Err(RebalanceError::Failed(error).into()),
+            Err(e) => Err(RebalanceError::Unknown(format!(
+                "{}, and canceling invoice {} failed: {}",
+                error, invoice.payment_hash, e
+            ))
+            .into()),
+        }
+    }
+
+    /// Pays the node's own invoice out through `req.outgoing_chan_id` and
+    /// back in from `req.last_hop_pubkey`.
+    async fn pay_circular(
+        &self,
+        payment_request: String,
+        req: &ClusterCircularRebalance,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let lnd_req = LndSendPaymentSyncReq {
+                    payment_request,
+                    amt: "0".to_string(),
+                    fee_limit: FeePolicy::ppm(req.max_fee_ppm).to_lnd(req.amount_sat * 1000),
+                    allow_self_payment: true,
+                    outgoing_chan_id: Some(req.outgoing_chan_id.clone()),
+                    last_hop_pubkey: Some(crate::lnd::to_base64(&req.last_hop_pubkey)?),
+                    ..Default::default()
+                };
+                let payment = client.send_payment_sync(lnd_req).await?;
+                Ok(payment.to_cluster(self.pubkey.clone()))
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Rebalancing")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Connects to `peer`, given as `pubkey` or `pubkey@host:port`, unless
+    /// already connected. Returns the peer's pubkey.
+    pub async fn connect_peer(&self, peer: &str) -> Result<String> {
+        let (peer_pubkey, host) = match peer.split_once('@') {
+            Some((peer_pubkey, host)) => (peer_pubkey, Some(host)),
+            None => (peer, None),
+        };
+
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let peers = client.list_peers().await?;
+                if peers.peers.iter().any(|peer| peer.pub_key == peer_pubkey) {
+                    return Ok(peer_pubkey.to_string());
+                }
+
+                let host = host.ok_or_else(|| {
+                    anyhow::anyhow!("Not connected to {}, provide pubkey@host:port", peer_pubkey)
+                })?;
+                let req = ConnectPeerLndRequest {
+                    addr: LightningAddress {
+                        pubkey: peer_pubkey.to_string(),
+                        host: host.to_string(),
+                    },
+                    perm: false,
+                    timeout: "30".to_string(),
+                };
+                client.connect_peer(req).await?;
+
+                Ok(peer_pubkey.to_string())
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Connecting peers")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Returns once the funding transaction is published.
+    pub async fn open_channel(&self, req: &ClusterOpenChannel) -> Result<ClusterChannelOperation> {
+        let peer = self.connect_peer(&req.peer).await?;
+
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let lnd_req = OpenChannelLndRequest {
+                    node_pubkey: crate::lnd::to_base64(&peer)?,
+                    local_funding_amount: req.amount.to_string(),
+                    push_sat: req.push_sat.to_string(),
+                    private: req.private,
+                    target_conf: req.fee.target_conf(),
+                    sat_per_vbyte: req.fee.sat_per_vbyte(),
+                };
+                let stream = client.open_channel(lnd_req).await?;
+                follow_channel_stream(&self.pubkey, &peer, None, stream).await
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Opening channels")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    /// Returns once the closing transaction is published.
+    pub async fn close_channel(
+        &self,
+        channel: &ClusterChannel,
+        force: bool,
+        fee: Option<ClusterOnchainFee>,
+    ) -> Result<ClusterChannelOperation> {
+        let (funding_txid, output_index) = channel
+            .channel_point
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid channel point {}", channel.channel_point))?;
+
+        match &self.client {
+            NodeClient::Lnd(client) => {
+                let stream = client
+                    .close_channel(
+                        funding_txid,
+                        output_index.parse()?,
+                        force,
+                        fee.and_then(|fee| fee.target_conf()),
+                        fee.and_then(|fee| fee.sat_per_vbyte()),
+                    )
+                    .await?;
+                follow_channel_stream(
+                    &self.pubkey,
+                    &channel.peer,
+                    Some(&channel.channel_point),
+                    stream,
+                )
+                .await
+            }
+            NodeClient::CLightning(_) => Err(cln_unsupported("Closing channels")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+
+    pub async fn onchain_balance(&self) -> Result<ClusterOnchainBalance> {
+        match &self.client {
+            NodeClient::Lnd(client) => client.wallet_balance().await?.to_cluster(),
+            NodeClient::CLightning(_) => Err(cln_unsupported("On-chain balances")),
+            _ => {
+                panic!("We only support LND nodes at this time.")
+            }
+        }
+    }
+}
+
+impl Cluster {
+    pub fn new(
+        nodes: Vec<Node>,
+        redis: redis::aio::Connection,
+        inv_exp_sec: i64,
+        addr_exp_sec: i64,
+        utxo_exp_sec: i64,
+    ) -> Cluster {
+        Self {
+            nodes,
+            cache: redis,
+            inv_exp_sec,
+            addr_exp_sec,
+            utxo_exp_sec,
+            channel_exp_sec: 60,
+            events: None,
+            route_by_fee: false,
+            spend_limits: SpendLimits::default(),
+            destination_policy: DestinationPolicy::default(),
+            fee_policy: FeePolicy::percent(1.0).with_floor(10),
+        }
+    }
+
+    /// Returns a receiver for cluster events. Every subscriber receives
+    /// every event emitted after it subscribed.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<ClusterEvent> {
+        self.events
+            .get_or_insert_with(ClusterEventSender::default)
+            .subscribe()
+    }
+
+    fn emit(&self, event: ClusterEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    fn emit_payment(&self, payment: &ClusterPayPaymentRequestRes) {
+        if payment.payment_error.is_some() {
+            self.emit(ClusterEvent::PaymentFailed(payment.clone()));
+        } else if payment.payment_preimage.is_some() {
+            self.emit(ClusterEvent::PaymentSucceeded(payment.clone()));
+        }
+    }
+
+    /// Spawns a task per node supporting invoices that follows LND's invoice
+    /// subscription and publishes an event for every accepted or settled
+    /// invoice.
+    pub fn watch_invoices(&self) -> Result<Vec<JoinHandle<()>>> {
+        let events = self
+            .events
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Call subscribe before watching invoices"))?;
+
+        let mut handles = vec![];
+        for node in self.nodes.iter().filter(|node| node.supports_invoices()) {
+            let client = match &node.client {
+                NodeClient::Lnd(client) => client.clone(),
+                _ => {
+                    panic!("We only support LND nodes at this time.")
+                }
+            };
+            let pubkey = node.pubkey.clone();
+            let events = events.clone();
+
+            handles.push(tokio::spawn(async move {
+                // resubscribing from the last indexes seen replays the
+                // invoices added or settled while disconnected
+                let mut add_index = 0;
+                let mut settle_index = 0;
+                while !events.is_closed() {
+                    let mut stream = match client.subscribe_invoices(add_index, settle_index).await
+                    {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("invoice subscription failed for {}: {}", pubkey, e);
+                            tokio::time::sleep(Duration::from_secs(5)).await;
+                            continue;
+                        }
+                    };
+
+                    loop {
+                        match stream.next().await {
+                            Ok(Some(invoice)) => {
+                                add_index = add_index.max(invoice.add_index.parse().unwrap_or(0));
+                                settle_index =
+                                    settle_index.max(invoice.settle_index.parse().unwrap_or(0));
+                                let invoice = invoice.to_cluster(&pubkey);
+                                let hexed_invoice =
+                                    match (to_hex(&invoice.r_hash), to_hex(&invoice.r_preimage)) {
+                                        (Ok(r_hash), Ok(r_preimage)) => ClusterLookupInvoice {
+                                            r_hash,
+                                            r_preimage,
+                                            ..invoice
+                                        },
+                                        _ => continue,
+                                    };
+                                let event = match hexed_invoice.state {
+                                    ClusterInvoiceState::Accepted => {
+                                        ClusterEvent::InvoiceAccepted(hexed_invoice)
+                                    }
+                                    ClusterInvoiceState::Settled => {
+                                        ClusterEvent::InvoiceSettled(hexed_invoice)
+                                    }
+                                    _ => continue,
+                                };
+                                if events.send(event).is_err() {
+                                    return;
+                                }
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                eprintln!("invoice subscription failed for {}: {}", pubkey, e);
+                                break;
+                            }
+                        }
+                    }
+
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }));
+        }
+
+        Ok(handles)
+    }
+
+    /// Spawns `watcher`, publishing deposit events for addresses issued by
+    /// `next_address` on every node.
+    pub fn watch_deposits(&self, watcher: DepositWatcher) -> Result<JoinHandle<()>> {
+        let events = self
+            .events
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Call subscribe before watching deposits"))?;
+
+        let nodes = self
+            .nodes_where(None, Node::supports_wallet)
+            .into_iter()
+            .cloned()
+            .collect();
+        Ok(tokio::spawn(watcher.run(nodes, events)))
+    }
+
+    pub async fn lookup_invoice(
+        &mut self,
+        r_hash: &str,
+        pubkey: Option<String>,
+    ) -> Result<ClusterLookupInvoice> {
+        let cached_invoice = self.cache.get(r_hash.to_string()).await?;
+
+        match cached_invoice {
+            Some(invoice) => {
+                eprintln!("cached");
+                Ok(invoice)
+            },
+            None => {
+                if let Some(pubkey) = pubkey {
+                    let node = self
+                        .nodes
+                        .iter()
+                        .find(|node| node.pubkey == pubkey)
+                        .unwrap();
+                    let invoice = node.lookup_invoice(r_hash).await?;
+                    let hexed_invoice = ClusterLookupInvoice {
+                        r_hash: to_hex(&invoice.r_hash)?,
+                        r_preimage: to_hex(&invoice.r_preimage)?,
+                        ..invoice
+                    };
+                    let json_string = serde_json::to_string(&hexed_invoice).unwrap();
+
+                    let _: Result<ClusterLookupInvoice, _> = self.cache
+                        .set_ex(
+                            r_hash.to_string(),
+                            json_string,
+                            self.inv_exp_sec as usize,
+                        ).await;
+                        eprintln!("requested invoice from node");
+                    Ok(hexed_invoice)
+                } else {
+                    // Make calls to all nodes to find who owns the invoice
+                    let mut tasks = vec![];
+                    for node in self.nodes.iter().filter(|node| node.supports_invoices()) {
+                        let task = node.lookup_invoice(r_hash);
+                        tasks.push(task);
+                    }
+
+                    // Wait for all tasks to complete and find the first successful result
+                    let success_result = match futures::future::join_all(tasks)
+                        .await
+                        .into_iter()
+                        .find_map(|result| result.ok())
+                    {
+                        Some(success_result) => success_result,
+                        None => return Err(anyhow::Error::msg("No nodes found this invoice.")),
+                    };
+
+                    let hexed_invoice = ClusterLookupInvoice {
+                        r_hash: to_hex(&success_result.r_hash)?,
+                        r_preimage: to_hex(&success_result.r_preimage)?,
+                        ..success_result.clone()
+                    };
+
+                    let json_invoice = serde_json::to_string(&hexed_invoice).unwrap();
+
+                    // Insert the successful result into the cache
+                    let _: Result<ClusterLookupInvoice, _> = self.cache
+                        .set_ex(
+                            r_hash.to_string(),
+                            json_invoice,
+                            self.inv_exp_sec as usize,
+                        )
+                        .await;
+
+                        eprintln!("requested invoice from node");
+
+                    Ok(hexed_invoice)
+                }
+            }
+        }
+    }
+
+    /// Lists invoices from every node (or `req.pubkey`) merged into one
+    /// stream ordered by creation date. Pass the returned `next_cursor` back
+    /// in `req.cursor` to read the next page. Pages can hold fewer than
+    /// `limit` invoices when `state` or settle date filters are set.
+    pub async fn list_invoices(&self, req: ClusterListInvoices) -> Result<ClusterInvoicePage> {
+        let cursor = match &req.cursor {
+            Some(cursor) => ClusterInvoiceCursor::decode(cursor)?,
+            None => ClusterInvoiceCursor::default(),
+        };
+        let limit = if req.limit == 0 { 100 } else { req.limit };
+
+        let mut tasks = vec![];
+        for node in self.nodes_where(req.pubkey.as_deref(), Node::supports_invoices) {
+            let index_offset = cursor.offsets.get(&node.pubkey).copied().unwrap_or(0);
+            tasks.push(node.list_invoices(&req, index_offset, limit));
+        }
+
+        let pages = futures::future::join_all(tasks)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(merge_invoice_pages(pages, &cursor, &req, limit))
+    }
+
+    pub async fn add_invoice(
+        &mut self,
+        req: ClusterAddInvoice,
+        pubkey: Option<String>,
+    ) -> Result<AddInvoiceResponse> {
+        req.validate()?;
+
+        let callback_url = req.callback_url.clone();
+        let expiry = req.expiry;
+
+        let invoice = match pubkey {
+            Some(pubkey) => {
+                let node = self
+                    .nodes
+                    .iter()
+                    .find(|node| node.pubkey == pubkey)
+                    .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey"))?;
+                node.add_invoice(req).await?
+            }
+            None => {
+                let node = self.select_node_where(None, Node::supports_invoices, "invoices")?;
+                node.add_invoice(req).await?
+            }
+        };
+
+        // the invoice is payable already, so a failed registration must not
+        // hide it from the caller
+        if let Some(url) = callback_url {
+            if let Err(e) = webhook::register_callback(
+                &mut self.cache,
+                &invoice.r_hash,
+                &url,
+                invoice_expiry_sec(expiry),
+            )
+            .await
+            {
+                eprintln!("failed to register callback for {}: {}", invoice.r_hash, e);
+            }
+        }
+
+        Ok(invoice)
+    }
+
+    pub async fn add_hold_invoice(
+        &mut self,
+        req: ClusterAddHoldInvoice,
+        pubkey: Option<String>,
+    ) -> Result<AddInvoiceResponse> {
+        req.validate()?;
+
+        let callback_url = req.callback_url.clone();
+        let expiry = req.expiry;
+
+        let node = match pubkey {
+            Some(pubkey) => self
+                .nodes
+                .iter()
+                .find(|node| node.pubkey == pubkey)
+                .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey"))?,
+            None => self.select_node_where(None, Node::supports_invoices, "invoices")?,
+        };
+        let invoice = node.add_hold_invoice(req).await?;
+        let owner = node.pubkey.clone();
+
+        let _: Result<String, _> = self
+            .cache
+            .set_ex(
+                hold_invoice_key(&invoice.r_hash),
+                owner,
+                self.inv_exp_sec.max(invoice_expiry_sec(expiry) as i64) as usize,
+            )
+            .await;
+
+        if let Some(url) = callback_url {
+            if let Err(e) = webhook::register_callback(
+                &mut self.cache,
+                &invoice.r_hash,
+                &url,
+                invoice_expiry_sec(expiry),
+            )
+            .await
+            {
+                eprintln!("failed to register callback for {}: {}", invoice.r_hash, e);
+            }
+        }
+
+        Ok(invoice)
+    }
+
+    /// Stores a payer's note for an invoice that can't go in its memo, such
+    /// as an LNURL-pay comment on a `description_hash` invoice.
+    pub async fn set_invoice_comment(
+        &mut self,
+        r_hash: &str,
+        comment: &str,
+        expiry: i64,
+    ) -> Result<()> {
+        let _: () = self
+            .cache
+            .set_ex(
+                comment_key(r_hash),
+                comment,
+                self.inv_exp_sec.max(expiry) as usize,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The note stored with `set_invoice_comment`, by hex encoded payment
+    /// hash.
+    pub async fn invoice_comment(&mut self, r_hash: &str) -> Result<Option<String>> {
+        Ok(self.cache.get(comment_key(r_hash)).await?)
+    }
+
+    /// Settles an accepted hold invoice on the node that owns it.
+    pub async fn settle_hold_invoice(&mut self, preimage: &str) -> Result<()> {
+        let r_hash = hex::encode(Sha256::digest(hex::decode(preimage)?));
+        let owner = self.hold_invoice_owner(&r_hash).await?;
+        let node = self
+            .nodes
+            .iter()
+            .find(|node| node.pubkey == owner)
+            .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey"))?;
+
+        node.settle_invoice(preimage).await?;
+
+        // drop the cached lookup so the settled state is picked up
+        let _: Result<(), _> = self.cache.del(&r_hash).await;
+        Ok(())
+    }
+
+    /// Cancels a hold invoice on the node that owns it.
+    pub async fn cancel_hold_invoice(&mut self, r_hash: &str) -> Result<()> {
+        let owner = self.hold_invoice_owner(r_hash).await?;
+        let node = self
+            .nodes
+            .iter()
+            .find(|node| node.pubkey == owner)
+            .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey"))?;
+
+        node.cancel_invoice(r_hash).await?;
+
+        let _: Result<(), _> = self.cache.del(r_hash).await;
+        Ok(())
+    }
+
+    async fn hold_invoice_owner(&mut self, r_hash: &str) -> Result<String> {
+        let owner: Option<String> = self.cache.get(hold_invoice_key(r_hash)).await?;
+
+        match owner {
+            Some(owner) => Ok(owner),
+            None => Ok(self.lookup_invoice(r_hash, None).await?.pubkey),
+        }
+    }
+
+    pub async fn next_address(
+        &mut self,
+        pubkey: Option<String>,
+        req: ClusterNewAddress,
+    ) -> Result<String> {
+        match pubkey {
+            Some(pubkey) => {
+                let node = self
+                    .nodes
+                    .iter()
+                    .find(|node| node.pubkey == pubkey)
+                    .unwrap();
+
+                let addr = node.next_address(req).await?;
+
+                let _: Result<String, _> = self.cache
+                    .set_ex(
+                        addr.clone(),
+                        node.clone().pubkey,
+                        self.addr_exp_sec as usize,
+                    )
+                    .await;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let _ = deposits::track_address(&mut self.cache, &node.pubkey, &addr, now).await;
+                Ok(addr)
+            }
+            None => {
+                let node = self
+                    .select_node_where(None, Node::supports_wallet, "on-chain wallets")?
+                    .clone();
+
+                let addr = node.next_address(req).await?;
+
+                let _: Result<String, _> = self.cache.set_ex(
+                    addr.clone(),
+                    node.clone().pubkey,
+                    self.addr_exp_sec as usize,
+                ).await;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let _ = deposits::track_address(&mut self.cache, &node.pubkey, &addr, now).await;
+                Ok(addr)
+            }
+        }
+    }
+
+    /// The pubkey of the node that issued `address`. Addresses whose cache
+    /// entry expired are found by asking every node whether it has received
+    /// to the address within the last `ADDRESS_SCAN_BLOCKS` blocks; nodes that
+    /// fail to answer are skipped.
+    pub async fn address_owner(&mut self, address: &str) -> Result<Option<String>> {
+        let pubkey: Option<String> = self.cache.get(address).await?;
+        if pubkey.is_some() {
+            return Ok(pubkey);
+        }
+
+        let nodes = self.nodes_where(None, Node::supports_wallet);
+        let tasks = nodes.iter().map(|node| node.address_deposits(address));
+        let deposits = futures::future::join_all(tasks).await;
+
+        // An unreachable node only matters when no other node owns the address.
+        let mut error = None;
+        for (node, deposits) in nodes.iter().zip(deposits) {
+            match deposits {
+                Ok(deposits) if !deposits.is_empty() => return Ok(Some(node.pubkey.clone())),
+                Ok(_) => {}
+                Err(e) => error = Some(e),
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// Deposits to an address issued by `next_address`, from whichever node
+    /// owns it.
+    pub async fn lookup_address_deposits(
+        &mut self,
+        address: &str,
+    ) -> Result<Vec<ClusterAddressDeposit>> {
+        let pubkey = self
+            .address_owner(address)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No node in the cluster owns address {}", address))?;
+        let node = self.select_node(Some(&pubkey))?;
+
+        node.address_deposits(address).await
+    }
+
+    pub async fn list_utxos(&mut self, pubkey: Option<&str>) -> Result<ClusterUtxos> {
+        match pubkey {
+            Some(pubkey) => {
+                let node = self
+                    .nodes
+                    .iter()
+                    .find(|node| node.pubkey == pubkey)
+                    .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey"))?;
+
+                let cache_key = format!("utxos:{}", node.pubkey);
+                let cached_utxos = self.cache.get(&cache_key).await?;
+
+                match cached_utxos {
+                    Some(utxos) => Ok(utxos),
+                    None => {
+                        let utxos = node.list_utxos().await?;
+                        let json_utxos = serde_json::to_string(&utxos)?;
+                        let _: Result<ClusterUtxos, _> = self.cache.set_ex(
+                            cache_key,
+                            json_utxos,
+                            self.utxo_exp_sec as usize,
+                        ).await;
+                        Ok(utxos)
+                    }
+                }
+            }
+            None => {
+                let mut cluster_utxos = ClusterUtxos { utxos: vec![] };
+
+                let nodes: Vec<Node> = self
+                    .nodes_where(None, Node::supports_wallet)
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                for node in &nodes {
+                    let cache_key = format!("utxos:{}", node.pubkey);
+                    let cached_utxos = self.cache.get(&cache_key).await?;
+
+                    let node_utxos = match cached_utxos {
+                        Some(utxos) => utxos,
+                        None => {
+                            let fetched_utxos = node.list_utxos().await?;
+                            let json_utxos = serde_json::to_string(&fetched_utxos)?;
+                            let _: Result<ClusterUtxos, _> = self.cache.set_ex(
+                                cache_key,
+                                json_utxos,
+                                self.utxo_exp_sec as usize,
+                            ).await;
+                            fetched_utxos
+                        }
+                    };
+
+                    cluster_utxos.utxos.extend(node_utxos.utxos);
+                }
+
+                Ok(cluster_utxos)
+            }
+        }
+    }
+
+    /// Opens a channel from the given node, connecting to the peer first if
+    /// needed. Returns once the funding transaction is published, with the
+    /// updates until the channel confirms.
+    pub async fn open_channel(
+        &mut self,
+        req: ClusterOpenChannel,
+        pubkey: &str,
+    ) -> Result<ClusterChannelOperation> {
+        req.validate()?;
+        let node = self.select_node(Some(pubkey))?;
+
+        let operation = node.open_channel(&req).await?;
+        // the funding transaction is published, a cache error must not lose
+        // the pending channel and its updates
+        let _: Result<(), _> = self.cache.del(channels_key(pubkey)).await;
+
+        Ok(operation)
+    }
+
+    /// Closes a channel from whichever node owns `channel_point`. Returns
+    /// once the closing transaction is published, with the updates until it
+    /// confirms. A fee can't be set for a force close.
+    pub async fn close_channel(
+        &mut self,
+        channel_point: &str,
+        force: bool,
+        fee: Option<ClusterOnchainFee>,
+    ) -> Result<ClusterChannelOperation> {
+        if let Some(fee) = &fee {
+            if force {
+                return Err(anyhow::anyhow!("A fee can't be set for a force close"));
+            }
+            fee.validate()?;
+        }
+
+        let channel = self
+            .list_channels(None)
+            .await?
+            .into_iter()
+            .find(|channel| channel.channel_point == channel_point)
+            .ok_or_else(|| {
+                anyhow::anyhow!("No node in the cluster has channel {}", channel_point)
+            })?;
+        let node = self.select_node(Some(&channel.pubkey))?;
+
+        let operation = node.close_channel(&channel, force, fee).await?;
+        let _: Result<(), _> = self.cache.del(channels_key(&channel.pubkey)).await;
+
+        Ok(operation)
+    }
+
+    /// Moves `amount_sat` of outbound liquidity from `from` to `to` by paying
+    /// an invoice of `to` from `from`, for at most `max_fee_sat`. The
+    /// invoice's preimage is generated here, and the rebalance only succeeds
+    /// when the payment returns it. Otherwise the invoice is canceled so a
+    /// late payment can't settle it.
+    pub async fn rebalance(
+        &mut self,
+        from: &str,
+        to: &str,
+        amount_sat: u64,
+        max_fee_sat: u64,
+    ) -> Result<ClusterRebalance> {
+        if from == to {
+            return Err(anyhow::anyhow!("Can't rebalance a node with itself"));
+        }
+        if amount_sat == 0 {
+            return Err(anyhow::anyhow!("amount must be positive"));
+        }
+        let from_node = self.select_node(Some(from))?;
+        let to_node = self.select_node(Some(to))?;
+        if let Some(node) = [from_node, to_node]
+            .iter()
+            .find(|node| !node.supports_channels())
+        {
+            return Err(anyhow::anyhow!(
+                "Node {} does not support rebalancing",
+                node.pubkey
+            ));
+        }
+
+        let invoice = to_node
+            .add_rebalance_invoice(amount_sat, format!("rebalance from {}", from))
+            .await?;
+        let payment = from_node
+            .pay_invoice(
+                0,
+                invoice.payment_request.clone(),
+                &FeePolicy::fixed(max_fee_sat),
+            )
+            .await;
+        let payment = to_node
+            .verify_rebalance(&invoice, payment)
+            .await
+            .map_err(|e| e.context(format!("Rebalance from {} to {} failed", from, to)))?;
+        if let Some(payment) = &payment {
+            self.emit_payment(payment);
+        }
+
+        // the channel listings are stale either way, an error here must not
+        // hide the completed rebalance
+        let _: Result<(), _> = self
+            .cache
+            .del(&[channels_key(from), channels_key(to)])
+            .await;
+
+        Ok(invoice.to_cluster(from, to, amount_sat, payment.as_ref()))
+    }
+
+    /// Moves `req.amount_sat` of a node's outbound liquidity from the
+    /// outgoing channel to its channel with the last hop, by paying itself
+    /// along a circular route.
+    pub async fn circular_rebalance(
+        &mut self,
+        pubkey: &str,
+        req: ClusterCircularRebalance,
+    ) -> Result<ClusterRebalance> {
+        req.validate()?;
+        let channels = self.list_channels(Some(pubkey)).await?;
+        let outgoing = channels
+            .iter()
+            .find(|channel| channel.chan_id == req.outgoing_chan_id)
+            .ok_or_else(|| anyhow::anyhow!("Node has no channel {}", req.outgoing_chan_id))?;
+        if outgoing.peer == req.last_hop_pubkey {
+            return Err(anyhow::anyhow!(
+                "The last hop must not be the outgoing channel's peer"
+            ));
+        }
+        if !channels
+            .iter()
+            .any(|channel| channel.peer == req.last_hop_pubkey)
+        {
+            return Err(anyhow::anyhow!(
+                "Node has no channel with {}",
+                req.last_hop_pubkey
+            ));
+        }
+        let node = self.select_node(Some(pubkey))?;
+
+        let invoice = node
+            .add_rebalance_invoice(
+                req.amount_sat,
+                format!(
+                    "rebalance {} to {}",
+                    req.outgoing_chan_id, req.last_hop_pubkey
+                ),
+            )
+            .await?;
+        let payment = node
+            .pay_circular(invoice.payment_request.clone(), &req)
+            .await;
+        let payment = node
+            .verify_rebalance(&invoice, payment)
+            .await
+            .map_err(|e| e.context(format!("Circular rebalance of {} failed", pubkey)))?;
+        if let Some(payment) = &payment {
+            self.emit_payment(payment);
+        }
+
+        let _: Result<(), _> = self.cache.del(channels_key(pubkey)).await;
+
+        Ok(invoice.to_cluster(pubkey, pubkey, req.amount_sat, payment.as_ref()))
+    }
+
+    /// Plans circular rebalances from overfull to depleted channels on every
+    /// node. Unless `dry_run`, the plans are executed in order from fresh
+    /// channel balances, each reserving its maximum fee from the daily fee
+    /// budget first. Plans that don't fit the budget are skipped. The unused
+    /// part of a reservation is given back only when the fee actually paid
+    /// is known, see `budget_refund_sat`.
+    pub async fn circular_rebalance_channels(
+        &mut self,
+        policy: &CircularRebalancePolicy,
+        dry_run: bool,
+    ) -> Result<Vec<CircularRebalancePlan>> {
+        policy.validate()?;
+
+        if !dry_run {
+            let keys: Vec<String> = self
+                .nodes
+                .iter()
+                .map(|node| channels_key(&node.pubkey))
+                .collect();
+            let _: () = self.cache.del(keys).await?;
+        }
+        let channels = self.list_channels(None).await?;
+
+        let mut plans = plan_circular_rebalances(&channels, policy);
+        if dry_run {
+            return Ok(plans);
+        }
+
+        let budget_key =
+            rebalance::fee_budget_key(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+        for plan in &mut plans {
+            let reserved: u64 = self.cache.incr(&budget_key, plan.max_fee_sat).await?;
+            let _: () = self.cache.expire(&budget_key, 2 * 86400).await?;
+            if reserved > policy.daily_fee_budget_sat {
+                let _: () = self.cache.decr(&budget_key, plan.max_fee_sat).await?;
+                plan.error = Some("Daily rebalance fee budget exhausted".to_string());
+                continue;
+            }
+
+            let req = ClusterCircularRebalance {
+                outgoing_chan_id: plan.outgoing_chan_id.clone(),
+                last_hop_pubkey: plan.last_hop_pubkey.clone(),
+                amount_sat: plan.amount_sat,
+                max_fee_ppm: policy.max_fee_ppm,
+            };
+            let pubkey = plan.pubkey.clone();
+            let result = self.circular_rebalance(&pubkey, req).await;
+
+            // a failed refund only leaves the budget counting too much, the
+            // outcome of the rebalance must still be recorded
+            let refund = rebalance::budget_refund_sat(plan.max_fee_sat, &result);
+            if refund > 0 {
+                let _: Result<(), _> = self.cache.decr(&budget_key, refund).await;
+            }
+            match result {
+                Ok(rebalance) => plan.rebalance = Some(rebalance),
+                Err(e) => plan.error = Some(e.to_string()),
+            }
+        }
+
+        Ok(plans)
+    }
+
+    /// Rebalance fees reserved or spent today by `circular_rebalance_channels`.
+    pub async fn rebalance_fees_today(&mut self) -> Result<u64> {
+        let budget_key =
+            rebalance::fee_budget_key(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+        let spent: Option<u64> = self.cache.get(budget_key).await?;
+        Ok(spent.unwrap_or(0))
+    }
+
+    /// Plans rebalances that bring every node's local ratio back within its
+    /// band. Unless `dry_run`, the plans are executed in order from fresh
+    /// channel balances, recording each outcome on its plan.
+    pub async fn auto_rebalance(
+        &mut self,
+        policy: &AutoRebalancePolicy,
+        dry_run: bool,
+    ) -> Result<Vec<RebalancePlan>> {
+        policy.validate()?;
+
+        if !dry_run {
+            let keys: Vec<String> = self
+                .nodes
+                .iter()
+                .map(|node| channels_key(&node.pubkey))
+                .collect();
+            let _: () = self.cache.del(keys).await?;
+        }
+        let report = self.liquidity_report(None).await?;
+
+        let mut plans = plan_rebalances(&report, policy);
+        if dry_run {
+            return Ok(plans);
+        }
+
+        for plan in &mut plans {
+            let result = self
+                .rebalance(
+                    &plan.from,
+                    &plan.to,
+                    plan.amount_sat as u64,
+                    plan.max_fee_sat,
+                )
+                .await;
+            match result {
+                Ok(rebalance) => plan.rebalance = Some(rebalance),
+                Err(e) => plan.error = Some(e.to_string()),
+            }
+        }
+
+        Ok(plans)
+    }
+
+    /// Channels of one node, or of every node when no pubkey is given.
+    /// Each node's channels are cached for `channel_exp_sec`.
+    pub async fn list_channels(&mut self, pubkey: Option<&str>) -> Result<Vec<ClusterChannel>> {
+        let nodes: Vec<Node> = self
+            .nodes_where(pubkey, Node::supports_channels)
+            .into_iter()
+            .cloned()
+            .collect();
+        if nodes.is_empty() {
+            return Err(anyhow::anyhow!("Node not found with provided pubkey"));
+        }
+
+        let mut channels = vec![];
+        for node in nodes {
+            let cache_key = channels_key(&node.pubkey);
+            let cached: Option<String> = self.cache.get(&cache_key).await?;
+
+            let node_channels: Vec<ClusterChannel> = match cached {
+                Some(json) => serde_json::from_str(&json)?,
+                None => {
+                    let fetched = node.list_channels().await?;
+                    let json = serde_json::to_string(&fetched)?;
+                    let _: () = self
+                        .cache
+                        .set_ex(cache_key, json, self.channel_exp_sec as usize)
+                        .await?;
+                    fetched
+                }
+            };
+
+            channels.extend(node_channels);
+        }
+
+        Ok(channels)
+    }
 
-                    let _: Result<ClusterLookupInvoice, _> = self.cache
-                        .set_ex(
-                            r_hash.to_string(),
-                            json_string,
-                            self.inv_exp_sec as usize,
-                        ).await;
-                        eprintln!("requested invoice from node");
-                    Ok(hexed_invoice)
-                } else {
-                    // Make calls to all nodes to find who owns the invoice
-                    let mut tasks = vec![];
-                    for node in &self.nodes {
-                        let r_hash_clone = r_hash.clone();
-                        let task = node.lookup_invoice(r_hash_clone);
-                        tasks.push(task);
-                    }
+    /// Inbound and outbound liquidity of one node, or of every node and the
+    /// cluster total when no pubkey is given. Built from `list_channels`,
+    /// so it is as fresh as the cached channels.
+    pub async fn liquidity_report(
+        &mut self,
+        pubkey: Option<&str>,
+    ) -> Result<ClusterLiquidityReport> {
+        let channels = self.list_channels(pubkey).await?;
 
-                    // Wait for all tasks to complete and find the first successful result
-                    let success_result = match futures::future::join_all(tasks)
-                        .await
-                        .into_iter()
-                        .enumerate()
-                        .find_map(|(_index, result)| result.ok())
-                    {
-                        Some(success_result) => success_result,
-                        None => return Err(anyhow::Error::msg("No nodes found this invoice.")),
-                    };
+        Ok(ClusterLiquidityReport::from_channels(&channels))
+    }
 
-                    let hexed_invoice = ClusterLookupInvoice {
-                        r_hash: to_hex(&success_result.r_hash)?,
-                        r_preimage: to_hex(&success_result.r_preimage)?,
-                        ..success_result.clone()
-                    };
+    /// On-chain balance of one node, or of every node and the cluster total
+    /// when no pubkey is given.
+    pub async fn onchain_balance(&self, pubkey: Option<&str>) -> Result<ClusterOnchainBalances> {
+        let nodes = self.nodes_where(pubkey, Node::supports_wallet);
+        if nodes.is_empty() {
+            return Err(anyhow::anyhow!("Node not found with provided pubkey"));
+        }
 
-                    let json_invoice = serde_json::to_string(&hexed_invoice).unwrap();
+        let tasks = nodes.iter().map(|node| node.onchain_balance());
+        let results = futures::future::join_all(tasks).await;
 
-                    // Insert the successful result into the cache
-                    let _: Result<ClusterLookupInvoice, _> = self.cache
-                        .set_ex(
-                            r_hash.to_string(),
-                            json_invoice,
-                            self.inv_exp_sec as usize,
-                        )
-                        .await;
+        let mut balances = ClusterOnchainBalances::default();
+        for (node, balance) in nodes.iter().zip(results) {
+            let balance = balance?;
+            balances.total += balance;
+            balances.nodes.insert(node.pubkey.clone(), balance);
+        }
 
-                        eprintln!("requested invoice from node");
+        Ok(balances)
+    }
 
-                    Ok(hexed_invoice)
+    /// Sends on-chain from the given node, or from a random node when none
+    /// is given.
+    pub async fn send_onchain(
+        &self,
+        req: ClusterSendOnchain,
+        pubkey: Option<String>,
+    ) -> Result<ClusterOnchainTx> {
+        req.validate()?;
+        let node =
+            self.select_node_where(pubkey.as_deref(), Node::supports_wallet, "on-chain wallets")?;
+
+        node.send_onchain(&req).await
+    }
+
+    /// Plans consolidating small UTXOs on every node, fetched fresh from the
+    /// nodes. Unless `dry_run`, each worthwhile plan is swept into a new address
+    /// of the same node; a failed sweep is recorded on its plan.
+    pub async fn consolidate_utxos(
+        &mut self,
+        policy: &ConsolidationPolicy,
+        dry_run: bool,
+    ) -> Result<ConsolidationReport> {
+        let nodes = self.nodes_where(None, Node::supports_wallet);
+        let tasks = nodes.iter().map(|node| node.list_utxos());
+        let mut utxos = vec![];
+        for node_utxos in futures::future::join_all(tasks).await {
+            utxos.extend(node_utxos?.utxos);
+        }
+
+        let mut report = plan_consolidation(&utxos, policy);
+        if dry_run {
+            return Ok(report);
+        }
+
+        for plan in report.plans.iter_mut().filter(|plan| plan.worthwhile()) {
+            match self.sweep_consolidation(plan, policy).await {
+                Ok(tx) => {
+                    plan.tx = Some(tx);
+                    let _: Result<(), _> = self.cache.del(format!("utxos:{}", plan.pubkey)).await;
                 }
+                Err(e) => plan.error = Some(e.to_string()),
             }
         }
+
+        Ok(report)
     }
 
-    pub async fn add_invoice(
-        &self,
-        req: ClusterAddInvoice,
+    async fn sweep_consolidation(
+        &mut self,
+        plan: &ConsolidationPlan,
+        policy: &ConsolidationPolicy,
+    ) -> Result<ClusterOnchainTx> {
+        let addr = self
+            .next_address(Some(plan.pubkey.clone()), ClusterNewAddress::default())
+            .await?;
+        let node = self.select_node(Some(&plan.pubkey))?;
+
+        node.sweep_utxos(
+            &plan.utxos,
+            &addr,
+            ClusterOnchainFee::SatPerVbyte(policy.sat_per_vbyte),
+            Some("consolidation".to_string()),
+        )
+        .await
+    }
+
+    /// Moves the confirmed, unlocked on-chain funds of `from_pubkey` above
+    /// `keep_sat` to a fresh address of `to_pubkey`. `keep_sat` must leave
+    /// room for the fee. Returns `None` when there is no excess.
+    pub async fn sweep_excess(
+        &mut self,
+        from_pubkey: &str,
+        to_pubkey: &str,
+        keep_sat: i64,
+        fee: ClusterOnchainFee,
+        dry_run: bool,
+    ) -> Result<Option<SweepPlan>> {
+        if from_pubkey == to_pubkey {
+            return Err(anyhow::anyhow!("Can not sweep a node to itself"));
+        }
+        fee.validate()?;
+
+        self.select_node(Some(to_pubkey))?;
+        let from = self.select_node(Some(from_pubkey))?;
+
+        let balance = from.onchain_balance().await?;
+        let amount_sat = balance.confirmed - balance.locked - keep_sat;
+        if amount_sat <= 0 {
+            return Ok(None);
+        }
+
+        let mut plan = SweepPlan {
+            from_pubkey: from_pubkey.to_string(),
+            to_pubkey: to_pubkey.to_string(),
+            amount_sat,
+            address: None,
+            tx: None,
+        };
+        if dry_run {
+            return Ok(Some(plan));
+        }
+
+        let addr = self
+            .next_address(Some(to_pubkey.to_string()), ClusterNewAddress::default())
+            .await?;
+        let req = ClusterSendOnchain {
+            addr: addr.clone(),
+            amount: amount_sat,
+            fee,
+            label: Some("sweep".to_string()),
+        };
+        let from = self.select_node(Some(from_pubkey))?;
+        plan.tx = Some(from.send_onchain(&req).await?);
+        plan.address = Some(addr);
+
+        Ok(Some(plan))
+    }
+
+    /// Funds a PSBT on the given node, or a random node, leasing the
+    /// selected inputs. The flow is tracked until it is published or
+    /// released with `release_psbt`.
+    pub async fn fund_psbt(
+        &mut self,
+        req: ClusterFundPsbt,
         pubkey: Option<String>,
-    ) -> Result<AddInvoiceResponse> {
-        match pubkey {
-            Some(pubkey) => {
-                let node = self
-                    .nodes
-                    .iter()
-                    .find(|node| node.pubkey == pubkey)
-                    .unwrap();
-                node.add_invoice(req).await
+    ) -> Result<ClusterPsbt> {
+        req.validate()?;
+        let node =
+            self.select_node_where(pubkey.as_deref(), Node::supports_wallet, "on-chain wallets")?;
+
+        let psbt = node.fund_psbt(&req).await?;
+        self.save_psbt(&psbt).await?;
+        let _: () = self.cache.sadd(PSBTS_KEY, &psbt.id).await?;
+
+        Ok(psbt)
+    }
+
+    /// Signs the funding node's inputs and finalizes the PSBT. `psbt`
+    /// replaces the funded PSBT, e.g. with signatures from an external
+    /// wallet added. The flow stays `Funded` if finalizing fails, e.g. while
+    /// signatures are still missing.
+    pub async fn finalize_psbt(&mut self, id: &str, psbt: Option<String>) -> Result<ClusterPsbt> {
+        let mut tracked = self.tracked_psbt(id, ClusterPsbtState::Funded).await?;
+        let node = self.select_node(Some(&tracked.pubkey))?;
+
+        let psbt = psbt.unwrap_or_else(|| tracked.psbt.clone());
+        let (signed_psbt, raw_final_tx) = node.finalize_psbt(&psbt).await?;
+
+        tracked.psbt = signed_psbt;
+        tracked.raw_final_tx = Some(raw_final_tx);
+        tracked.state = ClusterPsbtState::Finalized;
+        self.save_psbt(&tracked).await?;
+        Ok(tracked)
+    }
+
+    /// Broadcasts a finalized PSBT. The leases are released only when the
+    /// node rejected the transaction. On any other error the flow stays
+    /// `Finalized`, as the transaction may have been broadcast, and can be
+    /// published again.
+    pub async fn publish_psbt(&mut self, id: &str, label: Option<String>) -> Result<ClusterPsbt> {
+        let mut tracked = self.tracked_psbt(id, ClusterPsbtState::Finalized).await?;
+        let node = self.select_node(Some(&tracked.pubkey))?;
+
+        let raw_final_tx = tracked.raw_final_tx.clone().unwrap_or_default();
+        match node.publish_transaction(&raw_final_tx, label).await? {
+            PublishOutcome::Published => {
+                tracked.state = ClusterPsbtState::Published;
+                self.save_psbt(&tracked).await?;
+                Ok(tracked)
             }
-            None => {
-                let mut rng = rand::thread_rng();
-                let node = self.nodes.choose(&mut rng).unwrap();
-                node.add_invoice(req).await
+            PublishOutcome::Rejected(error) => {
+                self.abort_psbt(tracked).await?;
+                Err(anyhow::anyhow!("Node rejected psbt {}: {}", id, error))
             }
         }
     }
 
-    pub async fn next_address(&mut self, pubkey: Option<String>) -> Result<String> {
-        match pubkey {
-            Some(pubkey) => {
-                let node = self
-                    .nodes
-                    .iter()
-                    .find(|node| node.pubkey == pubkey)
-                    .unwrap();
+    /// Aborts a PSBT flow that was not published, releasing its inputs.
+    pub async fn release_psbt(&mut self, id: &str) -> Result<ClusterPsbt> {
+        let tracked = self
+            .lookup_psbt(id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Unknown psbt {}", id))?;
 
-                let addr = node.next_address().await?;
+        match tracked.state {
+            ClusterPsbtState::Published => {
+                Err(anyhow::anyhow!("Psbt {} was already published", id))
+            }
+            ClusterPsbtState::Released => Ok(tracked),
+            _ => self.abort_psbt(tracked).await,
+        }
+    }
 
-                let _: Result<String, _> = self.cache
-                    .set_ex(
-                        addr.clone(),
-                        node.clone().pubkey,
-                        self.addr_exp_sec as usize,
-                    )
-                    .await;
-                Ok(addr)
+    pub async fn lookup_psbt(&mut self, id: &str) -> Result<Option<ClusterPsbt>> {
+        let json: Option<String> = self.cache.get(psbt_key(id)).await?;
+        json.map(|json| serde_json::from_str(&json).map_err(anyhow::Error::from))
+            .transpose()
+    }
+
+    /// Every tracked PSBT flow, including finished ones until they expire
+    /// from the cache.
+    pub async fn list_psbts(&mut self) -> Result<Vec<ClusterPsbt>> {
+        let ids: Vec<String> = self.cache.smembers(PSBTS_KEY).await?;
+
+        let mut psbts = vec![];
+        for id in ids {
+            match self.lookup_psbt(&id).await? {
+                Some(psbt) => psbts.push(psbt),
+                None => {
+                    let _: () = self.cache.srem(PSBTS_KEY, &id).await?;
+                }
             }
-            None => {
-                let mut rng = rand::thread_rng();
-                let node = self.nodes.choose(&mut rng).unwrap();
+        }
+        Ok(psbts)
+    }
 
-                let addr = node.next_address().await?;
+    async fn tracked_psbt(&mut self, id: &str, state: ClusterPsbtState) -> Result<ClusterPsbt> {
+        let tracked = self
+            .lookup_psbt(id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Unknown psbt {}", id))?;
 
-                let _: Result<String, _> = self.cache.set_ex(
-                    addr.clone(),
-                    node.clone().pubkey,
-                    self.addr_exp_sec as usize,
-                ).await;
-                Ok(addr)
+        if tracked.state != state {
+            return Err(anyhow::anyhow!(
+                "Psbt {} is {:?}, expected {:?}",
+                id,
+                tracked.state,
+                state
+            ));
+        }
+        Ok(tracked)
+    }
+
+    async fn abort_psbt(&mut self, mut tracked: ClusterPsbt) -> Result<ClusterPsbt> {
+        let node = self.select_node(Some(&tracked.pubkey))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if !tracked.leases_expired(now) {
+            node.release_leases(&tracked.leases).await?;
+        }
+
+        tracked.state = ClusterPsbtState::Released;
+        self.save_psbt(&tracked).await?;
+        Ok(tracked)
+    }
+
+    async fn save_psbt(&mut self, psbt: &ClusterPsbt) -> Result<()> {
+        let json = serde_json::to_string(psbt)?;
+        let _: () = self
+            .cache
+            .set_ex(psbt_key(&psbt.id), json, PSBT_EXP_SEC)
+            .await?;
+        Ok(())
+    }
+
+    /// Starts a batcher that queues on-chain sends from one node for
+    /// `window` and pays each batch in a single transaction.
+    pub fn onchain_batcher(
+        &self,
+        pubkey: Option<&str>,
+        window: Duration,
+        fee: ClusterOnchainFee,
+    ) -> Result<OnchainBatcher> {
+        let node = self
+            .select_node_where(pubkey, Node::supports_wallet, "on-chain wallets")?
+            .clone();
+
+        OnchainBatcher::spawn(node, window, fee)
+    }
+
+    /// Pays a BOLT11 invoice. `fee_policy` overrides the cluster's default
+    /// `fee_policy` for this payment, and `tenant` selects the budget in
+    /// `spend_limits` the payment counts against.
+    pub async fn pay_invoice(
+        &mut self,
+        amount: u64,
+        payment_request: String,
+        fee_policy: Option<FeePolicy>,
+        pubkey: Option<String>,
+        tenant: Option<String>,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let (fee_policy, node_pubkey, reservation) = self
+            .prepare_invoice_payment(amount, &payment_request, fee_policy, pubkey, tenant)
+            .await
+            .map_err(PaymentNotSent::wrap)?;
+        let node = self.select_node(Some(&node_pubkey))?;
+
+        let payment = node.pay_invoice(amount, payment_request, &fee_policy).await;
+        self.finish_spend(reservation, &payment).await;
+
+        let payment = payment?;
+        self.emit_payment(&payment);
+        Ok(payment)
+    }
+
+    /// Everything `pay_invoice` does before handing the payment to a node:
+    /// picks the payer, checks the destination and reserves the spend.
+    async fn prepare_invoice_payment(
+        &mut self,
+        amount: u64,
+        payment_request: &str,
+        fee_policy: Option<FeePolicy>,
+        pubkey: Option<String>,
+        tenant: Option<String>,
+    ) -> Result<(FeePolicy, String, Option<SpendReservation>)> {
+        let fee_policy = self.resolve_fee_policy(fee_policy)?;
+        let amount_msat = payment_amount_msat(payment_request, amount)?;
+        let invoice = payment_request
+            .parse::<Bolt11Invoice>()
+            .map_err(|e| anyhow::anyhow!("Invalid payment request: {}", e))?;
+        let dest = invoice.get_payee_pub_key().to_string();
+
+        let node_pubkey = match pubkey {
+            None if self.route_by_fee => self
+                .select_cheapest_node(payment_request, amount, &fee_policy, &dest)
+                .await?
+                .pubkey
+                .clone(),
+            _ => self.select_payer(pubkey.as_deref(), &dest)?.pubkey.clone(),
+        };
+
+        self.check_destination(
+            &node_pubkey,
+            &dest,
+            Some(invoice.payment_hash().to_string()),
+        )
+        .await?;
+
+        let reservation = self
+            .reserve_spend(&node_pubkey, tenant.as_deref(), amount_msat, &fee_policy)
+            .await?;
+
+        Ok((fee_policy, node_pubkey, reservation))
+    }
+
+    /// Whether `payment` definitely failed. Only LND reports an error once
+    /// nothing more can be sent; other nodes may report one for a payment
+    /// that is still in flight.
+    pub fn payment_failed(&self, payment: &ClusterPayPaymentRequestRes) -> bool {
+        payment.payment_error.is_some()
+            && self.nodes.iter().any(|node| {
+                node.pubkey == payment.pubkey && matches!(node.client, NodeClient::Lnd(_))
+            })
+    }
+
+    /// Estimates the fee and success probability of paying `dest`
+    /// `amount_msat` from every node that supports it, in parallel.
+    /// Reachable nodes are listed first, cheapest first.
+    pub async fn estimate_route_fee(
+        &self,
+        dest: &str,
+        amount_msat: u64,
+    ) -> Result<Vec<ClusterRouteFeeEstimate>> {
+        hex::decode(dest).map_err(|_| anyhow::anyhow!("dest must be a hex encoded pubkey"))?;
+
+        let tasks = self
+            .nodes
+            .iter()
+            .filter(|node| node.supports_route_estimates())
+            .map(|node| node.estimate_route_fee(dest, amount_msat));
+
+        let mut estimates = futures::future::join_all(tasks).await;
+        sort_route_estimates(&mut estimates);
+        Ok(estimates)
+    }
+
+    /// Estimates the fee of paying a BOLT11 invoice from every node that
+    /// supports it, probing with the invoice so its route hints are used.
+    /// Zero amount invoices cannot be probed, so they fall back to
+    /// `estimate_route_fee` for the payee and `amount` in sats.
+    pub async fn estimate_invoice_fee(
+        &self,
+        payment_request: &str,
+        amount: u64,
+    ) -> Result<Vec<ClusterRouteFeeEstimate>> {
+        let invoice = payment_request
+            .parse::<Bolt11Invoice>()
+            .map_err(|e| anyhow::anyhow!("Invalid payment request: {}", e))?;
+        if invoice.amount_milli_satoshis().is_none() {
+            let dest = invoice.get_payee_pub_key().to_string();
+            return self.estimate_route_fee(&dest, amount * 1000).await;
+        }
+
+        let tasks = self
+            .nodes
+            .iter()
+            .filter(|node| node.supports_route_estimates())
+            .map(|node| node.estimate_invoice_fee(payment_request));
+
+        let mut estimates = futures::future::join_all(tasks).await;
+        sort_route_estimates(&mut estimates);
+        Ok(estimates)
+    }
+
+    /// The node with the cheapest route within the fee policy among those
+    /// `destination_policy` lets pay `dest`, or `select_payer` when no such
+    /// node found one.
+    async fn select_cheapest_node(
+        &self,
+        payment_request: &str,
+        amount: u64,
+        fee_policy: &FeePolicy,
+        dest: &str,
+    ) -> Result<&Node> {
+        let max_fee_msat = fee_policy.max_fee_msat(payment_amount_msat(payment_request, amount)?);
+        let estimates = self.estimate_invoice_fee(payment_request, amount).await?;
+
+        let cheapest = estimates.iter().find(|estimate| {
+            estimate
+                .fee_msat
+                .is_some_and(|fee_msat| fee_msat <= max_fee_msat)
+                && self
+                    .destination_policy
+                    .check(&estimate.pubkey, dest)
+                    .is_none()
+        });
+
+        match cheapest {
+            Some(estimate) => self.select_node(Some(&estimate.pubkey)),
+            None => self.select_payer(None, dest),
+        }
+    }
+
+    /// Sends a spontaneous payment to `req.dest`, using the same node
+    /// selection and fee limit as `pay_invoice`.
+    pub async fn keysend(
+        &mut self,
+        req: ClusterKeysend,
+        fee_policy: Option<FeePolicy>,
+        pubkey: Option<String>,
+        tenant: Option<String>,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        req.validate()?;
+        let fee_policy = self.resolve_fee_policy(fee_policy)?;
+        let node_pubkey = self
+            .select_payer_with(pubkey.as_deref(), &req.dest, Node::supports_keysend)?
+            .pubkey
+            .clone();
+        self.check_destination(&node_pubkey, &req.dest, None)
+            .await?;
+
+        let reservation = self
+            .reserve_spend(
+                &node_pubkey,
+                tenant.as_deref(),
+                req.amount * 1000,
+                &fee_policy,
+            )
+            .await?;
+        let node = self.select_node(Some(&node_pubkey))?;
+
+        let payment = node.keysend(&req, &fee_policy).await;
+        self.finish_spend(reservation, &payment).await;
+
+        let payment = payment?;
+        self.emit_payment(&payment);
+        Ok(payment)
+    }
+
+    /// Pays an `lnurl1...` LNURL-pay link through `pay_invoice` after
+    /// checking the amount, comment and invoice description hash.
+    pub async fn pay_lnurl(
+        &mut self,
+        lnurl: &str,
+        amount_msat: u64,
+        comment: Option<&str>,
+        fee_policy: Option<FeePolicy>,
+        pubkey: Option<String>,
+        tenant: Option<String>,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let url = crate::lnurl::decode_lnurl(lnurl)?;
+        self.pay_lnurl_pay_url(&url, amount_msat, comment, fee_policy, pubkey, tenant)
+            .await
+    }
+
+    /// Pays a Lightning Address (`user@domain`) through `pay_invoice`.
+    pub async fn pay_lightning_address(
+        &mut self,
+        address: &str,
+        amount_msat: u64,
+        comment: Option<&str>,
+        fee_policy: Option<FeePolicy>,
+        pubkey: Option<String>,
+        tenant: Option<String>,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let url = crate::lnurl::lightning_address_url(address)?;
+        self.pay_lnurl_pay_url(&url, amount_msat, comment, fee_policy, pubkey, tenant)
+            .await
+    }
+
+    async fn pay_lnurl_pay_url(
+        &mut self,
+        url: &str,
+        amount_msat: u64,
+        comment: Option<&str>,
+        fee_policy: Option<FeePolicy>,
+        pubkey: Option<String>,
+        tenant: Option<String>,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let client = LnurlPayClient::new();
+        let params = client.fetch_params(url).await?;
+        let payment_request = client.fetch_invoice(&params, amount_msat, comment).await?;
+
+        self.pay_invoice(0, payment_request, fee_policy, pubkey, tenant)
+            .await
+    }
+
+    /// Creates a BOLT12 offer on the given node, or on a random node that
+    /// supports offers. The issuing node is recorded so payments against the
+    /// offer can be attributed with `offer_owner`.
+    pub async fn create_offer(
+        &mut self,
+        req: ClusterCreateOffer,
+        pubkey: Option<String>,
+    ) -> Result<ClusterOffer> {
+        let node = self.select_offer_node(pubkey.as_deref())?;
+        let offer = node.create_offer(req).await?;
+
+        let json_offer = serde_json::to_string(&offer)?;
+        let _: Result<(), _> = self
+            .cache
+            .set_ex(offer_key(&offer.offer_id), json_offer, OFFER_EXP_SEC)
+            .await;
+
+        Ok(offer)
+    }
+
+    /// The offer and the node that issued it. Offers whose cache entry
+    /// expired are found by asking every node that supports offers.
+    pub async fn offer_owner(&mut self, offer_id: &str) -> Result<Option<ClusterOffer>> {
+        let cached: Option<ClusterOffer> = self.cache.get(offer_key(offer_id)).await?;
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        let nodes = self.nodes_where(None, Node::supports_offers);
+        let tasks = nodes.iter().map(|node| node.lookup_offer(offer_id));
+        let offer = futures::future::join_all(tasks)
+            .await
+            .into_iter()
+            .find_map(|offer| offer.ok().flatten());
+
+        if let Some(offer) = &offer {
+            let json_offer = serde_json::to_string(offer)?;
+            let _: Result<(), _> = self
+                .cache
+                .set_ex(offer_key(offer_id), json_offer, OFFER_EXP_SEC)
+                .await;
+        }
+
+        Ok(offer)
+    }
+
+    /// Payments received against an offer, attributed to the node that
+    /// issued it.
+    pub async fn offer_payments(&mut self, offer_id: &str) -> Result<Vec<ClusterOfferPayment>> {
+        let offer = self
+            .offer_owner(offer_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No node in the cluster issued offer {}", offer_id))?;
+        let node = self.select_node(Some(&offer.pubkey))?;
+
+        node.offer_payments(offer_id).await
+    }
+
+    /// Pays a BOLT12 offer. `amount_msat` is required when spend limits
+    /// are configured, as the invoice amount is only known once fetched.
+    pub async fn pay_offer(
+        &mut self,
+        offer: &str,
+        amount_msat: Option<u64>,
+        fee_policy: Option<FeePolicy>,
+        pubkey: Option<String>,
+        tenant: Option<String>,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let fee_policy = self.resolve_fee_policy(fee_policy)?;
+        let issuer = self
+            .select_offer_node(pubkey.as_deref())?
+            .offer_issuer(offer)
+            .await?;
+        let node_pubkey = match &issuer {
+            Some(issuer) => {
+                let node_pubkey = self
+                    .select_payer_with(pubkey.as_deref(), issuer, Node::supports_offers)?
+                    .pubkey
+                    .clone();
+                self.check_destination(&node_pubkey, issuer, None).await?;
+                node_pubkey
+            }
+            None if !self.destination_policy.is_empty() => {
+                return Err(anyhow::anyhow!(
+                    "The offer does not name its issuer, so destination_policy cannot be applied"
+                ))
+            }
+            None => self.select_offer_node(pubkey.as_deref())?.pubkey.clone(),
+        };
+
+        let reservation = match amount_msat {
+            Some(amount_msat) => {
+                self.reserve_spend(&node_pubkey, tenant.as_deref(), amount_msat, &fee_policy)
+                    .await?
+            }
+            None if self.spend_limits.is_active() => {
+                return Err(SpendLimitError::UnknownAmount.into())
+            }
+            None => None,
+        };
+        let node = self.select_node(Some(&node_pubkey))?;
+
+        let payment = node.pay_offer(offer, amount_msat, &fee_policy).await;
+        self.finish_spend(reservation, &payment).await;
+
+        let payment = payment?;
+        self.emit_payment(&payment);
+        Ok(payment)
+    }
+
+    fn select_offer_node(&self, pubkey: Option<&str>) -> Result<&Node> {
+        self.select_node_where(pubkey, Node::supports_offers, "BOLT12 offers")
+    }
+
+    /// Outbound lightning balance in millisatoshis of one node, or of the
+    /// whole cluster when no pubkey is given.
+    pub async fn channel_balance(&self, pubkey: Option<&str>) -> Result<u64> {
+        let tasks = self
+            .nodes_where(pubkey, Node::supports_channels)
+            .into_iter()
+            .map(|node| node.channel_balance());
+
+        let balances = futures::future::join_all(tasks)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<u64>>>()?;
+
+        Ok(balances.iter().sum())
+    }
+
+    /// Rejects the payment if `destination_policy` does not let `pubkey` pay
+    /// `dest`, recording the rejection for audit.
+    async fn check_destination(
+        &mut self,
+        pubkey: &str,
+        dest: &str,
+        payment_hash: Option<String>,
+    ) -> Result<()> {
+        let reason = match self.destination_policy.check(pubkey, dest) {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+
+        let rejection = DestinationRejection {
+            pubkey: pubkey.to_string(),
+            dest: dest.to_string(),
+            reason,
+            payment_hash,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+
+        if let Err(e) = self.record_rejection(&rejection).await {
+            eprintln!("failed to record destination rejection: {}", e);
+        }
+
+        Err(rejection.into())
+    }
+
+    async fn record_rejection(&mut self, rejection: &DestinationRejection) -> Result<()> {
+        let json = serde_json::to_string(rejection)?;
+        let _: () = redis::pipe()
+            .atomic()
+            .lpush(destinations::AUDIT_KEY, json)
+            .ignore()
+            .ltrim(destinations::AUDIT_KEY, 0, destinations::AUDIT_LIMIT - 1)
+            .ignore()
+            .query_async(&mut self.cache)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Payments rejected by `destination_policy`, most recent first.
+    pub async fn destination_rejections(&mut self) -> Result<Vec<DestinationRejection>> {
+        let entries: Vec<String> = self.cache.lrange(destinations::AUDIT_KEY, 0, -1).await?;
+
+        entries
+            .iter()
+            .map(|entry| serde_json::from_str(entry).map_err(anyhow::Error::from))
+            .collect()
+    }
+
+    /// Current spending against every configured node and tenant budget.
+    pub async fn spend_status(&mut self) -> Result<Vec<SpendStatus>> {
+        let mut scopes: Vec<SpendScope> = self
+            .nodes
+            .iter()
+            .map(|node| SpendScope::Node(node.pubkey.clone()))
+            .collect();
+        scopes.extend(
+            self.spend_limits
+                .tenant_budgets
+                .keys()
+                .map(|tenant| SpendScope::Tenant(tenant.clone())),
+        );
+
+        let mut statuses = vec![];
+        for scope in scopes {
+            if let Some(status) = self.spend_limits.status(&mut self.cache, &scope).await? {
+                statuses.push(status);
             }
         }
+        Ok(statuses)
     }
 
-    pub async fn list_utxos(&mut self, pubkey: Option<&str>) -> Result<ClusterUtxos> {
-        match pubkey {
-            Some(pubkey) => {
-                let node = self
-                    .nodes
-                    .iter()
-                    .find(|node| node.pubkey == pubkey)
-                    .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey"))?;
+    /// Checks the payment against `spend_limits` and counts the amount plus
+    /// the most the fee policy allows against the node and tenant budgets.
+    async fn reserve_spend(
+        &mut self,
+        pubkey: &str,
+        tenant: Option<&str>,
+        amount_msat: u64,
+        fee_policy: &FeePolicy,
+    ) -> Result<Option<SpendReservation>> {
+        self.spend_limits.check_payment(amount_msat)?;
 
-                let cache_key = format!("utxos:{}", node.pubkey);
-                let cached_utxos = self.cache.get(&cache_key).await?;
+        let spend_msat = amount_msat + fee_policy.max_fee_msat(amount_msat);
+        let (reservation, statuses) = self
+            .spend_limits
+            .reserve(&mut self.cache, pubkey, tenant, spend_msat)
+            .await?;
 
-                match cached_utxos {
-                    Some(utxos) => Ok(utxos),
-                    None => {
-                        let utxos = node.list_utxos().await?;
-                        let json_utxos = serde_json::to_string(&utxos).unwrap();
-                        let _: Result<ClusterUtxos, _> = self.cache.set_ex(
-                            cache_key,
-                            json_utxos,
-                            self.utxo_exp_sec as usize,
-                        ).await;
-                        Ok(utxos)
-                    }
-                }
+        for status in statuses {
+            if status.near_limit(self.spend_limits.warn_ratio) {
+                self.emit(ClusterEvent::SpendLimitWarning(status));
             }
-            None => {
-                let mut cluster_utxos = ClusterUtxos { utxos: vec![] };
+        }
 
-                for node in &self.nodes {
-                    let cache_key = format!("utxos:{}", node.pubkey);
-                    let cached_utxos = self.cache.get(&cache_key).await?;
+        Ok(reservation)
+    }
 
-                    let node_utxos = match cached_utxos {
-                        Some(utxos) => utxos,
-                        None => {
-                            let fetched_utxos = node.list_utxos().await?;
-                            let json_utxos = serde_json::to_string(&fetched_utxos).unwrap();
-                            let _: Result<ClusterUtxos, _> = self.cache.set_ex(
-                                cache_key,
-                                json_utxos,
-                                self.utxo_exp_sec as usize,
-                            ).await;
-                            fetched_utxos
-                        }
-                    };
+    /// Releases the reservation when the node reported the payment failed
+    /// and settles it to the amount sent when it succeeded. The reservation
+    /// is kept when the outcome is unknown, as the payment may still be in
+    /// flight. Cache errors are ignored so they never hide the payment's
+    /// result.
+    async fn finish_spend(
+        &mut self,
+        reservation: Option<SpendReservation>,
+        payment: &Result<ClusterPayPaymentRequestRes>,
+    ) {
+        let (reservation, payment) = match (reservation, payment) {
+            (Some(reservation), Ok(payment)) => (reservation, payment),
+            _ => return,
+        };
 
-                    cluster_utxos.utxos.extend(node_utxos.utxos);
-                }
+        if payment.payment_error.is_some() {
+            let _ = self
+                .spend_limits
+                .release(&mut self.cache, &reservation)
+                .await;
+            return;
+        }
 
-                Ok(cluster_utxos)
-            }
+        let spent_msat = payment
+            .payment_route
+            .as_ref()
+            .and_then(|route| route.total_amt_msat.parse::<u64>().ok());
+        if let Some(spent_msat) = spent_msat {
+            let _ = self
+                .spend_limits
+                .settle(&mut self.cache, &reservation, spent_msat)
+                .await;
         }
     }
 
-    pub async fn pay_invoice(
+    fn resolve_fee_policy(&self, fee_policy: Option<FeePolicy>) -> Result<FeePolicy> {
+        let fee_policy = fee_policy.unwrap_or_else(|| self.fee_policy.clone());
+        fee_policy.validate()?;
+        Ok(fee_policy)
+    }
+
+    /// Returns the node with the given pubkey, or a random node that
+    /// `destination_policy` lets pay `dest` when none is given. When no node
+    /// may, any node is returned so `check_destination` records the
+    /// rejection.
+    fn select_payer(&self, pubkey: Option<&str>, dest: &str) -> Result<&Node> {
+        self.select_payer_with(pubkey, dest, |_| true)
+    }
+
+    /// `select_payer` among the nodes for which `eligible` holds.
+    fn select_payer_with(
         &self,
-        amount: u64,
-        payment_request: String,
-        max_fee: i64,
-        pubkey: Option<String>,
-    ) -> Result<ClusterPayPaymentRequestRes> {
-        // node selected
+        pubkey: Option<&str>,
+        dest: &str,
+        eligible: impl Fn(&Node) -> bool,
+    ) -> Result<&Node> {
+        if pubkey.is_some() {
+            return self.select_node(pubkey);
+        }
+
+        let mut rng = rand::thread_rng();
+        payer_candidates(&self.nodes, &self.destination_policy, dest, eligible)
+            .choose(&mut rng)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("No node in the cluster can make this payment"))
+    }
+
+    /// Returns the node with the given pubkey, or a random node for which
+    /// `eligible` holds when none is given. `operation` names what the node
+    /// is needed for in the error when no node is eligible.
+    fn select_node_where(
+        &self,
+        pubkey: Option<&str>,
+        eligible: impl Fn(&Node) -> bool,
+        operation: &str,
+    ) -> Result<&Node> {
         if pubkey.is_some() {
-            let node = self
+            return self.select_node(pubkey);
+        }
+
+        let nodes: Vec<&Node> = self.nodes.iter().filter(|node| eligible(node)).collect();
+        let mut rng = rand::thread_rng();
+        nodes
+            .choose(&mut rng)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("No nodes in the cluster support {}", operation))
+    }
+
+    /// The node with the given pubkey, or every node for which `eligible`
+    /// holds when none is given.
+    fn nodes_where(&self, pubkey: Option<&str>, eligible: impl Fn(&Node) -> bool) -> Vec<&Node> {
+        self.nodes
+            .iter()
+            .filter(|node| match pubkey {
+                Some(pubkey) => node.pubkey == pubkey,
+                None => eligible(node),
+            })
+            .collect()
+    }
+
+    /// Returns the node with the given pubkey, or a random node when none is
+    /// given.
+    fn select_node(&self, pubkey: Option<&str>) -> Result<&Node> {
+        match pubkey {
+            Some(pubkey) => self
                 .nodes
                 .iter()
-                .find(|node| &node.pubkey == pubkey.as_ref().unwrap())
-                .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey"))?;
-
-            match &node.client {
-                NodeClient::Lnd(client) => {
-                    let req = LndSendPaymentSyncReq {
-                        payment_request: payment_request.clone(),
-                        amt: amount.to_string(),
-                        fee_limit: FeeLimit {
-                            fixed: max_fee.to_string(),
-                        },
-                        allow_self_payment: false,
-                    };
-                    let invoice = client.send_payment_sync(req).await?;
-                    eprintln!("{:?}", invoice);
-                    Ok(invoice.to_cluster(node.clone().pubkey))
-                }
-                _ => {
-                    panic!("We only support LND nodes at this time.")
-                }
-            }
-        } else {
-            // no node selected, select a node at random
-            let mut rng = rand::thread_rng();
-            let node = self.nodes.choose(&mut rng).unwrap();
-
-            match &node.client {
-                NodeClient::Lnd(client) => {
-                    let req = LndSendPaymentSyncReq {
-                        payment_request: payment_request.clone(),
-                        amt: amount.to_string(),
-                        fee_limit: FeeLimit {
-                            fixed: max_fee.to_string(),
-                        },
-                        allow_self_payment: false,
-                    };
-                    let invoice = client.send_payment_sync(req).await?;
-                    Ok(invoice.to_cluster(node.clone().pubkey))
-                }
-                _ => {
-                    panic!("We only support LND nodes at this time.")
-                }
+                .find(|node| node.pubkey == pubkey)
+                .ok_or_else(|| anyhow::anyhow!("Node not found with provided pubkey")),
+            None => {
+                let mut rng = rand::thread_rng();
+                self.nodes
+                    .choose(&mut rng)
+                    .ok_or_else(|| anyhow::anyhow!("Cluster has no nodes"))
             }
         }
     }
 }
 
+/// The error returned by `Node` methods that only LND nodes implement.
+fn cln_unsupported(operation: &str) -> anyhow::Error {
+    anyhow::anyhow!("{} is not supported on CLN nodes", operation)
+}
+
+/// The eligible nodes `policy` lets pay `dest`, or every eligible node when
+/// none may.
+fn payer_candidates<'a>(
+    nodes: &'a [Node],
+    policy: &DestinationPolicy,
+    dest: &str,
+    eligible: impl Fn(&Node) -> bool,
+) -> Vec<&'a Node> {
+    let nodes: Vec<&Node> = nodes.iter().filter(|node| eligible(node)).collect();
+    let allowed: Vec<&Node> = nodes
+        .iter()
+        .copied()
+        .filter(|node| policy.check(&node.pubkey, dest).is_none())
+        .collect();
+
+    if allowed.is_empty() {
+        nodes
+    } else {
+        allowed
+    }
+}
+
 impl Node {
     pub fn new(
         pubkey: String,
@@ -517,6 +3762,226 @@ impl Display for NodeNetwork {
     }
 }
 
+/// Merges per node pages into one ordered page. An invoice is only emitted
+/// once no node that still has unread invoices could hold an earlier one, and
+/// each node's offset only moves past the invoices that were consumed.
+fn merge_invoice_pages(
+    pages: Vec<NodeInvoicePage>,
+    cursor: &ClusterInvoiceCursor,
+    req: &ClusterListInvoices,
+    limit: usize,
+) -> ClusterInvoicePage {
+    let boundary = pages
+        .iter()
+        .filter(|page| !page.exhausted)
+        .filter_map(|page| page.invoices.last().map(|invoice| invoice.sort_key()))
+        .min();
+
+    let mut eligible: Vec<&ClusterLookupInvoice> = pages
+        .iter()
+        .flat_map(|page| &page.invoices)
+        .filter(|invoice| boundary.as_ref().is_none_or(|b| &invoice.sort_key() <= b))
+        .filter(|invoice| req.matches(invoice))
+        .collect();
+    eligible.sort_by_key(|invoice| invoice.sort_key());
+
+    let full = eligible.len() > limit;
+    eligible.truncate(limit);
+
+    let cut = if full {
+        eligible.last().map(|invoice| invoice.sort_key())
+    } else {
+        boundary.clone()
+    };
+
+    let mut next = cursor.clone();
+    for page in &pages {
+        let consumed = page
+            .invoices
+            .iter()
+            .filter(|invoice| cut.as_ref().is_none_or(|c| &invoice.sort_key() <= c))
+            .map(|invoice| invoice.add_index.parse::<u64>().unwrap_or(0))
+            .max();
+        if let Some(index) = consumed {
+            next.offsets.insert(page.pubkey.clone(), index);
+        }
+    }
+
+    let done = !full && boundary.is_none();
+
+    ClusterInvoicePage {
+        invoices: eligible.into_iter().cloned().collect(),
+        next_cursor: if done { None } else { Some(next.encode()) },
+    }
+}
+
+/// Orders reachable estimates by fee, then by success probability, with
+/// unreachable nodes last.
+pub fn sort_route_estimates(estimates: &mut [ClusterRouteFeeEstimate]) {
+    estimates.sort_by(|a, b| {
+        b.reachable()
+            .cmp(&a.reachable())
+            .then(a.fee_msat.cmp(&b.fee_msat))
+            .then(b.success_prob.total_cmp(&a.success_prob))
+    });
+}
+
+/// Amount being paid in millisatoshis: the invoice amount, or `amount` sats
+/// for zero amount invoices.
+fn payment_amount_msat(payment_request: &str, amount: u64) -> Result<u64> {
+    let invoice = payment_request
+        .parse::<Bolt11Invoice>()
+        .map_err(|e| anyhow::anyhow!("Invalid payment request: {}", e))?;
+
+    Ok(invoice.amount_milli_satoshis().unwrap_or(amount * 1000))
+}
+
+/// Waits for the pending update of a channel open or close, then forwards
+/// the remaining updates from a spawned task. `channel_point` is known up
+/// front when closing.
+async fn follow_channel_stream(
+    pubkey: &str,
+    peer: &str,
+    channel_point: Option<&str>,
+    mut stream: LndStream<ChannelStatusUpdate>,
+) -> Result<ClusterChannelOperation> {
+    let pending = loop {
+        let update = stream
+            .next()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Node closed the channel stream before publishing"))?;
+
+        if let Some(pending) = update.chan_pending.or(update.close_pending) {
+            let txid = txid_from_bytes(&pending.txid)?;
+            break ClusterPendingChannel {
+                pubkey: pubkey.to_string(),
+                peer: peer.to_string(),
+                channel_point: match channel_point {
+                    Some(channel_point) => channel_point.to_string(),
+                    None => format!("{}:{}", txid, pending.output_index),
+                },
+                txid,
+            };
+        }
+    };
+
+    let (tx, updates) = mpsc::unbounded_channel();
+    let channel_point = pending.channel_point.clone();
+    tokio::spawn(async move {
+        let update = loop {
+            match stream.next().await {
+                Ok(Some(update)) => {
+                    if update.chan_open.is_some() {
+                        break ClusterChannelUpdate::Opened { channel_point };
+                    }
+                    if let Some(close) = update.chan_close {
+                        break match txid_from_bytes(&close.closing_txid) {
+                            Ok(closing_txid) => ClusterChannelUpdate::Closed {
+                                channel_point,
+                                closing_txid,
+                            },
+                            Err(e) => ClusterChannelUpdate::Interrupted {
+                                channel_point,
+                                error: e.to_string(),
+                            },
+                        };
+                    }
+                }
+                Ok(None) => {
+                    break ClusterChannelUpdate::Interrupted {
+                        channel_point,
+                        error: "Node closed the channel stream".to_string(),
+                    }
+                }
+                Err(e) => {
+                    break ClusterChannelUpdate::Interrupted {
+                        channel_point,
+                        error: e.to_string(),
+                    }
+                }
+            }
+        };
+        let _ = tx.send(update);
+    });
+
+    Ok(ClusterChannelOperation { pending, updates })
+}
+
+/// An invoice for a rebalance whose preimage the cluster generated.
+struct RebalanceInvoice {
+    payment_request: String,
+    payment_hash: String,
+    preimage: [u8; 32],
+}
+
+impl RebalanceInvoice {
+    fn to_cluster(
+        &self,
+        from: &str,
+        to: &str,
+        amount_sat: u64,
+        payment: Option<&ClusterPayPaymentRequestRes>,
+    ) -> ClusterRebalance {
+        ClusterRebalance {
+            from: from.to_string(),
+            to: to.to_string(),
+            amount_sat,
+            fee_sat: payment
+                .and_then(|payment| payment.payment_route.as_ref())
+                .and_then(|route| route.total_fees.parse().ok()),
+            payment_hash: self.payment_hash.clone(),
+            preimage: hex::encode(self.preimage),
+        }
+    }
+}
+
+/// Whether the payment returned `preimage`. Payments carry it hex encoded.
+fn payment_preimage_matches(payment: &ClusterPayPaymentRequestRes, preimage: &[u8]) -> bool {
+    payment
+        .payment_preimage
+        .as_deref()
+        .is_some_and(|returned| returned.eq_ignore_ascii_case(&hex::encode(preimage)))
+}
+
+fn channels_key(pubkey: &str) -> String {
+    format!("channels:{}", pubkey)
+}
+
+const PSBTS_KEY: &str = "psbts";
+/// How long PSBT flows are tracked after their last change.
+const PSBT_EXP_SEC: usize = 86400;
+
+fn psbt_key(id: &str) -> String {
+    format!("psbt:{}", id)
+}
+
+/// How long the issuing node of an offer is cached.
+const OFFER_EXP_SEC: usize = 30 * 86400;
+
+fn offer_key(offer_id: &str) -> String {
+    format!("offer:{}", offer_id)
+}
+
+/// LND's expiry for invoices created with an expiry of 0.
+const DEFAULT_INVOICE_EXPIRY_SEC: i64 = 86400;
+
+/// The seconds until an invoice created with `expiry` expires.
+fn invoice_expiry_sec(expiry: i64) -> usize {
+    if expiry > 0 {
+        expiry as usize
+    } else {
+        DEFAULT_INVOICE_EXPIRY_SEC as usize
+    }
+}
+
+fn comment_key(r_hash: &str) -> String {
+    format!("comment:{}", r_hash)
+}
+
+fn hold_invoice_key(r_hash: &str) -> String {
+    format!("hold:{}", r_hash)
+}
+
 pub fn to_hex(str: &str) -> Result<String> {
     let decoded_bytes = base64::decode(str)?;
     let hex_string = hex::encode(decoded_bytes);
@@ -526,9 +3991,22 @@ pub fn to_hex(str: &str) -> Result<String> {
 
 #[cfg(test)]
 pub mod tests {
-    use crate::lnd::LndClient;
+    use sha2::{Digest, Sha256};
+    use std::collections::BTreeMap;
 
-    use super::{Cluster, ClusterAddInvoice, Node, NodeClient, NodeLightningImpl, NodeNetwork};
+    use crate::cln::ClnClient;
+    use crate::lnd::{LndClient, LndSendPaymentSyncRes};
+
+    use super::{
+        invoice_expiry_sec, merge_invoice_pages, payer_candidates, payment_preimage_matches,
+        sort_route_estimates, Cluster, ClusterAddHoldInvoice, ClusterAddInvoice,
+        ClusterAddressType, ClusterChannel, ClusterCircularRebalance, ClusterEvent,
+        ClusterEventSender, ClusterInvoiceCursor, ClusterInvoiceState, ClusterKeysend,
+        ClusterLiquidityReport, ClusterListInvoices, ClusterLookupInvoice, ClusterNewAddress,
+        ClusterPayPaymentRequestRes, ClusterRouteFeeEstimate, DestinationPolicy,
+        DestinationRejection, Node, NodeClient, NodeInvoicePage, NodeLightningImpl, NodeNetwork,
+        KEYSEND_PREIMAGE_RECORD,
+    };
 
     #[tokio::test]
     async fn test_add_lookup_invoice() {
@@ -538,6 +4016,7 @@ pub mod tests {
             memo: String::from("test"),
             value: 1000,
             expiry: 1000,
+            ..Default::default()
         };
         let invoice = cluster.add_invoice(add_invoice, None).await.unwrap();
 
@@ -549,6 +4028,428 @@ pub mod tests {
         assert_eq!(lookup_invoice.r_hash, invoice.r_hash);
     }
 
+    #[test]
+    fn test_add_invoice_validate() {
+        let msat_invoice = ClusterAddInvoice {
+            memo: String::from("test"),
+            value_msat: Some(1500),
+            expiry: 1000,
+            ..Default::default()
+        };
+        assert!(msat_invoice.validate().is_ok());
+
+        let both_amounts = ClusterAddInvoice {
+            value: 1,
+            value_msat: Some(1500),
+            ..Default::default()
+        };
+        assert!(both_amounts.validate().is_err());
+
+        let short_hash = ClusterAddInvoice {
+            description_hash: Some(String::from("abcd")),
+            ..Default::default()
+        };
+        assert!(short_hash.validate().is_err());
+
+        let memo_and_hash = ClusterAddInvoice {
+            memo: String::from("test"),
+            description_hash: Some("ab".repeat(32)),
+            ..Default::default()
+        };
+        assert!(memo_and_hash.validate().is_err());
+    }
+
+    #[test]
+    fn test_add_hold_invoice_validate() {
+        let hold_invoice = ClusterAddHoldInvoice {
+            hash: "ab".repeat(32),
+            value_msat: Some(1500),
+            description_hash: Some("cd".repeat(32)),
+            ..Default::default()
+        };
+        assert!(hold_invoice.validate().is_ok());
+
+        let short_hash = ClusterAddHoldInvoice {
+            hash: String::from("abcd"),
+            ..Default::default()
+        };
+        assert!(short_hash.validate().is_err());
+
+        let both_amounts = ClusterAddHoldInvoice {
+            hash: "ab".repeat(32),
+            value: 1,
+            value_msat: Some(1500),
+            ..Default::default()
+        };
+        assert!(both_amounts.validate().is_err());
+
+        let negative = ClusterAddHoldInvoice {
+            hash: "ab".repeat(32),
+            value: -1,
+            ..Default::default()
+        };
+        assert!(negative.validate().is_err());
+
+        let memo_and_hash = ClusterAddHoldInvoice {
+            hash: "ab".repeat(32),
+            memo: String::from("test"),
+            description_hash: Some("cd".repeat(32)),
+            ..Default::default()
+        };
+        assert!(memo_and_hash.validate().is_err());
+    }
+
+    #[test]
+    fn test_keysend_validate() {
+        let dest = format!("02{}", "ab".repeat(32));
+        let keysend = ClusterKeysend {
+            dest: dest.clone(),
+            amount: 1000,
+            custom_records: BTreeMap::from([(7629169, String::from("7b7d"))]),
+        };
+        assert!(keysend.validate().is_ok());
+
+        let short_dest = ClusterKeysend {
+            dest: "02ab".to_string(),
+            amount: 1000,
+            ..Default::default()
+        };
+        assert!(short_dest.validate().is_err());
+
+        let zero_amount = ClusterKeysend {
+            dest: dest.clone(),
+            ..Default::default()
+        };
+        assert!(zero_amount.validate().is_err());
+
+        for key in [65535, KEYSEND_PREIMAGE_RECORD] {
+            let reserved = ClusterKeysend {
+                dest: dest.clone(),
+                amount: 1000,
+                custom_records: BTreeMap::from([(key, String::from("00"))]),
+            };
+            assert!(reserved.validate().is_err());
+        }
+
+        let not_hex = ClusterKeysend {
+            dest,
+            amount: 1000,
+            custom_records: BTreeMap::from([(7629169, String::from("zz"))]),
+        };
+        assert!(not_hex.validate().is_err());
+    }
+
+    #[test]
+    fn test_circular_rebalance_validate() {
+        let rebalance = ClusterCircularRebalance {
+            outgoing_chan_id: "1".to_string(),
+            last_hop_pubkey: format!("02{}", "ab".repeat(32)),
+            amount_sat: 1000,
+            max_fee_ppm: 100,
+        };
+        assert!(rebalance.validate().is_ok());
+
+        for last_hop_pubkey in ["", "02ab", "zz"] {
+            let invalid = ClusterCircularRebalance {
+                last_hop_pubkey: last_hop_pubkey.to_string(),
+                ..rebalance.clone()
+            };
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_keysend_dest_custom_records() {
+        let keysend = ClusterKeysend {
+            dest: format!("02{}", "ab".repeat(32)),
+            amount: 1000,
+            custom_records: BTreeMap::from([(7629169, String::from("7b7d"))]),
+        };
+        let preimage = [7u8; 32];
+        let records = keysend.dest_custom_records(&preimage).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records["7629169"], base64::encode(b"{}"));
+        assert_eq!(
+            records[&KEYSEND_PREIMAGE_RECORD.to_string()],
+            base64::encode(preimage)
+        );
+    }
+
+    #[test]
+    fn test_merge_invoice_pages() {
+        let invoice = |pubkey: &str, add_index: u64, creation_date: u64| ClusterLookupInvoice {
+            pubkey: pubkey.to_string(),
+            memo: "".to_string(),
+            r_preimage: "".to_string(),
+            r_hash: format!("{}{}", pubkey, add_index),
+            value: "1000".to_string(),
+            settle_date: "0".to_string(),
+            payment_request: "".to_string(),
+            description_hash: "".to_string(),
+            expiry: "3600".to_string(),
+            amt_paid_sat: "0".to_string(),
+            state: ClusterInvoiceState::Open,
+            creation_date: creation_date.to_string(),
+            add_index: add_index.to_string(),
+            value_msat: "1000000".to_string(),
+        };
+
+        let pages = vec![
+            NodeInvoicePage {
+                pubkey: "a".to_string(),
+                invoices: vec![invoice("a", 1, 10), invoice("a", 2, 30)],
+                exhausted: false,
+            },
+            NodeInvoicePage {
+                pubkey: "b".to_string(),
+                invoices: vec![invoice("b", 7, 20), invoice("b", 8, 40)],
+                exhausted: false,
+            },
+        ];
+
+        let req = ClusterListInvoices::default();
+        let page = merge_invoice_pages(pages, &ClusterInvoiceCursor::default(), &req, 2);
+
+        let hashes: Vec<&str> = page.invoices.iter().map(|i| i.r_hash.as_str()).collect();
+        assert_eq!(hashes, vec!["a1", "b7"]);
+
+        let cursor = ClusterInvoiceCursor::decode(&page.next_cursor.unwrap()).unwrap();
+        assert_eq!(cursor.offsets.get("a"), Some(&1));
+        assert_eq!(cursor.offsets.get("b"), Some(&7));
+
+        let last_pages = vec![NodeInvoicePage {
+            pubkey: "a".to_string(),
+            invoices: vec![invoice("a", 2, 30)],
+            exhausted: true,
+        }];
+        let page = merge_invoice_pages(last_pages, &cursor, &req, 2);
+        assert_eq!(page.invoices.len(), 1);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_sort_route_estimates() {
+        let estimate =
+            |pubkey: &str, fee_msat: Option<u64>, success_prob: f64| ClusterRouteFeeEstimate {
+                pubkey: pubkey.to_string(),
+                fee_msat,
+                success_prob,
+                time_lock: fee_msat.map(|_| 144),
+                error: match fee_msat {
+                    Some(_) => None,
+                    None => Some("No route found".to_string()),
+                },
+            };
+
+        let mut estimates = vec![
+            estimate("a", None, 0.0),
+            estimate("b", Some(2000), 0.9),
+            estimate("c", Some(1000), 0.5),
+            estimate("d", Some(1000), 0.8),
+        ];
+        sort_route_estimates(&mut estimates);
+
+        let pubkeys: Vec<&str> = estimates.iter().map(|e| e.pubkey.as_str()).collect();
+        assert_eq!(pubkeys, vec!["d", "c", "b", "a"]);
+        assert!(!estimates[3].reachable());
+    }
+
+    #[test]
+    fn test_address_type_matches() {
+        let p2wkh = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        let p2tr = "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0";
+        let nested = "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy";
+
+        assert!(ClusterAddressType::P2wkh.matches(p2wkh));
+        assert!(!ClusterAddressType::P2wkh.matches(p2tr));
+        assert!(ClusterAddressType::P2tr.matches(p2tr));
+        assert!(!ClusterAddressType::P2tr.matches(nested));
+        assert!(ClusterAddressType::NestedP2wkh.matches(nested));
+        assert!(!ClusterAddressType::NestedP2wkh.matches(p2wkh));
+
+        assert_eq!(ClusterAddressType::P2tr.to_lnd(true), 5);
+    }
+
+    fn channel(
+        pubkey: &str,
+        local_balance: i64,
+        remote_balance: i64,
+        active: bool,
+    ) -> ClusterChannel {
+        ClusterChannel {
+            pubkey: pubkey.to_string(),
+            peer: "03cc".to_string(),
+            chan_id: "1".to_string(),
+            channel_point: "ab01:0".to_string(),
+            capacity: local_balance + remote_balance,
+            local_balance,
+            remote_balance,
+            active,
+            private: false,
+            fee_policy: None,
+        }
+    }
+
+    #[test]
+    fn test_liquidity_report() {
+        let channels = vec![
+            channel("02aa", 800_000, 200_000, true),
+            channel("02aa", 100_000, 0, false),
+            channel("02bb", 0, 500_000, true),
+        ];
+        let report = ClusterLiquidityReport::from_channels(&channels);
+
+        let a = report.nodes["02aa"];
+        assert_eq!(a.channels, 2);
+        assert_eq!(a.active_channels, 1);
+        assert_eq!(a.outbound, 800_000);
+        assert_eq!(a.inbound, 200_000);
+        assert_eq!(a.inactive_outbound, 100_000);
+        assert_eq!(a.local_ratio(), Some(0.8));
+        assert_eq!(report.nodes["02bb"].local_ratio(), Some(0.0));
+
+        assert_eq!(report.total.capacity, 1_600_000);
+        assert_eq!(report.total.outbound, 800_000);
+        assert_eq!(report.total.inbound, 700_000);
+    }
+
+    #[test]
+    fn test_payer_candidates() {
+        let node = |pubkey: &str| Node {
+            pubkey: pubkey.to_string(),
+            ip: "127.0.0.1".to_string(),
+            port: "10009".to_string(),
+            network: NodeNetwork::Testnet,
+            lightning_impl: NodeLightningImpl::Lnd,
+            client: NodeClient::Lnd(LndClient::new(String::new(), String::new(), String::new())),
+        };
+        let nodes = vec![node("node1"), node("node2")];
+        let pubkeys = |candidates: Vec<&Node>| -> Vec<String> {
+            candidates.iter().map(|node| node.pubkey.clone()).collect()
+        };
+
+        let mut policy = DestinationPolicy::default();
+        policy.allow("node1", "02bb");
+        assert_eq!(
+            pubkeys(payer_candidates(&nodes, &policy, "02cc", |_| true)),
+            vec!["node2"]
+        );
+        assert_eq!(
+            pubkeys(payer_candidates(&nodes, &policy, "02bb", |_| true)),
+            vec!["node1", "node2"]
+        );
+
+        policy.deny("02dd");
+        assert_eq!(
+            pubkeys(payer_candidates(&nodes, &policy, "02dd", |_| true)),
+            vec!["node1", "node2"]
+        );
+        assert!(payer_candidates(&nodes, &policy, "02bb", |_| false).is_empty());
+    }
+
+    #[test]
+    fn test_event_sender_fans_out() {
+        let events = ClusterEventSender::default();
+        let mut first = events.subscribe();
+        let mut second = events.subscribe();
+        let event = || {
+            ClusterEvent::PaymentFailed(ClusterPayPaymentRequestRes {
+                pubkey: "02ab".to_string(),
+                payment_error: Some("no route".to_string()),
+                payment_preimage: None,
+                payment_route: None,
+                payment_hash: None,
+            })
+        };
+
+        assert!(events.send(event()).is_ok());
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+
+        drop(first);
+        assert!(events.send(event()).is_ok());
+        assert!(second.try_recv().is_ok());
+        assert!(!events.is_closed());
+
+        drop(second);
+        assert!(events.is_closed());
+        assert!(events.send(event()).is_err());
+    }
+
+    #[test]
+    fn test_invoice_expiry_sec() {
+        assert_eq!(invoice_expiry_sec(600), 600);
+        assert_eq!(invoice_expiry_sec(0), 86400);
+    }
+
+    #[tokio::test]
+    async fn test_cln_node_unsupported() {
+        let node = Node {
+            pubkey: "02cc".to_string(),
+            ip: "127.0.0.1".to_string(),
+            port: "3010".to_string(),
+            network: NodeNetwork::Testnet,
+            lightning_impl: NodeLightningImpl::CLightning,
+            client: NodeClient::CLightning(ClnClient::new(
+                String::new(),
+                String::new(),
+                String::new(),
+            )),
+        };
+        assert!(node.supports_offers());
+        assert!(!node.supports_invoices() && !node.supports_wallet() && !node.supports_channels());
+
+        let hashed = ClusterAddInvoice {
+            description_hash: Some("ab".repeat(32)),
+            ..Default::default()
+        };
+        let err = node.add_invoice(hashed).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "description_hash is not supported on CLN nodes"
+        );
+        let err = node
+            .add_hold_invoice(ClusterAddHoldInvoice::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Creating hold invoices is not supported on CLN nodes"
+        );
+        assert!(node
+            .next_address(ClusterNewAddress::default())
+            .await
+            .is_err());
+        assert!(node.list_utxos().await.is_err());
+        assert!(node.list_channels().await.is_err());
+        assert!(node.estimate_route_fee("02ab", 1000).await.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_requires_channel_support() {
+        let cln_node = |pubkey: &str| Node {
+            pubkey: pubkey.to_string(),
+            ip: "127.0.0.1".to_string(),
+            port: "3010".to_string(),
+            network: NodeNetwork::Testnet,
+            lightning_impl: NodeLightningImpl::CLightning,
+            client: NodeClient::CLightning(ClnClient::new(
+                String::new(),
+                String::new(),
+                String::new(),
+            )),
+        };
+        let mut cluster =
+            crate::testing::test_cluster(vec![cln_node("02aa"), cln_node("02bb")]).await;
+
+        let err = cluster
+            .rebalance("02aa", "02bb", 1000, 10)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Node 02aa does not support rebalancing");
+    }
+
     pub async fn create_test_cluster() -> Cluster {
         let node1 = Node {
             pubkey: dotenvy::var("NODE1_PUBKEY").unwrap(),
@@ -565,8 +4466,61 @@ pub mod tests {
 
         let nodes = vec![node1];
         let redis = redis::Client::open("redis://127.0.01/").unwrap().get_async_connection().await.unwrap();
-        let cluster = Cluster::new(nodes, redis, 60, 60, 60);
 
-        cluster
+        Cluster::new(nodes, redis, 60, 60, 60)
+    }
+
+    #[test]
+    fn test_payment_preimage_matches() {
+        let preimage = [7u8; 32];
+        let json = format!(
+            r#"{{
+                "payment_error": "",
+                "payment_preimage": "{}",
+                "payment_route": {{
+                    "total_time_lock": 2580,
+                    "total_fees": "1",
+                    "total_amt": "100001",
+                    "hops": [{{
+                        "chan_id": "2667786019881041920",
+                        "chan_capacity": "1000000",
+                        "amt_to_forward": "100000",
+                        "fee": "1",
+                        "expiry": 2540,
+                        "amt_to_forward_msat": "100000000",
+                        "fee_msat": "1000",
+                        "pub_key": "03b9d2c2a8ef3b5b5a8e46b6d1f8e3e5f5b0a5e3b4c4d5e6f708192a3b4c5d6e7f",
+                        "metadata": ""
+                    }}],
+                    "total_fees_msat": "1000",
+                    "total_amt_msat": "100001000"
+                }},
+                "payment_hash": "{}"
+            }}"#,
+            base64::encode(preimage),
+            base64::encode(Sha256::digest(preimage)),
+        );
+
+        let payment = LndSendPaymentSyncRes::from_json(&json)
+            .unwrap()
+            .to_cluster("02aa".to_string());
+        assert!(payment.payment_error.is_none());
+        assert!(payment_preimage_matches(&payment, &preimage));
+        assert!(!payment_preimage_matches(&payment, &[8u8; 32]));
+    }
+
+    #[tokio::test]
+    async fn test_check_destination_returns_rejection() {
+        let mut cluster = crate::testing::test_cluster(vec![]).await;
+        cluster.destination_policy.deny("02AA");
+
+        // The stand-in Redis does not answer MULTI/EXEC, so the audit write
+        // fails and the rejection must still be returned.
+        let error = cluster
+            .check_destination("03bb", "02aa", None)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<DestinationRejection>().is_some());
+        assert!(cluster.check_destination("03bb", "02cc", None).await.is_ok());
     }
 }
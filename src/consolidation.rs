@@ -0,0 +1,193 @@
+use crate::cluster::{ClusterAddressType, ClusterOnchainTx, ClusterUtxo};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Transaction overhead in vbytes, rounded up.
+const TX_OVERHEAD_VBYTES: u64 = 11;
+/// The consolidated P2WKH output.
+const OUTPUT_VBYTES: u64 = 31;
+/// Smallest P2WKH output most nodes relay.
+const DUST_LIMIT_SAT: u64 = 294;
+
+/// Which UTXOs to consolidate and at what fee rate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConsolidationPolicy {
+    /// UTXOs below this amount are consolidated.
+    pub threshold_sat: u64,
+    /// Skip nodes with fewer small UTXOs than this.
+    pub min_utxos: usize,
+    pub min_confirmations: u64,
+    pub sat_per_vbyte: u64,
+    /// The rate the UTXOs would otherwise be spent at later, used to
+    /// estimate savings. Consolidating only pays off when this is higher
+    /// than `sat_per_vbyte`.
+    pub future_sat_per_vbyte: u64,
+}
+
+impl Default for ConsolidationPolicy {
+    fn default() -> Self {
+        Self {
+            threshold_sat: 100_000,
+            min_utxos: 5,
+            min_confirmations: 1,
+            sat_per_vbyte: 2,
+            future_sat_per_vbyte: 20,
+        }
+    }
+}
+
+/// A proposed sweep of one node's small UTXOs into one output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConsolidationPlan {
+    pub pubkey: String,
+    pub utxos: Vec<ClusterUtxo>,
+    pub input_sat: u64,
+    pub vsize: u64,
+    pub fee_sat: u64,
+    pub output_sat: u64,
+    /// Fees saved by spending one output instead of every input later at
+    /// `future_sat_per_vbyte`, net of the consolidation fee. Negative when
+    /// consolidating costs more than it saves.
+    pub savings_sat: i64,
+    /// Set once the sweep was broadcast.
+    pub tx: Option<ClusterOnchainTx>,
+    /// Set when the sweep failed.
+    pub error: Option<String>,
+}
+
+impl ConsolidationPlan {
+    pub fn worthwhile(&self) -> bool {
+        self.savings_sat > 0 && self.output_sat >= DUST_LIMIT_SAT
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ConsolidationReport {
+    pub plans: Vec<ConsolidationPlan>,
+    pub total_fee_sat: u64,
+    pub total_savings_sat: i64,
+}
+
+/// Moving a node's excess on-chain funds to an address of another node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SweepPlan {
+    pub from_pubkey: String,
+    pub to_pubkey: String,
+    pub amount_sat: i64,
+    /// Only issued when the sweep is executed.
+    pub address: Option<String>,
+    pub tx: Option<ClusterOnchainTx>,
+}
+
+/// Spend size in vbytes of an input of the given type, rounded up.
+/// Unknown types are assumed to be P2WKH.
+pub fn input_vbytes(address_type: Option<ClusterAddressType>) -> u64 {
+    match address_type {
+        Some(ClusterAddressType::NestedP2wkh) => 91,
+        Some(ClusterAddressType::P2tr) => 58,
+        Some(ClusterAddressType::P2wkh) | None => 68,
+    }
+}
+
+/// Proposes one consolidation per node, reporting every node with enough
+/// small UTXOs whether or not the plan is `worthwhile`.
+pub fn plan_consolidation(
+    utxos: &[ClusterUtxo],
+    policy: &ConsolidationPolicy,
+) -> ConsolidationReport {
+    let mut by_node: BTreeMap<&str, Vec<&ClusterUtxo>> = BTreeMap::new();
+    for utxo in utxos {
+        if utxo.amount < policy.threshold_sat && utxo.confirmations >= policy.min_confirmations {
+            by_node.entry(&utxo.pubkey).or_default().push(utxo);
+        }
+    }
+
+    let mut report = ConsolidationReport::default();
+    for (pubkey, node_utxos) in by_node {
+        if node_utxos.len() < policy.min_utxos.max(2) {
+            continue;
+        }
+
+        let input_sat: u64 = node_utxos.iter().map(|utxo| utxo.amount).sum();
+        let inputs_vbytes: u64 = node_utxos
+            .iter()
+            .map(|utxo| input_vbytes(utxo.address_type))
+            .sum();
+        let vsize = TX_OVERHEAD_VBYTES + inputs_vbytes + OUTPUT_VBYTES;
+        let fee_sat = vsize * policy.sat_per_vbyte;
+
+        let spend_inputs_later = inputs_vbytes * policy.future_sat_per_vbyte;
+        let spend_output_later =
+            input_vbytes(Some(ClusterAddressType::P2wkh)) * policy.future_sat_per_vbyte;
+        let savings_sat = spend_inputs_later as i64 - (fee_sat + spend_output_later) as i64;
+
+        let plan = ConsolidationPlan {
+            pubkey: pubkey.to_string(),
+            utxos: node_utxos.into_iter().cloned().collect(),
+            input_sat,
+            vsize,
+            fee_sat,
+            output_sat: input_sat.saturating_sub(fee_sat),
+            savings_sat,
+            tx: None,
+            error: None,
+        };
+
+        if plan.worthwhile() {
+            report.total_fee_sat += plan.fee_sat;
+            report.total_savings_sat += plan.savings_sat;
+        }
+        report.plans.push(plan);
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plan_consolidation, ConsolidationPolicy};
+    use crate::cluster::{ClusterAddressType, ClusterUtxo};
+
+    fn utxo(pubkey: &str, amount: u64, output_index: u32) -> ClusterUtxo {
+        ClusterUtxo {
+            pubkey: pubkey.to_string(),
+            address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+            amount,
+            confirmations: 6,
+            txid: "ab01".to_string(),
+            output_index,
+            pk_script: "".to_string(),
+            address_type: Some(ClusterAddressType::P2wkh),
+        }
+    }
+
+    #[test]
+    fn test_plan_consolidation() {
+        let mut utxos: Vec<ClusterUtxo> = (0..10).map(|i| utxo("a", 10_000, i)).collect();
+        utxos.push(utxo("a", 5_000_000, 10));
+        utxos.extend((0..2).map(|i| utxo("b", 10_000, i)));
+
+        let policy = ConsolidationPolicy::default();
+        let report = plan_consolidation(&utxos, &policy);
+
+        // node b has too few small UTXOs and the large UTXO is left alone
+        assert_eq!(report.plans.len(), 1);
+        let plan = &report.plans[0];
+        assert_eq!(plan.pubkey, "a");
+        assert_eq!(plan.utxos.len(), 10);
+        assert_eq!(plan.input_sat, 100_000);
+        assert_eq!(plan.vsize, 11 + 680 + 31);
+        assert_eq!(plan.fee_sat, 722 * 2);
+        assert_eq!(plan.savings_sat, 680 * 20 - (722 * 2 + 68 * 20));
+        assert!(plan.worthwhile());
+        assert_eq!(report.total_fee_sat, plan.fee_sat);
+
+        let expensive = ConsolidationPolicy {
+            sat_per_vbyte: 50,
+            ..policy
+        };
+        let report = plan_consolidation(&utxos, &expensive);
+        assert!(!report.plans[0].worthwhile());
+        assert_eq!(report.total_savings_sat, 0);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::cluster::{ClusterAddressDeposit, ClusterEvent, ClusterEventSender, Node};
+use anyhow::Result;
+use redis::aio::Connection;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// How long an issued address is watched for deposits.
+pub const ADDRESS_RETENTION_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// Blocks scanned back from the tip when looking up the deposits to one
+/// address, about `ADDRESS_RETENTION_SECS` at ten minutes a block.
+pub const ADDRESS_SCAN_BLOCKS: i32 = (ADDRESS_RETENTION_SECS / 600) as i32;
+
+/// Polls each node's wallet transactions and reports deposits to addresses
+/// issued by `Cluster::next_address`. Every poll scans from the last
+/// checkpointed block height, far enough back to cover every deposit that
+/// had not yet reached the highest threshold. The deposits still being
+/// followed are kept in the cache, so a restart continues from the last
+/// poll.
+pub struct DepositWatcher {
+    pub cache: Connection,
+    /// Confirmation counts to report, in ascending order.
+    pub thresholds: Vec<i32>,
+    pub poll_interval: Duration,
+}
+
+/// A deposit still being followed and the highest threshold reported for
+/// it, 0 when only its detection was reported.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrackedDeposit {
+    pub deposit: ClusterAddressDeposit,
+    pub notified: i32,
+}
+
+impl DepositWatcher {
+    pub fn new(cache: Connection, mut thresholds: Vec<i32>) -> Result<DepositWatcher> {
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        if thresholds.first().is_none_or(|threshold| *threshold < 1) {
+            return Err(anyhow::anyhow!(
+                "Thresholds must be at least 1 confirmation"
+            ));
+        }
+
+        Ok(Self {
+            cache,
+            thresholds,
+            poll_interval: Duration::from_secs(30),
+        })
+    }
+
+    pub async fn run(mut self, nodes: Vec<Node>, events: ClusterEventSender) {
+        while !events.is_closed() {
+            for node in &nodes {
+                if let Err(e) = self.poll(node, &events).await {
+                    eprintln!("deposit poll failed for {}: {}", node.pubkey, e);
+                }
+            }
+
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Scans one node once. Events are published before the new state is
+    /// saved, so a crash in between repeats events rather than losing them.
+    pub async fn poll(&mut self, node: &Node, events: &ClusterEventSender) -> Result<()> {
+        let tip = node.block_height().await?;
+        let max_threshold = self.thresholds.last().copied().unwrap_or(1);
+
+        let checkpoint: Option<i32> = self.cache.get(checkpoint_key(&node.pubkey)).await?;
+        // A deposit at height h had tip - h + 1 confirmations at the last
+        // checkpoint, so only heights above checkpoint - max_threshold + 1
+        // can still have thresholds left to report.
+        let start_height = (checkpoint.unwrap_or(tip).min(tip) - max_threshold + 2).max(0);
+
+        let mut issued = vec![];
+        for deposit in node.wallet_deposits(start_height).await? {
+            let issued_at: Option<u64> = self
+                .cache
+                .zscore(addresses_key(&node.pubkey), &deposit.address)
+                .await?;
+            if issued_at.is_some() {
+                issued.push(deposit);
+            }
+        }
+
+        let tracked_json: HashMap<String, String> =
+            self.cache.hgetall(tracked_key(&node.pubkey)).await?;
+        let tracked = tracked_json
+            .iter()
+            .map(|(key, json)| Ok((key.clone(), serde_json::from_str(json)?)))
+            .collect::<Result<HashMap<String, TrackedDeposit>>>()?;
+
+        let (new_events, tracked) = diff_deposits(&tracked, issued, &self.thresholds);
+        for event in new_events {
+            let _ = events.send(event);
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(tracked_key(&node.pubkey)).ignore();
+        for (key, tracked_deposit) in &tracked {
+            pipe.hset(
+                tracked_key(&node.pubkey),
+                key,
+                serde_json::to_string(tracked_deposit)?,
+            )
+            .ignore();
+        }
+        pipe.set(checkpoint_key(&node.pubkey), tip).ignore();
+        let _: () = pipe.query_async(&mut self.cache).await?;
+
+        Ok(())
+    }
+}
+
+/// Compares a scan with the deposits followed so far, returning the events
+/// to publish and the deposits to keep following.
+pub fn diff_deposits(
+    tracked: &HashMap<String, TrackedDeposit>,
+    current: Vec<ClusterAddressDeposit>,
+    thresholds: &[i32],
+) -> (Vec<ClusterEvent>, HashMap<String, TrackedDeposit>) {
+    let max_threshold = thresholds.last().copied().unwrap_or(0);
+    let mut events = vec![];
+    let mut next = HashMap::new();
+    let mut seen = HashSet::new();
+
+    for deposit in current {
+        let key = outpoint_key(&deposit);
+        seen.insert(key.clone());
+
+        let mut notified = match tracked.get(&key) {
+            None => {
+                events.push(ClusterEvent::DepositDetected(deposit.clone()));
+                0
+            }
+            Some(prev)
+                if prev.deposit.block_height != 0
+                    && prev.deposit.block_height != deposit.block_height =>
+            {
+                events.push(ClusterEvent::DepositReorged(deposit.clone()));
+                0
+            }
+            Some(prev) => prev.notified,
+        };
+
+        for threshold in thresholds {
+            if *threshold > notified && *threshold <= deposit.confirmations {
+                events.push(ClusterEvent::DepositConfirmed {
+                    deposit: deposit.clone(),
+                    threshold: *threshold,
+                });
+                notified = *threshold;
+            }
+        }
+
+        if notified < max_threshold {
+            next.insert(key, TrackedDeposit { deposit, notified });
+        }
+    }
+
+    for (key, prev) in tracked {
+        if !seen.contains(key) {
+            events.push(ClusterEvent::DepositDoubleSpent(prev.deposit.clone()));
+        }
+    }
+
+    (events, next)
+}
+
+/// Records that `Cluster::next_address` issued `address` from a node at
+/// `now`, forgetting the addresses issued more than
+/// `ADDRESS_RETENTION_SECS` earlier.
+pub async fn track_address(
+    cache: &mut Connection,
+    pubkey: &str,
+    address: &str,
+    now: u64,
+) -> Result<()> {
+    let key = addresses_key(pubkey);
+    let cutoff = now.saturating_sub(ADDRESS_RETENTION_SECS);
+    let _: () = redis::pipe()
+        .atomic()
+        .zadd(&key, address, now)
+        .ignore()
+        .zrembyscore(&key, "-inf", format!("({}", cutoff))
+        .ignore()
+        .query_async(cache)
+        .await?;
+
+    Ok(())
+}
+
+/// Sorted set of the addresses `Cluster::next_address` issued from a node,
+/// scored by the time they were issued.
+pub fn addresses_key(pubkey: &str) -> String {
+    format!("addresses:issued:{}", pubkey)
+}
+
+fn tracked_key(pubkey: &str) -> String {
+    format!("deposits:tracked:{}", pubkey)
+}
+
+fn checkpoint_key(pubkey: &str) -> String {
+    format!("deposits:checkpoint:{}", pubkey)
+}
+
+fn outpoint_key(deposit: &ClusterAddressDeposit) -> String {
+    format!("{}:{}", deposit.txid, deposit.output_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff_deposits;
+    use crate::cluster::{ClusterAddressDeposit, ClusterEvent};
+    use std::collections::HashMap;
+
+    fn deposit(confirmations: i32, block_height: i32) -> ClusterAddressDeposit {
+        ClusterAddressDeposit {
+            pubkey: "02ab".to_string(),
+            address: "bc1qdeposit".to_string(),
+            txid: "ab01".to_string(),
+            output_index: 0,
+            amount: 5000,
+            confirmations,
+            block_height,
+        }
+    }
+
+    fn kinds(events: &[ClusterEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| match event {
+                ClusterEvent::DepositDetected(_) => "detected".to_string(),
+                ClusterEvent::DepositConfirmed { threshold, .. } => {
+                    format!("confirmed:{}", threshold)
+                }
+                ClusterEvent::DepositReorged(_) => "reorged".to_string(),
+                ClusterEvent::DepositDoubleSpent(_) => "double_spent".to_string(),
+                _ => "other".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_deposits() {
+        let thresholds = [1, 3];
+
+        let (events, tracked) = diff_deposits(&HashMap::new(), vec![deposit(0, 0)], &thresholds);
+        assert_eq!(kinds(&events), vec!["detected"]);
+
+        let (events, tracked) = diff_deposits(&tracked, vec![deposit(2, 100)], &thresholds);
+        assert_eq!(kinds(&events), vec!["confirmed:1"]);
+
+        // the block at 100 was replaced, the deposit confirmed again at 101
+        let (events, tracked) = diff_deposits(&tracked, vec![deposit(1, 101)], &thresholds);
+        assert_eq!(kinds(&events), vec!["reorged", "confirmed:1"]);
+
+        let (events, tracked) = diff_deposits(&tracked, vec![deposit(3, 101)], &thresholds);
+        assert_eq!(kinds(&events), vec!["confirmed:3"]);
+        assert!(tracked.is_empty());
+
+        let (_, tracked) = diff_deposits(&HashMap::new(), vec![deposit(0, 0)], &thresholds);
+        let (events, tracked) = diff_deposits(&tracked, vec![], &thresholds);
+        assert_eq!(kinds(&events), vec!["double_spent"]);
+        assert!(tracked.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub const AUDIT_KEY: &str = "audit:destination_rejections";
+/// Rejections kept under `AUDIT_KEY`, older ones are trimmed.
+pub const AUDIT_LIMIT: isize = 1000;
+
+/// Destinations the cluster may pay. Pubkeys are hex encoded and compared
+/// case-insensitively.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DestinationPolicy {
+    /// Destinations no node may pay.
+    pub deny: HashSet<String>,
+    /// Nodes that may only pay the destinations listed for them.
+    pub node_allowlists: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    Denied,
+    NotAllowlisted,
+}
+
+/// A payment refused by `DestinationPolicy`. Returned wrapped in
+/// `anyhow::Error` and recorded under `AUDIT_KEY`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DestinationRejection {
+    pub pubkey: String,
+    pub dest: String,
+    pub reason: RejectionReason,
+    pub payment_hash: Option<String>,
+    pub timestamp: u64,
+}
+
+impl fmt::Display for DestinationRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            RejectionReason::Denied => write!(f, "Payments to {} are denied", self.dest),
+            RejectionReason::NotAllowlisted => write!(
+                f,
+                "Node {} may not pay {}, it is not on the node's allowlist",
+                self.pubkey, self.dest
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DestinationRejection {}
+
+impl DestinationPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.node_allowlists.is_empty()
+    }
+
+    pub fn deny(&mut self, dest: &str) {
+        self.deny.insert(dest.to_lowercase());
+    }
+
+    pub fn allow(&mut self, pubkey: &str, dest: &str) {
+        self.node_allowlists
+            .entry(pubkey.to_string())
+            .or_default()
+            .insert(dest.to_lowercase());
+    }
+
+    /// Why `pubkey` may not pay `dest`, if it may not. The fields are public
+    /// and deserialized as written, so entries are matched ignoring case.
+    pub fn check(&self, pubkey: &str, dest: &str) -> Option<RejectionReason> {
+        let matches = |entry: &String| entry.eq_ignore_ascii_case(dest);
+
+        if self.deny.iter().any(matches) {
+            return Some(RejectionReason::Denied);
+        }
+
+        let allowlist = self
+            .node_allowlists
+            .iter()
+            .find(|(node, _)| node.eq_ignore_ascii_case(pubkey));
+        match allowlist {
+            Some((_, allowlist)) if !allowlist.iter().any(matches) => {
+                Some(RejectionReason::NotAllowlisted)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DestinationPolicy, RejectionReason};
+
+    #[test]
+    fn test_destination_policy() {
+        let mut policy = DestinationPolicy::default();
+        policy.deny("02AA");
+        policy.allow("node1", "02bb");
+
+        assert_eq!(policy.check("node2", "02aa"), Some(RejectionReason::Denied));
+        assert_eq!(policy.check("node1", "02aa"), Some(RejectionReason::Denied));
+        assert_eq!(policy.check("node1", "02BB"), None);
+        assert_eq!(
+            policy.check("node1", "02cc"),
+            Some(RejectionReason::NotAllowlisted)
+        );
+        assert_eq!(policy.check("node2", "02cc"), None);
+    }
+
+    #[test]
+    fn test_destination_policy_from_config() {
+        let policy: DestinationPolicy =
+            serde_json::from_str(r#"{"deny": ["02AA"], "node_allowlists": {"03DD": ["02BB"]}}"#)
+                .unwrap();
+
+        assert_eq!(policy.check("node2", "02aa"), Some(RejectionReason::Denied));
+        assert_eq!(policy.check("03dd", "02bb"), None);
+        assert_eq!(
+            policy.check("03dd", "02cc"),
+            Some(RejectionReason::NotAllowlisted)
+        );
+    }
+}
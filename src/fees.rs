@@ -0,0 +1,175 @@
+use crate::lnd::FeeLimit;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// The most a payment may spend on routing fees. Every configured cap
+/// applies and the smallest one wins, then `floor_sat` raises the result,
+/// so `min(1%, 500 sat)` with a floor of 10 sat is
+/// `FeePolicy::percent(1.0).with_cap(500).with_floor(10)`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FeePolicy {
+    pub percent: Option<f64>,
+    pub ppm: Option<u64>,
+    pub fixed_sat: Option<u64>,
+    pub floor_sat: Option<u64>,
+}
+
+/// CLN `pay` fee parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClnFeeLimit {
+    pub maxfeepercent: f64,
+    pub exemptfee_msat: u64,
+}
+
+impl FeePolicy {
+    pub fn fixed(fixed_sat: u64) -> FeePolicy {
+        Self {
+            fixed_sat: Some(fixed_sat),
+            ..Default::default()
+        }
+    }
+
+    pub fn percent(percent: f64) -> FeePolicy {
+        Self {
+            percent: Some(percent),
+            ..Default::default()
+        }
+    }
+
+    pub fn ppm(ppm: u64) -> FeePolicy {
+        Self {
+            ppm: Some(ppm),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_cap(self, fixed_sat: u64) -> FeePolicy {
+        Self {
+            fixed_sat: Some(fixed_sat),
+            ..self
+        }
+    }
+
+    pub fn with_floor(self, floor_sat: u64) -> FeePolicy {
+        Self {
+            floor_sat: Some(floor_sat),
+            ..self
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(percent) = self.percent {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(anyhow::anyhow!("Fee percent must be between 0 and 100"));
+            }
+        }
+
+        if self.percent.is_none()
+            && self.ppm.is_none()
+            && self.fixed_sat.is_none()
+            && self.floor_sat.is_none()
+        {
+            return Err(anyhow::anyhow!("Fee policy has no limits configured"));
+        }
+
+        Ok(())
+    }
+
+    /// The fee limit in millisatoshis for a payment of `amount_msat`.
+    pub fn max_fee_msat(&self, amount_msat: u64) -> u64 {
+        let caps = [
+            self.percent
+                .map(|percent| (amount_msat as f64 * percent / 100.0).floor() as u64),
+            self.ppm
+                .map(|ppm| (amount_msat as u128 * ppm as u128 / 1_000_000) as u64),
+            self.fixed_sat.map(|fixed_sat| fixed_sat * 1000),
+        ];
+
+        let cap = caps.iter().flatten().min().copied().unwrap_or(0);
+        let floor = self.floor_sat.unwrap_or(0) * 1000;
+
+        cap.max(floor)
+    }
+
+    /// Whether the policy is a plain whole percentage, which LND can apply
+    /// natively.
+    fn whole_percent(&self) -> Option<i64> {
+        match self {
+            FeePolicy {
+                percent: Some(percent),
+                ppm: None,
+                fixed_sat: None,
+                floor_sat: None,
+            } if percent.fract() == 0.0 => Some(*percent as i64),
+            _ => None,
+        }
+    }
+
+    /// LND `fee_limit` for a payment of `amount_msat`.
+    pub fn to_lnd(&self, amount_msat: u64) -> FeeLimit {
+        match self.whole_percent() {
+            Some(percent) => FeeLimit {
+                percent: Some(percent.to_string()),
+                ..Default::default()
+            },
+            None => FeeLimit {
+                fixed_msat: Some(self.max_fee_msat(amount_msat).to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// CLN `maxfeepercent`/`exemptfee` for a payment of `amount_msat`. A
+    /// percentage with an optional floor maps directly, anything else is
+    /// expressed as an exempt fee with no percentage allowance.
+    pub fn to_cln(&self, amount_msat: u64) -> ClnFeeLimit {
+        match self {
+            FeePolicy {
+                percent: Some(percent),
+                ppm: None,
+                fixed_sat: None,
+                floor_sat,
+            } => ClnFeeLimit {
+                maxfeepercent: *percent,
+                exemptfee_msat: floor_sat.unwrap_or(0) * 1000,
+            },
+            _ => ClnFeeLimit {
+                maxfeepercent: 0.0,
+                exemptfee_msat: self.max_fee_msat(amount_msat),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FeePolicy;
+
+    #[test]
+    fn test_fee_policy_limits() {
+        let policy = FeePolicy::percent(1.0).with_cap(500).with_floor(10);
+
+        // 1% of 100 sat is below the floor
+        assert_eq!(policy.max_fee_msat(100_000), 10_000);
+        // 1% of 20k sat
+        assert_eq!(policy.max_fee_msat(20_000_000), 200_000);
+        // 1% of 1M sat is above the cap
+        assert_eq!(policy.max_fee_msat(1_000_000_000), 500_000);
+
+        assert_eq!(FeePolicy::ppm(1000).max_fee_msat(1_000_000), 1000);
+
+        let lnd = FeePolicy::percent(3.0).to_lnd(1_000_000);
+        assert_eq!(lnd.percent.as_deref(), Some("3"));
+        assert!(lnd.fixed_msat.is_none());
+
+        let lnd = policy.to_lnd(1_000_000_000);
+        assert_eq!(lnd.fixed_msat.as_deref(), Some("500000"));
+
+        let cln = FeePolicy::percent(0.5).with_floor(5).to_cln(1_000_000);
+        assert_eq!(cln.maxfeepercent, 0.5);
+        assert_eq!(cln.exemptfee_msat, 5000);
+
+        assert!(FeePolicy::default().validate().is_err());
+    }
+}
@@ -1,2 +1,17 @@
+pub mod cln;
 pub mod cluster;
+pub mod consolidation;
+pub mod deposits;
+pub mod destinations;
+pub mod fees;
+pub mod limits;
 pub mod lnd;
+pub mod lnurl;
+pub mod lnurl_server;
+pub mod lnurl_withdraw;
+pub mod nwc;
+pub mod onchain_batch;
+pub mod rebalance;
+#[cfg(test)]
+mod testing;
+pub mod webhook;
@@ -0,0 +1,471 @@
+use anyhow::Result;
+use redis::aio::Connection;
+use redis::{AsyncCommands, Script};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Checks every budget before adding the reservation to any of them, so a
+/// payment is either counted against all of its budgets or none. Each key
+/// is a sorted set of `<id>:<msat>` members scored by time in milliseconds.
+const RESERVE_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local amount = tonumber(ARGV[2])
+local member = ARGV[3]
+local spent = {}
+for i, key in ipairs(KEYS) do
+  local budget = tonumber(ARGV[2 + i * 2])
+  local window = tonumber(ARGV[3 + i * 2])
+  redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window * 1000)
+  local total = 0
+  for _, entry in ipairs(redis.call('ZRANGE', key, 0, -1)) do
+    total = total + tonumber(string.match(entry, ':(%d+)$'))
+  end
+  if total + amount > budget then
+    return {0, i, total}
+  end
+  spent[i] = total + amount
+end
+for i, key in ipairs(KEYS) do
+  local window = tonumber(ARGV[3 + i * 2])
+  redis.call('ZADD', key, now, member)
+  redis.call('PEXPIRE', key, window * 1000)
+end
+table.insert(spent, 1, 1)
+return spent
+"#;
+
+/// Replaces a reservation's member with one for the amount actually spent,
+/// keeping the time it was counted from.
+const SETTLE_SCRIPT: &str = r#"
+for _, key in ipairs(KEYS) do
+  local score = redis.call('ZSCORE', key, ARGV[1])
+  if score then
+    redis.call('ZREM', key, ARGV[1])
+    redis.call('ZADD', key, score, ARGV[2])
+  end
+end
+return 1
+"#;
+
+/// Limits on what the cluster may spend. Budgets are rolling windows, so a
+/// payment stops counting `window_sec` after it was made.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpendLimits {
+    pub max_payment_msat: Option<u64>,
+    /// Applies to every node separately.
+    pub node_budget: Option<SpendBudget>,
+    /// Budgets per API key or tenant.
+    pub tenant_budgets: HashMap<String, SpendBudget>,
+    /// Applies to tenants without an entry in `tenant_budgets`.
+    pub default_tenant_budget: Option<SpendBudget>,
+    /// Share of a budget after which `ClusterEvent::SpendLimitWarning` is
+    /// emitted.
+    pub warn_ratio: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SpendBudget {
+    pub budget_msat: u64,
+    pub window_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum SpendScope {
+    Node(String),
+    Tenant(String),
+}
+
+/// Spending within one budget's current window.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpendStatus {
+    pub scope: SpendScope,
+    pub spent_msat: u64,
+    pub budget_msat: u64,
+    pub window_sec: u64,
+}
+
+impl SpendStatus {
+    pub fn remaining_msat(&self) -> u64 {
+        self.budget_msat.saturating_sub(self.spent_msat)
+    }
+
+    pub fn near_limit(&self, warn_ratio: f64) -> bool {
+        self.spent_msat as f64 >= self.budget_msat as f64 * warn_ratio
+    }
+}
+
+/// Why a payment was rejected. Returned wrapped in `anyhow::Error`, use
+/// `downcast_ref::<SpendLimitError>()` to inspect it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpendLimitError {
+    PaymentTooLarge {
+        amount_msat: u64,
+        max_payment_msat: u64,
+    },
+    BudgetExceeded {
+        status: SpendStatus,
+        amount_msat: u64,
+    },
+    /// Spend limits are configured but the payment amount is not known up
+    /// front.
+    UnknownAmount,
+}
+
+impl fmt::Display for SpendLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpendLimitError::PaymentTooLarge {
+                amount_msat,
+                max_payment_msat,
+            } => write!(
+                f,
+                "Payment of {} msat exceeds the maximum of {} msat",
+                amount_msat, max_payment_msat
+            ),
+            SpendLimitError::BudgetExceeded {
+                status,
+                amount_msat,
+            } => write!(
+                f,
+                "Payment of {} msat exceeds the {:?} budget, {} of {} msat remaining",
+                amount_msat,
+                status.scope,
+                status.remaining_msat(),
+                status.budget_msat
+            ),
+            SpendLimitError::UnknownAmount => {
+                write!(f, "An amount is required when spend limits are configured")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SpendLimitError {}
+
+/// Spending counted against budgets by `SpendLimits::reserve`, to be
+/// released with `SpendLimits::release` if the payment fails or settled to
+/// the amount spent with `SpendLimits::settle` if it succeeds.
+#[derive(Debug, Clone)]
+pub struct SpendReservation {
+    keys: Vec<String>,
+    member: String,
+}
+
+impl SpendReservation {
+    /// The member counting `spent_msat` in place of the reserved amount.
+    fn settled_member(&self, spent_msat: u64) -> String {
+        let id = self
+            .member
+            .rsplit_once(':')
+            .map_or(self.member.as_str(), |(id, _)| id);
+        format!("{}:{}", id, spent_msat)
+    }
+}
+
+impl Default for SpendLimits {
+    fn default() -> Self {
+        Self {
+            max_payment_msat: None,
+            node_budget: None,
+            tenant_budgets: HashMap::new(),
+            default_tenant_budget: None,
+            warn_ratio: 0.8,
+        }
+    }
+}
+
+impl SpendLimits {
+    pub fn is_active(&self) -> bool {
+        self.max_payment_msat.is_some()
+            || self.node_budget.is_some()
+            || !self.tenant_budgets.is_empty()
+            || self.default_tenant_budget.is_some()
+    }
+
+    pub fn check_payment(&self, amount_msat: u64) -> Result<(), SpendLimitError> {
+        match self.max_payment_msat {
+            Some(max_payment_msat) if amount_msat > max_payment_msat => {
+                Err(SpendLimitError::PaymentTooLarge {
+                    amount_msat,
+                    max_payment_msat,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn tenant_budget(&self, tenant: &str) -> Option<SpendBudget> {
+        self.tenant_budgets
+            .get(tenant)
+            .copied()
+            .or(self.default_tenant_budget)
+    }
+
+    fn budgets(&self, pubkey: &str, tenant: Option<&str>) -> Vec<(SpendScope, SpendBudget)> {
+        let mut budgets = vec![];
+        if let Some(budget) = self.node_budget {
+            budgets.push((SpendScope::Node(pubkey.to_string()), budget));
+        }
+        if let Some(tenant) = tenant {
+            if let Some(budget) = self.tenant_budget(tenant) {
+                budgets.push((SpendScope::Tenant(tenant.to_string()), budget));
+            }
+        }
+        budgets
+    }
+
+    /// Atomically counts `amount_msat` against the node's and the tenant's
+    /// budgets, returning the reservation and the budgets' new state.
+    pub async fn reserve(
+        &self,
+        cache: &mut Connection,
+        pubkey: &str,
+        tenant: Option<&str>,
+        amount_msat: u64,
+    ) -> Result<(Option<SpendReservation>, Vec<SpendStatus>)> {
+        let budgets = self.budgets(pubkey, tenant);
+        if budgets.is_empty() {
+            return Ok((None, vec![]));
+        }
+
+        let member = format!(
+            "{}:{}",
+            hex::encode(rand::random::<[u8; 16]>()),
+            amount_msat
+        );
+        let (keys, args) = reserve_args(now_ms(), amount_msat, &member, &budgets);
+        let script = Script::new(RESERVE_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(keys).arg(args);
+
+        let result: Vec<u64> = invocation.invoke_async(cache).await?;
+        if result.first() != Some(&1) {
+            let (scope, budget) = budgets[result[1] as usize - 1].clone();
+            return Err(SpendLimitError::BudgetExceeded {
+                status: SpendStatus {
+                    scope,
+                    spent_msat: result[2],
+                    budget_msat: budget.budget_msat,
+                    window_sec: budget.window_sec,
+                },
+                amount_msat,
+            }
+            .into());
+        }
+
+        let statuses = budgets
+            .iter()
+            .zip(&result[1..])
+            .map(|((scope, budget), spent_msat)| SpendStatus {
+                scope: scope.clone(),
+                spent_msat: *spent_msat,
+                budget_msat: budget.budget_msat,
+                window_sec: budget.window_sec,
+            })
+            .collect();
+
+        let reservation = SpendReservation {
+            keys: budgets.iter().map(|(scope, _)| spend_key(scope)).collect(),
+            member,
+        };
+
+        Ok((Some(reservation), statuses))
+    }
+
+    pub async fn release(
+        &self,
+        cache: &mut Connection,
+        reservation: &SpendReservation,
+    ) -> Result<()> {
+        for key in &reservation.keys {
+            let _: () = cache.zrem(key, &reservation.member).await?;
+        }
+        Ok(())
+    }
+
+    /// Counts `spent_msat`, the payment amount plus the fee actually paid,
+    /// in place of the reserved amount.
+    pub async fn settle(
+        &self,
+        cache: &mut Connection,
+        reservation: &SpendReservation,
+        spent_msat: u64,
+    ) -> Result<()> {
+        let _: i32 = Script::new(SETTLE_SCRIPT)
+            .key(&reservation.keys)
+            .arg(&reservation.member)
+            .arg(reservation.settled_member(spent_msat))
+            .invoke_async(cache)
+            .await?;
+        Ok(())
+    }
+
+    /// Current spending against a budget, or `None` when the scope has no
+    /// budget.
+    pub async fn status(
+        &self,
+        cache: &mut Connection,
+        scope: &SpendScope,
+    ) -> Result<Option<SpendStatus>> {
+        let budget = match scope {
+            SpendScope::Node(_) => self.node_budget,
+            SpendScope::Tenant(tenant) => self.tenant_budget(tenant),
+        };
+        let budget = match budget {
+            Some(budget) => budget,
+            None => return Ok(None),
+        };
+
+        let since = now_ms().saturating_sub(budget.window_sec * 1000);
+        let entries: Vec<String> = cache.zrangebyscore(spend_key(scope), since, "+inf").await?;
+
+        Ok(Some(SpendStatus {
+            scope: scope.clone(),
+            spent_msat: entries.iter().map(|entry| entry_amount(entry)).sum(),
+            budget_msat: budget.budget_msat,
+            window_sec: budget.window_sec,
+        }))
+    }
+}
+
+/// The keys and arguments of `RESERVE_SCRIPT`: the time, amount and member
+/// followed by a budget and window per key.
+fn reserve_args(
+    now_ms: u64,
+    amount_msat: u64,
+    member: &str,
+    budgets: &[(SpendScope, SpendBudget)],
+) -> (Vec<String>, Vec<String>) {
+    let keys = budgets.iter().map(|(scope, _)| spend_key(scope)).collect();
+    let mut args = vec![
+        now_ms.to_string(),
+        amount_msat.to_string(),
+        member.to_string(),
+    ];
+    for (_, budget) in budgets {
+        args.push(budget.budget_msat.to_string());
+        args.push(budget.window_sec.to_string());
+    }
+    (keys, args)
+}
+
+fn entry_amount(entry: &str) -> u64 {
+    entry
+        .rsplit_once(':')
+        .and_then(|(_, amount)| amount.parse().ok())
+        .unwrap_or(0)
+}
+
+fn spend_key(scope: &SpendScope) -> String {
+    match scope {
+        SpendScope::Node(pubkey) => format!("limits:node:{}", pubkey),
+        SpendScope::Tenant(tenant) => format!("limits:tenant:{}", tenant),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        entry_amount, reserve_args, SpendBudget, SpendLimitError, SpendLimits, SpendReservation,
+        SpendScope, RESERVE_SCRIPT,
+    };
+
+    #[test]
+    fn test_spend_limits() {
+        let mut limits = SpendLimits {
+            max_payment_msat: Some(1_000_000),
+            ..Default::default()
+        };
+        assert!(limits.check_payment(1_000_000).is_ok());
+        assert_eq!(
+            limits.check_payment(1_000_001),
+            Err(SpendLimitError::PaymentTooLarge {
+                amount_msat: 1_000_001,
+                max_payment_msat: 1_000_000,
+            })
+        );
+
+        let budget = SpendBudget {
+            budget_msat: 5_000_000,
+            window_sec: 86400,
+        };
+        limits.node_budget = Some(budget);
+        limits.tenant_budgets.insert("acme".to_string(), budget);
+
+        let scopes: Vec<SpendScope> = limits
+            .budgets("02ab", Some("acme"))
+            .into_iter()
+            .map(|(scope, _)| scope)
+            .collect();
+        assert_eq!(
+            scopes,
+            vec![
+                SpendScope::Node("02ab".to_string()),
+                SpendScope::Tenant("acme".to_string())
+            ]
+        );
+        assert_eq!(limits.budgets("02ab", Some("other")).len(), 1);
+        assert!(limits.is_active());
+
+        assert_eq!(entry_amount("deadbeef:2500"), 2500);
+    }
+
+    #[test]
+    fn test_reserve_script_args() {
+        let budgets = vec![
+            (
+                SpendScope::Node("02ab".to_string()),
+                SpendBudget {
+                    budget_msat: 5_000_000,
+                    window_sec: 86400,
+                },
+            ),
+            (
+                SpendScope::Tenant("acme".to_string()),
+                SpendBudget {
+                    budget_msat: 1_000_000,
+                    window_sec: 3600,
+                },
+            ),
+        ];
+        let (keys, args) = reserve_args(1_700_000_000_000, 2500, "deadbeef:2500", &budgets);
+        assert_eq!(keys, vec!["limits:node:02ab", "limits:tenant:acme"]);
+
+        // The script reads ARGV[1..3] and then ARGV[2 + i * 2] and
+        // ARGV[3 + i * 2] for the i-th key, all 1-based.
+        assert!(RESERVE_SCRIPT.contains("tonumber(ARGV[2 + i * 2])"));
+        assert!(RESERVE_SCRIPT.contains("tonumber(ARGV[3 + i * 2])"));
+        let argv = |n: usize| args[n - 1].as_str();
+        assert_eq!(
+            (argv(1), argv(2), argv(3)),
+            ("1700000000000", "2500", "deadbeef:2500")
+        );
+        for (i, (_, budget)) in budgets.iter().enumerate().map(|(i, b)| (i + 1, b)) {
+            assert_eq!(argv(2 + i * 2), budget.budget_msat.to_string());
+            assert_eq!(argv(3 + i * 2), budget.window_sec.to_string());
+        }
+        assert_eq!(args.len(), 3 + keys.len() * 2);
+    }
+
+    #[test]
+    fn test_settled_member() {
+        let reservation = SpendReservation {
+            keys: vec!["limits:node:02ab".to_string()],
+            member: "deadbeef:2500".to_string(),
+        };
+        let settled = reservation.settled_member(2010);
+        assert_eq!(settled, "deadbeef:2010");
+        assert_eq!(entry_amount(&settled), 2010);
+    }
+}
@@ -1,10 +1,13 @@
-use crate::cluster::{self, ClusterAddInvoice, ClusterUtxo, ClusterUtxos};
+use crate::cluster::{self, ClusterAddHoldInvoice, ClusterAddInvoice, ClusterUtxo, ClusterUtxos};
 use anyhow::{Context, Result};
 use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Response;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::Read;
+use std::marker::PhantomData;
 
 #[derive(Clone)]
 pub struct LndClient {
@@ -18,11 +21,255 @@ pub struct NewAddressResponse {
     pub address: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SendCoinsLndRequest {
+    pub addr: String,
+    pub amount: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_conf: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sat_per_vbyte: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Sweep the whole wallet, or only `outpoints` when given, with
+    /// `amount` set to 0.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub send_all: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outpoints: Vec<LndOutPoint>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LndOutPoint {
+    pub txid_str: String,
+    pub output_index: u32,
+}
+
+/// Either `psbt` (base64), or `raw` to let LND build the transaction.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct FundPsbtLndRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub psbt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<TxTemplate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_conf: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sat_per_vbyte: Option<String>,
+    pub min_confs: i32,
+    pub spend_unconfirmed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TxTemplate {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<LndOutPoint>,
+    pub outputs: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FundPsbtResponse {
+    pub funded_psbt: String,
+    pub change_output_index: i32,
+    #[serde(default)]
+    pub locked_utxos: Vec<UtxoLease>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UtxoLease {
+    pub id: String,
+    pub outpoint: Outpoint,
+    pub expiration: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FinalizePsbtLndRequest {
+    pub funded_psbt: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FinalizePsbtResponse {
+    pub signed_psbt: String,
+    pub raw_final_tx: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PublishTransactionLndRequest {
+    pub tx_hex: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PublishResponse {
+    #[serde(default)]
+    pub publish_error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReleaseOutputLndRequest {
+    pub id: String,
+    pub outpoint: LndOutPoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SendManyLndRequest {
+    #[serde(rename = "AddrToAmount")]
+    pub addr_to_amount: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_conf: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sat_per_vbyte: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendCoinsResponse {
+    pub txid: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetInfoResponse {
+    pub identity_pubkey: String,
+    pub block_height: i32,
+    #[serde(default)]
+    pub synced_to_chain: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetTransactionsResponse {
+    #[serde(default)]
+    pub transactions: Vec<LndTransaction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LndTransaction {
+    pub tx_hash: String,
+    pub amount: String,
+    #[serde(default)]
+    pub num_confirmations: i32,
+    #[serde(default)]
+    pub block_hash: String,
+    #[serde(default)]
+    pub block_height: i32,
+    pub time_stamp: String,
+    #[serde(default)]
+    pub output_details: Vec<OutputDetail>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputDetail {
+    pub address: String,
+    pub output_index: String,
+    pub amount: String,
+    #[serde(default)]
+    pub is_our_address: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AddInvoiceLndRequest {
     pub memo: String,
     pub value: i64,
     pub expiry: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_msat: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description_hash: Option<String>,
+    pub private: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cltv_expiry: Option<String>,
+    pub is_amp: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r_preimage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_addr: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddHoldInvoiceLndRequest {
+    pub memo: String,
+    pub hash: String,
+    pub value: i64,
+    pub expiry: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_msat: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cltv_expiry: Option<String>,
+    pub private: bool,
+}
+
+impl AddHoldInvoiceLndRequest {
+    pub fn from_cluster(req: ClusterAddHoldInvoice) -> Result<AddHoldInvoiceLndRequest> {
+        Ok(AddHoldInvoiceLndRequest {
+            memo: req.memo,
+            hash: to_base64(&req.hash)?,
+            value: req.value,
+            expiry: req.expiry,
+            value_msat: req.value_msat.map(|msat| msat.to_string()),
+            description_hash: req.description_hash.as_deref().map(to_base64).transpose()?,
+            cltv_expiry: req.cltv_expiry.map(|cltv| cltv.to_string()),
+            private: req.private,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddHoldInvoiceResponse {
+    pub payment_request: String,
+    pub add_index: String,
+    pub payment_addr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SettleInvoiceLndRequest {
+    pub preimage: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelInvoiceLndRequest {
+    pub payment_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChannelBalanceResponse {
+    pub local_balance: Amount,
+    pub remote_balance: Amount,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Amount {
+    pub sat: String,
+    pub msat: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WalletBalanceResponse {
+    pub total_balance: String,
+    pub confirmed_balance: String,
+    pub unconfirmed_balance: String,
+    #[serde(default)]
+    pub locked_balance: String,
+}
+
+impl WalletBalanceResponse {
+    pub fn to_cluster(&self) -> Result<cluster::ClusterOnchainBalance> {
+        let parse = |value: &str| -> Result<i64> {
+            match value {
+                "" => Ok(0),
+                value => Ok(value.parse::<i64>()?),
+            }
+        };
+
+        Ok(cluster::ClusterOnchainBalance {
+            confirmed: parse(&self.confirmed_balance)?,
+            unconfirmed: parse(&self.unconfirmed_balance)?,
+            locked: parse(&self.locked_balance)?,
+            total: parse(&self.total_balance)?,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,12 +292,14 @@ impl ListUnspentResponse {
             utxos.push(utxo.to_cluster(pubkey.clone())?);
         }
 
-        Ok(ClusterUtxos { utxos: utxos })
+        Ok(ClusterUtxos { utxos })
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Utxo {
+    #[serde(default)]
+    pub address_type: String,
     pub address: String,
     pub amount_sat: String,
     pub confirmations: String,
@@ -62,10 +311,14 @@ impl Utxo {
     pub fn to_cluster(self, pubkey: String) -> Result<ClusterUtxo> {
         let amount = self.amount_sat.parse::<u64>()?;
         Ok(ClusterUtxo {
-            pubkey: pubkey,
+            pubkey,
             address: self.address,
-            amount: amount,
+            amount,
             confirmations: self.confirmations.parse::<u64>()?,
+            txid: self.outpoint.txid_str,
+            output_index: self.outpoint.output_index as u32,
+            pk_script: self.pk_script,
+            address_type: cluster::ClusterAddressType::from_lnd(&self.address_type),
         })
     }
 }
@@ -97,6 +350,31 @@ pub struct LookupInvoiceResponse {
     pub expiry: String,
     pub amt_paid_sat: String,
     pub state: InvoiceState,
+    #[serde(default)]
+    pub creation_date: String,
+    #[serde(default)]
+    pub add_index: String,
+    #[serde(default)]
+    pub settle_index: String,
+    #[serde(default)]
+    pub value_msat: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListInvoicesResponse {
+    #[serde(default)]
+    pub invoices: Vec<LookupInvoiceResponse>,
+    pub last_index_offset: String,
+    pub first_index_offset: String,
+}
+
+#[derive(Debug)]
+pub struct ListInvoicesLndRequest {
+    pub index_offset: u64,
+    pub num_max_invoices: u64,
+    pub pending_only: bool,
+    pub creation_date_start: Option<u64>,
+    pub creation_date_end: Option<u64>,
 }
 
 impl LookupInvoiceResponse {
@@ -113,7 +391,73 @@ impl LookupInvoiceResponse {
             description_hash: self.description_hash,
             expiry: self.expiry,
             amt_paid_sat: self.amt_paid_sat,
-            state: state,
+            state,
+            creation_date: self.creation_date,
+            add_index: self.add_index,
+            value_msat: self.value_msat,
+        }
+    }
+}
+
+/// A server-streaming LND REST response, delivered as newline delimited
+/// `{"result": ...}` JSON messages.
+pub struct LndStream<T> {
+    response: Response,
+    buf: Vec<u8>,
+    done: bool,
+    _marker: PhantomData<T>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LndStreamMessage<T> {
+    result: Option<T>,
+    error: Option<serde_json::Value>,
+}
+
+impl<T: DeserializeOwned> LndStream<T> {
+    pub fn new(response: Response) -> LndStream<T> {
+        Self {
+            response,
+            buf: Vec::new(),
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the next message, or `None` once LND closes the stream.
+    pub async fn next(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                if line.iter().all(|b| b.is_ascii_whitespace()) {
+                    continue;
+                }
+
+                let message = serde_json::from_slice::<LndStreamMessage<T>>(&line)
+                    .context("Failed to parse streamed JSON from LND API")?;
+
+                if let Some(error) = message.error {
+                    return Err(anyhow::anyhow!("LND stream error: {}", error));
+                }
+
+                match message.result {
+                    Some(result) => return Ok(Some(result)),
+                    None => continue,
+                }
+            }
+
+            if self.done {
+                return Ok(None);
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => {
+                    // flush a trailing message that was not newline terminated
+                    self.done = true;
+                    self.buf.push(b'\n');
+                }
+            }
         }
     }
 }
@@ -130,17 +474,37 @@ pub enum InvoiceState {
     Accepted = 3,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct LndSendPaymentSyncReq {
     pub payment_request: String,
     pub amt: String,
     pub fee_limit: FeeLimit,
     pub allow_self_payment: bool,
+    /// Base64 encoded destination pubkey, for keysend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest: Option<String>,
+    /// Base64 encoded TLV record values keyed by record type.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dest_custom_records: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_hash: Option<String>,
+    /// Channel id the payment must leave through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outgoing_chan_id: Option<String>,
+    /// Base64 encoded pubkey of the peer the payment must arrive from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_hop_pubkey: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// LND accepts exactly one of these limits.
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct FeeLimit {
-    pub fixed: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_msat: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -155,7 +519,12 @@ pub struct LndSendPaymentSyncRes {
 pub struct Route {
     pub total_time_lock: u64,
     pub total_fees: String,
+    #[serde(default)]
+    pub total_fees_msat: String,
     pub total_amt: String,
+    /// The amount sent including fees.
+    #[serde(default)]
+    pub total_amt_msat: String,
     pub hops: Vec<Hop>,
 }
 
@@ -172,10 +541,342 @@ pub struct Hop {
     pub metadata: String,
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QueryRoutesResponse {
+    #[serde(default)]
+    pub routes: Vec<QueriedRoute>,
+    #[serde(default)]
+    pub success_prob: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QueriedRoute {
+    pub total_time_lock: u64,
+    pub total_fees_msat: String,
+    pub total_amt_msat: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EstimateRouteFeeLndRequest {
+    pub payment_request: String,
+    /// Seconds to spend probing before giving up.
+    pub timeout: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EstimateRouteFeeResponse {
+    #[serde(default)]
+    pub routing_fee_msat: String,
+    #[serde(default)]
+    pub time_lock_delay: String,
+    #[serde(default)]
+    pub failure_reason: String,
+}
+
+impl EstimateRouteFeeResponse {
+    /// A successful probe means a route exists, so its success probability
+    /// is reported as 1.
+    pub fn to_cluster(&self, pubkey: &str) -> cluster::ClusterRouteFeeEstimate {
+        let mut estimate = cluster::ClusterRouteFeeEstimate {
+            pubkey: pubkey.to_string(),
+            fee_msat: None,
+            success_prob: 0.0,
+            time_lock: None,
+            error: None,
+        };
+
+        if !self.failure_reason.is_empty() && self.failure_reason != "FAILURE_REASON_NONE" {
+            estimate.error = Some(self.failure_reason.clone());
+            return estimate;
+        }
+
+        match self.routing_fee_msat.parse::<u64>() {
+            Ok(fee_msat) => {
+                estimate.fee_msat = Some(fee_msat);
+                estimate.success_prob = 1.0;
+                estimate.time_lock = self.time_lock_delay.parse::<u64>().ok();
+            }
+            Err(e) => estimate.error = Some(e.to_string()),
+        }
+
+        estimate
+    }
+}
+
+impl FundPsbtResponse {
+    pub fn to_cluster(self, id: String, pubkey: &str) -> Result<cluster::ClusterPsbt> {
+        let leases = self
+            .locked_utxos
+            .into_iter()
+            .map(|lease| {
+                Ok(cluster::ClusterUtxoLease {
+                    id: to_hex(&lease.id)?,
+                    txid: lease.outpoint.txid_str,
+                    output_index: lease.outpoint.output_index as u32,
+                    expiration: lease.expiration.parse()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(cluster::ClusterPsbt {
+            id,
+            pubkey: pubkey.to_string(),
+            psbt: self.funded_psbt,
+            change_output_index: self.change_output_index,
+            leases,
+            state: cluster::ClusterPsbtState::Funded,
+            raw_final_tx: None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListChannelsResponse {
+    #[serde(default)]
+    pub channels: Vec<LndChannel>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LndChannel {
+    #[serde(default)]
+    pub active: bool,
+    pub remote_pubkey: String,
+    pub channel_point: String,
+    pub chan_id: String,
+    pub capacity: String,
+    #[serde(default)]
+    pub local_balance: String,
+    #[serde(default)]
+    pub remote_balance: String,
+    #[serde(default)]
+    pub private: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FeeReportResponse {
+    #[serde(default)]
+    pub channel_fees: Vec<ChannelFeeReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChannelFeeReport {
+    pub channel_point: String,
+    #[serde(default)]
+    pub base_fee_msat: String,
+    #[serde(default)]
+    pub fee_per_mil: String,
+}
+
+impl ListChannelsResponse {
+    /// Channels with the fee policy from `fees` for each channel it covers.
+    pub fn to_cluster(
+        self,
+        pubkey: &str,
+        fees: FeeReportResponse,
+    ) -> Result<Vec<cluster::ClusterChannel>> {
+        let parse = |value: &str| -> Result<i64> {
+            match value {
+                "" => Ok(0),
+                value => Ok(value.parse::<i64>()?),
+            }
+        };
+
+        let mut policies = HashMap::new();
+        for fee in fees.channel_fees {
+            let policy = cluster::ClusterChannelFeePolicy {
+                base_fee_msat: parse(&fee.base_fee_msat)?,
+                fee_ppm: parse(&fee.fee_per_mil)?,
+            };
+            policies.insert(fee.channel_point, policy);
+        }
+
+        self.channels
+            .into_iter()
+            .map(|channel| {
+                Ok(cluster::ClusterChannel {
+                    pubkey: pubkey.to_string(),
+                    peer: channel.remote_pubkey,
+                    chan_id: channel.chan_id,
+                    capacity: parse(&channel.capacity)?,
+                    local_balance: parse(&channel.local_balance)?,
+                    remote_balance: parse(&channel.remote_balance)?,
+                    active: channel.active,
+                    private: channel.private,
+                    fee_policy: policies.remove(&channel.channel_point),
+                    channel_point: channel.channel_point,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListPeersResponse {
+    #[serde(default)]
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Peer {
+    pub pub_key: String,
+    #[serde(default)]
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectPeerLndRequest {
+    pub addr: LightningAddress,
+    pub perm: bool,
+    pub timeout: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LightningAddress {
+    pub pubkey: String,
+    pub host: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OpenChannelLndRequest {
+    /// Base64 encoded.
+    pub node_pubkey: String,
+    pub local_funding_amount: String,
+    pub push_sat: String,
+    pub private: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_conf: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sat_per_vbyte: Option<String>,
+}
+
+/// A streamed update of a channel open or close. Exactly one field is set.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChannelStatusUpdate {
+    pub chan_pending: Option<PendingUpdate>,
+    pub chan_open: Option<ChannelOpenUpdate>,
+    pub close_pending: Option<PendingUpdate>,
+    pub chan_close: Option<ChannelCloseUpdate>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingUpdate {
+    /// Base64 encoded, in internal byte order.
+    pub txid: String,
+    #[serde(default)]
+    pub output_index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChannelOpenUpdate {
+    pub channel_point: ChannelPoint,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChannelPoint {
+    /// Base64 encoded, in internal byte order.
+    pub funding_txid_bytes: String,
+    #[serde(default)]
+    pub output_index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChannelCloseUpdate {
+    /// Base64 encoded, in internal byte order.
+    pub closing_txid: String,
+    #[serde(default)]
+    pub success: bool,
+}
+
+impl LndTransaction {
+    /// Outputs of the transaction paying our `address`, or any of our
+    /// addresses when none is given.
+    pub fn to_cluster_deposits(
+        &self,
+        pubkey: &str,
+        address: Option<&str>,
+    ) -> Vec<cluster::ClusterAddressDeposit> {
+        self.output_details
+            .iter()
+            .filter(|output| {
+                output.is_our_address && address.is_none_or(|address| output.address == address)
+            })
+            .map(|output| cluster::ClusterAddressDeposit {
+                pubkey: pubkey.to_string(),
+                address: output.address.clone(),
+                txid: self.tx_hash.clone(),
+                output_index: output.output_index.parse().unwrap_or_default(),
+                amount: output.amount.parse().unwrap_or_default(),
+                confirmations: self.num_confirmations,
+                block_height: self.block_height,
+            })
+            .collect()
+    }
+}
+
 impl LndSendPaymentSyncRes {
+    /// Parses a `/v1/channels/transactions` response, hex encoding the
+    /// payment hash and preimage.
+    pub fn from_json(json_string: &str) -> Result<LndSendPaymentSyncRes> {
+        let json = serde_json::from_str::<serde_json::Value>(json_string)?;
+
+        let payment_hash = match &json["payment_hash"] {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(s) if s.is_empty() => None,
+            serde_json::Value::String(s) => Some(to_hex(s)?),
+            _ => None,
+        };
+
+        let payment_error = match &json["payment_error"] {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(s) if s.is_empty() => None,
+            serde_json::Value::String(s) => Some(s.clone()),
+            _ => None,
+        };
+
+        let payment_route = match &json["payment_route"] {
+            serde_json::Value::Null => None,
+            _ => {
+                let route = serde_json::from_value::<Route>(json["payment_route"].clone())?;
+                Some(route)
+            }
+        };
+
+        let payment_preimage = match &json["payment_preimage"] {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(s) if s.is_empty() => None,
+            serde_json::Value::String(s) => Some(to_hex(s)?),
+            _ => None,
+        };
+
+        Ok(LndSendPaymentSyncRes {
+            payment_error,
+            payment_preimage,
+            payment_route,
+            payment_hash,
+        })
+    }
+
+    /// A failed payment for a non-2xx response, such as
+    /// `{"code": 2, "message": "invoice expired"}`. LND rejects these
+    /// requests before sending anything.
+    pub fn from_error_body(body: &str) -> LndSendPaymentSyncRes {
+        let message = serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|json| json["message"].as_str().map(String::from))
+            .filter(|message| !message.is_empty())
+            .unwrap_or_else(|| format!("LND rejected the payment: {}", body));
+
+        LndSendPaymentSyncRes {
+            payment_error: Some(message),
+            payment_preimage: None,
+            payment_route: None,
+            payment_hash: None,
+        }
+    }
+
     pub fn to_cluster(self, pubkey: String) -> cluster::ClusterPayPaymentRequestRes {
         cluster::ClusterPayPaymentRequestRes {
-            pubkey: pubkey,
+            pubkey,
             payment_error: self.payment_error,
             payment_preimage: self.payment_preimage,
             payment_route: self.payment_route,
@@ -204,17 +905,75 @@ impl LndClient {
         }
     }
 
-    pub async fn new_address(&self) -> Result<NewAddressResponse> {
-        let url = format!("{}/v1/newaddress", self.host);
-        let response = LndClient::get(&self, &url)
+    /// `address_type` is LND's `AddressType`, e.g. 0 for P2WKH or 4 for
+    /// taproot.
+    pub async fn new_address(&self, address_type: u8) -> Result<NewAddressResponse> {
+        let url = format!("{}/v1/newaddress?type={}", self.host, address_type);
+        let response = LndClient::get(self, &url)
             .await
-            .map_err(|error| anyhow::Error::from(error))
             .context("Failed to make request to LND API")?;
 
         response
             .json::<NewAddressResponse>()
             .await
-            .map_err(|error| anyhow::Error::from(error))
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn get_info(&self) -> Result<GetInfoResponse> {
+        let url = format!("{}/v1/getinfo", self.host);
+        let response = LndClient::get(self, &url).await?;
+        let response = ensure_success(response, "get info").await?;
+
+        response
+            .json::<GetInfoResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    /// Wallet transactions between the two heights. An `end_height` of -1
+    /// includes unconfirmed transactions.
+    pub async fn get_transactions(
+        &self,
+        start_height: i32,
+        end_height: i32,
+    ) -> Result<GetTransactionsResponse> {
+        let url = format!(
+            "{}/v1/transactions?start_height={}&end_height={}",
+            self.host, start_height, end_height
+        );
+        let response = LndClient::get(self, &url).await?;
+        let response = ensure_success(response, "get transactions").await?;
+
+        response
+            .json::<GetTransactionsResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn send_coins(&self, req: SendCoinsLndRequest) -> Result<SendCoinsResponse> {
+        let url = format!("{}/v1/transactions", self.host);
+        let response = LndClient::post(self, &url, &req).await?;
+        let response = ensure_success(response, "send coins").await?;
+
+        response
+            .json::<SendCoinsResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn send_many(&self, req: SendManyLndRequest) -> Result<SendCoinsResponse> {
+        let url = format!("{}/v1/transactions/many", self.host);
+        let response = LndClient::post(self, &url, &req).await?;
+        let response = ensure_success(response, "send many").await?;
+
+        response
+            .json::<SendCoinsResponse>()
+            .await
+            .map_err(anyhow::Error::from)
             .context("Failed to parse JSON response from LND API")
     }
 
@@ -224,80 +983,338 @@ impl LndClient {
             memo: req.memo,
             value: req.value,
             expiry: req.expiry,
+            value_msat: req.value_msat.map(|msat| msat.to_string()),
+            description_hash: req.description_hash.as_deref().map(to_base64).transpose()?,
+            private: req.private,
+            cltv_expiry: req.cltv_expiry.map(|cltv| cltv.to_string()),
+            is_amp: req.is_amp,
+            r_preimage: req.r_preimage.as_deref().map(to_base64).transpose()?,
+            fallback_addr: req.fallback_addr,
         };
-        let response = LndClient::post(&self, &url, &body).await?;
+        let response = LndClient::post(self, &url, &body).await?;
 
         response
             .json::<AddInvoiceResponse>()
             .await
-            .map_err(|error| anyhow::Error::from(error))
+            .map_err(anyhow::Error::from)
             .context("Failed to parse JSON response from LND API")
     }
 
+    /// Creates a hold invoice for a hex encoded payment hash.
+    pub async fn add_hold_invoice(
+        &self,
+        req: ClusterAddHoldInvoice,
+    ) -> Result<AddHoldInvoiceResponse> {
+        let url = format!("{}/v2/invoices/hodl", self.host);
+        let body = AddHoldInvoiceLndRequest::from_cluster(req)?;
+        let response = LndClient::post(self, &url, &body).await?;
+        let response = ensure_success(response, "add hold invoice").await?;
+
+        response
+            .json::<AddHoldInvoiceResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    /// Settles an accepted hold invoice with its hex encoded preimage.
+    pub async fn settle_invoice(&self, preimage: &str) -> Result<()> {
+        let url = format!("{}/v2/invoices/settle", self.host);
+        let body = SettleInvoiceLndRequest {
+            preimage: to_base64(preimage)?,
+        };
+        let response = LndClient::post(self, &url, &body).await?;
+        ensure_success(response, "settle invoice").await?;
+
+        Ok(())
+    }
+
+    /// Cancels an open or accepted invoice by its hex encoded payment hash.
+    pub async fn cancel_invoice(&self, r_hash: &str) -> Result<()> {
+        let url = format!("{}/v2/invoices/cancel", self.host);
+        let body = CancelInvoiceLndRequest {
+            payment_hash: to_base64(r_hash)?,
+        };
+        let response = LndClient::post(self, &url, &body).await?;
+        ensure_success(response, "cancel invoice").await?;
+
+        Ok(())
+    }
+
     pub async fn lookup_invoice(&self, r_hash: &str) -> Result<LookupInvoiceResponse> {
         let url = format!("{}/v1/invoice/{}", self.host, r_hash);
-        let response = LndClient::get(&self, &url).await?;
+        let response = LndClient::get(self, &url).await?;
 
         response
             .json::<LookupInvoiceResponse>()
             .await
-            .map_err(|error| anyhow::Error::from(error))
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn list_invoices(&self, req: ListInvoicesLndRequest) -> Result<ListInvoicesResponse> {
+        let mut url = format!(
+            "{}/v1/invoices?index_offset={}&num_max_invoices={}&pending_only={}",
+            self.host, req.index_offset, req.num_max_invoices, req.pending_only
+        );
+        if let Some(start) = req.creation_date_start {
+            url.push_str(&format!("&creation_date_start={}", start));
+        }
+        if let Some(end) = req.creation_date_end {
+            url.push_str(&format!("&creation_date_end={}", end));
+        }
+        let response = LndClient::get(self, &url).await?;
+
+        response
+            .json::<ListInvoicesResponse>()
+            .await
+            .map_err(anyhow::Error::from)
             .context("Failed to parse JSON response from LND API")
     }
 
+    /// Streams invoice updates, first replaying the invoices added after
+    /// `add_index` and settled after `settle_index` when they are non-zero.
+    pub async fn subscribe_invoices(
+        &self,
+        add_index: u64,
+        settle_index: u64,
+    ) -> Result<LndStream<LookupInvoiceResponse>> {
+        let url = format!(
+            "{}/v1/invoices/subscribe?add_index={}&settle_index={}",
+            self.host, add_index, settle_index
+        );
+        let response = LndClient::get(self, &url).await?;
+
+        Ok(LndStream::new(response))
+    }
+
     pub async fn send_payment_sync(
         &self,
         req: LndSendPaymentSyncReq,
     ) -> Result<LndSendPaymentSyncRes> {
         let url = format!("{}/v1/channels/transactions", self.host);
-        let res = LndClient::post(&self, &url, &req).await.unwrap();
+        let res = LndClient::post(self, &url, &req).await?;
+        let status = res.status();
+        let json_string = res.text().await?;
 
-        let json_string = res.text().await.unwrap();
+        if !status.is_success() {
+            return Ok(LndSendPaymentSyncRes::from_error_body(&json_string));
+        }
 
-        eprintln!("{}", json_string);
+        LndSendPaymentSyncRes::from_json(&json_string)
+    }
 
-        let json = serde_json::from_str::<serde_json::Value>(&json_string).unwrap();
+    /// Finds a route to `pub_key` for `amt_msat` using mission control, so
+    /// the success probability reflects past payment attempts.
+    pub async fn query_routes(&self, pub_key: &str, amt_msat: u64) -> Result<QueryRoutesResponse> {
+        let url = format!(
+            "{}/v1/graph/routes/{}/{}?use_mission_control=true",
+            self.host,
+            pub_key,
+            amt_msat.div_ceil(1000)
+        );
+        let response = LndClient::get(self, &url).await?;
+        let response = ensure_success(response, "query routes").await?;
 
-        let payment_hash = match &json["payment_hash"] {
-            serde_json::Value::Null => None,
-            serde_json::Value::String(s) if s.is_empty() => None,
-            serde_json::Value::String(s) => Some(to_hex(&s)?),
-            _ => None,
-        };
+        response
+            .json::<QueryRoutesResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
 
-        let payment_error = match &json["payment_error"] {
-            serde_json::Value::Null => None,
-            serde_json::Value::String(s) if s.is_empty() => None,
-            serde_json::Value::String(s) => Some(s.clone()),
-            _ => None,
+    /// Estimates the fee of paying `payment_request` by probing with it, so
+    /// the invoice's route hints are taken into account.
+    pub async fn estimate_route_fee(
+        &self,
+        payment_request: &str,
+    ) -> Result<EstimateRouteFeeResponse> {
+        let url = format!("{}/v2/router/route/estimatefee", self.host);
+        let body = EstimateRouteFeeLndRequest {
+            payment_request: payment_request.to_string(),
+            timeout: 60,
         };
+        let response = LndClient::post(self, &url, &body).await?;
+        let response = ensure_success(response, "estimate route fee").await?;
 
-        let payment_route = match &json["payment_route"] {
-            serde_json::Value::Null => None,
-            _ => {
-                let route = serde_json::to_string(&json["payment_route"]).unwrap();
-                let route = serde_json::from_str::<Route>(&route).unwrap();
-                Some(route)
-            }
-        };
+        response
+            .json::<EstimateRouteFeeResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
 
-        let payment_preimage = match &json["payment_preimage"] {
-            serde_json::Value::Null => None,
-            serde_json::Value::String(s) if s.is_empty() => None,
-            serde_json::Value::String(s) => Some(to_hex(&s)?),
-            _ => None,
+    pub async fn channel_balance(&self) -> Result<ChannelBalanceResponse> {
+        let url = format!("{}/v1/balance/channels", self.host);
+        let response = LndClient::get(self, &url).await?;
+
+        response
+            .json::<ChannelBalanceResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn wallet_balance(&self) -> Result<WalletBalanceResponse> {
+        let url = format!("{}/v1/balance/blockchain", self.host);
+        let response = LndClient::get(self, &url).await?;
+        let response = ensure_success(response, "get wallet balance").await?;
+
+        response
+            .json::<WalletBalanceResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn list_channels(&self) -> Result<ListChannelsResponse> {
+        let url = format!("{}/v1/channels", self.host);
+        let response = LndClient::get(self, &url).await?;
+        let response = ensure_success(response, "list channels").await?;
+
+        response
+            .json::<ListChannelsResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn list_peers(&self) -> Result<ListPeersResponse> {
+        let url = format!("{}/v1/peers", self.host);
+        let response = LndClient::get(self, &url).await?;
+        let response = ensure_success(response, "list peers").await?;
+
+        response
+            .json::<ListPeersResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    pub async fn connect_peer(&self, req: ConnectPeerLndRequest) -> Result<()> {
+        let url = format!("{}/v1/peers", self.host);
+        let response = LndClient::post(self, &url, &req).await?;
+        ensure_success(response, "connect peer").await?;
+
+        Ok(())
+    }
+
+    /// Opens a channel, streaming a `chan_pending` update once the funding
+    /// transaction is published and `chan_open` once it confirms.
+    pub async fn open_channel(
+        &self,
+        req: OpenChannelLndRequest,
+    ) -> Result<LndStream<ChannelStatusUpdate>> {
+        let url = format!("{}/v1/channels/stream", self.host);
+        let response = LndClient::post(self, &url, &req).await?;
+        let response = ensure_success(response, "open channel").await?;
+
+        Ok(LndStream::new(response))
+    }
+
+    /// Closes a channel, streaming a `close_pending` update once the
+    /// closing transaction is published and `chan_close` once it confirms.
+    /// A fee can't be set for a force close.
+    pub async fn close_channel(
+        &self,
+        funding_txid: &str,
+        output_index: u32,
+        force: bool,
+        target_conf: Option<i32>,
+        sat_per_vbyte: Option<String>,
+    ) -> Result<LndStream<ChannelStatusUpdate>> {
+        let mut url = format!(
+            "{}/v1/channels/{}/{}?force={}",
+            self.host, funding_txid, output_index, force
+        );
+        if let Some(target_conf) = target_conf {
+            url.push_str(&format!("&target_conf={}", target_conf));
+        }
+        if let Some(sat_per_vbyte) = sat_per_vbyte {
+            url.push_str(&format!("&sat_per_vbyte={}", sat_per_vbyte));
+        }
+        let response = LndClient::delete(self, &url).await?;
+        let response = ensure_success(response, "close channel").await?;
+
+        Ok(LndStream::new(response))
+    }
+
+    /// Forwarding fee policy of every channel.
+    pub async fn fee_report(&self) -> Result<FeeReportResponse> {
+        let url = format!("{}/v1/fees", self.host);
+        let response = LndClient::get(self, &url).await?;
+        let response = ensure_success(response, "get fee report").await?;
+
+        response
+            .json::<FeeReportResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    /// Selects inputs for and leases them to the PSBT.
+    pub async fn fund_psbt(&self, req: FundPsbtLndRequest) -> Result<FundPsbtResponse> {
+        let url = format!("{}/v2/wallet/psbt/fund", self.host);
+        let response = LndClient::post(self, &url, &req).await?;
+        let response = ensure_success(response, "fund psbt").await?;
+
+        response
+            .json::<FundPsbtResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    /// Signs the wallet's inputs and finalizes the PSBT.
+    pub async fn finalize_psbt(&self, psbt: &str) -> Result<FinalizePsbtResponse> {
+        let url = format!("{}/v2/wallet/psbt/finalize", self.host);
+        let req = FinalizePsbtLndRequest {
+            funded_psbt: psbt.to_string(),
         };
+        let response = LndClient::post(self, &url, &req).await?;
+        let response = ensure_success(response, "finalize psbt").await?;
 
-        let res = LndSendPaymentSyncRes {
-            payment_error,
-            payment_preimage,
-            payment_route,
-            payment_hash,
+        response
+            .json::<FinalizePsbtResponse>()
+            .await
+            .map_err(anyhow::Error::from)
+            .context("Failed to parse JSON response from LND API")
+    }
+
+    /// Broadcasts a transaction. Errors leave it unknown whether the
+    /// transaction reached the network, only `PublishOutcome::Rejected`
+    /// means it did not.
+    pub async fn publish_transaction(
+        &self,
+        req: PublishTransactionLndRequest,
+    ) -> Result<PublishOutcome> {
+        let url = format!("{}/v2/wallet/tx", self.host);
+        let response = LndClient::post(self, &url, &req).await?;
+
+        let error = if response.status().is_success() {
+            let res = response
+                .json::<PublishResponse>()
+                .await
+                .map_err(anyhow::Error::from)
+                .context("Failed to parse JSON response from LND API")?;
+            res.publish_error
+        } else {
+            response.text().await?
         };
 
-        eprintln!("{:?}", res);
+        if error.is_empty() {
+            return Ok(PublishOutcome::Published);
+        }
+        classify_publish_error(&error)
+            .ok_or_else(|| anyhow::anyhow!("LND failed to publish transaction: {}", error))
+    }
+
+    pub async fn release_output(&self, req: ReleaseOutputLndRequest) -> Result<()> {
+        let url = format!("{}/v2/wallet/utxos/release", self.host);
+        let response = LndClient::post(self, &url, &req).await?;
+        ensure_success(response, "release output").await?;
 
-        Ok(res)
+        Ok(())
     }
 
     pub async fn list_unspent(&self) -> Result<ListUnspentResponse> {
@@ -309,12 +1326,12 @@ impl LndClient {
             account: None,
             unconfirmed_only: None,
         };
-        let response = LndClient::post(&self, &url, &req).await?;
+        let response = LndClient::post(self, &url, &req).await?;
 
         let json = response
             .json::<ListUnspentResponse>()
             .await
-            .map_err(|error| anyhow::Error::from(error))?;
+            .map_err(anyhow::Error::from)?;
 
         Ok(json)
     }
@@ -378,6 +1395,44 @@ impl LndClient {
 
         Ok(resp)
     }
+
+    async fn delete(&self, url: &str) -> Result<Response> {
+        let mut macaroon_data = Vec::new();
+        let mut macaroon_file = fs::File::open(&self.macaroon_path).unwrap();
+        macaroon_file.read_to_end(&mut macaroon_data).unwrap();
+        let macaroon_hex = hex::encode(macaroon_data);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Grpc-Metadata-macaroon",
+            HeaderValue::from_str(&macaroon_hex).unwrap(),
+        );
+
+        let mut buf = Vec::new();
+        fs::File::open(&self.cert_path)
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        let cert = reqwest::Certificate::from_pem(&buf).unwrap();
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .add_root_certificate(cert)
+            .build()
+            .unwrap();
+
+        let resp = client.delete(url).send().await?;
+
+        Ok(resp)
+    }
+}
+
+/// Hex txid, as displayed, from base64 encoded bytes in internal byte order.
+pub fn txid_from_bytes(str: &str) -> Result<String> {
+    let mut decoded_bytes = base64::decode(str)?;
+    decoded_bytes.reverse();
+
+    Ok(hex::encode(decoded_bytes))
 }
 
 pub fn to_hex(str: &str) -> Result<String> {
@@ -387,9 +1442,80 @@ pub fn to_hex(str: &str) -> Result<String> {
     Ok(hex_string)
 }
 
+pub fn to_base64(str: &str) -> Result<String> {
+    let decoded_bytes = hex::decode(str)?;
+    let base64_string = base64::encode(decoded_bytes);
+
+    Ok(base64_string)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublishOutcome {
+    /// Broadcast, possibly by an earlier attempt.
+    Published,
+    /// The node or its backend refused the transaction.
+    Rejected(String),
+}
+
+/// Sorts publish errors that tell for certain whether the transaction is
+/// on the network. Anything else returns `None`.
+pub fn classify_publish_error(error: &str) -> Option<PublishOutcome> {
+    const ALREADY_PUBLISHED: [&str; 5] = [
+        "already in mempool",
+        "txn-already-in-mempool",
+        "txn-already-known",
+        "transaction already exists",
+        "already have transaction",
+    ];
+    const REJECTED: [&str; 9] = [
+        "min relay fee not met",
+        "mempool min fee not met",
+        "insufficient fee",
+        "bad-txns",
+        "mandatory-script-verify-flag",
+        "dust",
+        "missing inputs",
+        "missingorspent",
+        "txn-mempool-conflict",
+    ];
+
+    let lower = error.to_lowercase();
+    if ALREADY_PUBLISHED
+        .iter()
+        .any(|pattern| lower.contains(pattern))
+    {
+        Some(PublishOutcome::Published)
+    } else if REJECTED.iter().any(|pattern| lower.contains(pattern)) {
+        Some(PublishOutcome::Rejected(error.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Turns a non-2xx LND response into an error carrying the response body.
+async fn ensure_success(response: Response, action: &str) -> Result<Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(anyhow::anyhow!(
+        "LND failed to {} ({}): {}",
+        action,
+        status,
+        body
+    ))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::lnd::{FeeLimit, LndClient, LndSendPaymentSyncReq};
+    use crate::cluster::{ClusterAddHoldInvoice, ClusterAddressType};
+    use crate::lnd::{
+        classify_publish_error, txid_from_bytes, AddHoldInvoiceLndRequest, ChannelStatusUpdate,
+        EstimateRouteFeeResponse, FeeLimit, FundPsbtResponse, LndClient, LndSendPaymentSyncReq,
+        LndSendPaymentSyncRes, LndTransaction, PublishOutcome, Utxo,
+    };
 
     #[tokio::test]
     async fn test_send_payment_sync() {
@@ -403,16 +1529,184 @@ mod tests {
         let payment_request = String::from("lntb10u1pjv4fjnpp5vnx7xwnqmaceg3kkeayhq7yk4zp7ppdvakdfuxj959k7d3s5gzmqdqqcqzzsxqr23ssp5vjnsq8jy5fw8ynq842ta8lppf4esh72m4mn79z46jxf93ncw7gus9qyyssqterg9uuet8uzqt63ehwha5pdv2ted8r2f8u4s35lg5yedrfutvkqjfxyf76zaskmycn9m05vnjy6ctytluxn639u2qdtydzzzn09r4qpv6uahm");
 
         let payment_req = LndSendPaymentSyncReq {
-            payment_request: payment_request,
+            payment_request,
             amt: String::from("1000"),
             fee_limit: FeeLimit {
-                fixed: 10.to_string(),
+                fixed: Some(10.to_string()),
+                ..Default::default()
             },
             allow_self_payment: true,
+            ..Default::default()
         };
 
         let payment = client.send_payment_sync(payment_req).await;
 
         eprintln!("{:?}", payment);
     }
+
+    #[test]
+    fn test_transaction_deposits() {
+        let tx: LndTransaction = serde_json::from_str(
+            r#"{
+                "tx_hash": "ab01",
+                "amount": "5000",
+                "num_confirmations": 3,
+                "block_height": 800000,
+                "time_stamp": "1700000000",
+                "output_details": [
+                    {"address": "bc1qours", "output_index": "1", "amount": "5000", "is_our_address": true},
+                    {"address": "bc1qtheirs", "output_index": "0", "amount": "9000", "is_our_address": false}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let deposits = tx.to_cluster_deposits("02ab", Some("bc1qours"));
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits[0].output_index, 1);
+        assert_eq!(deposits[0].amount, 5000);
+        assert_eq!(deposits[0].confirmations, 3);
+        assert!(tx
+            .to_cluster_deposits("02ab", Some("bc1qtheirs"))
+            .is_empty());
+        assert_eq!(tx.to_cluster_deposits("02ab", None).len(), 1);
+    }
+
+    #[test]
+    fn test_utxo_to_cluster() {
+        let utxo: Utxo = serde_json::from_str(
+            r#"{
+                "address_type": "TAPROOT_PUBKEY",
+                "address": "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                "amount_sat": "25000",
+                "pk_script": "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                "outpoint": {"txid_bytes": "", "txid_str": "ab01", "output_index": 2},
+                "confirmations": "6"
+            }"#,
+        )
+        .unwrap();
+
+        let utxo = utxo.to_cluster("02ab".to_string()).unwrap();
+        assert_eq!(utxo.outpoint(), "ab01:2");
+        assert_eq!(utxo.amount, 25000);
+        assert_eq!(utxo.address_type, Some(ClusterAddressType::P2tr));
+    }
+
+    #[test]
+    fn test_send_payment_sync_error_body() {
+        let res = LndSendPaymentSyncRes::from_error_body(
+            r#"{"code": 2, "message": "invoice expired", "details": []}"#,
+        );
+        assert_eq!(res.payment_error.as_deref(), Some("invoice expired"));
+        assert!(res.payment_preimage.is_none() && res.payment_route.is_none());
+
+        let res = LndSendPaymentSyncRes::from_error_body("Bad Gateway");
+        assert_eq!(
+            res.payment_error.as_deref(),
+            Some("LND rejected the payment: Bad Gateway")
+        );
+    }
+
+    #[test]
+    fn test_fund_psbt_to_cluster() {
+        let res: FundPsbtResponse = serde_json::from_str(
+            r#"{
+                "funded_psbt": "cHNidP8B",
+                "change_output_index": 1,
+                "locked_utxos": [{
+                    "id": "AQI=",
+                    "outpoint": {"txid_bytes": "AasB", "txid_str": "ab01", "output_index": 2},
+                    "expiration": "1700000600"
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let psbt = res.to_cluster("id1".to_string(), "02ab").unwrap();
+        assert_eq!(psbt.psbt, "cHNidP8B");
+        assert_eq!(psbt.change_output_index, 1);
+        assert_eq!(psbt.leases[0].id, "0102");
+        assert_eq!(psbt.leases[0].output_index, 2);
+        assert_eq!(psbt.leases[0].expiration, 1_700_000_600);
+        assert!(psbt.leases_expired(1_700_000_600));
+        assert!(!psbt.leases_expired(1_700_000_000));
+    }
+
+    #[test]
+    fn test_channel_status_update() {
+        let update: ChannelStatusUpdate =
+            serde_json::from_str(r#"{"chan_pending": {"txid": "AQID", "output_index": 1}}"#)
+                .unwrap();
+        assert!(update.chan_open.is_none());
+
+        let pending = update.chan_pending.unwrap();
+        assert_eq!(txid_from_bytes(&pending.txid).unwrap(), "030201");
+        assert_eq!(pending.output_index, 1);
+    }
+
+    #[test]
+    fn test_classify_publish_error() {
+        assert_eq!(
+            classify_publish_error("transaction already exists"),
+            Some(PublishOutcome::Published)
+        );
+        assert_eq!(
+            classify_publish_error("-26: min relay fee not met, 110 < 141"),
+            Some(PublishOutcome::Rejected(
+                "-26: min relay fee not met, 110 < 141".to_string()
+            ))
+        );
+        assert_eq!(classify_publish_error("context deadline exceeded"), None);
+    }
+
+    #[test]
+    fn test_estimate_route_fee_to_cluster() {
+        let res: EstimateRouteFeeResponse = serde_json::from_str(
+            r#"{"routing_fee_msat": "1500", "time_lock_delay": "120", "failure_reason": "FAILURE_REASON_NONE"}"#,
+        )
+        .unwrap();
+        let estimate = res.to_cluster("02ab");
+        assert!(estimate.reachable());
+        assert_eq!(estimate.fee_msat, Some(1500));
+        assert_eq!(estimate.time_lock, Some(120));
+
+        let res: EstimateRouteFeeResponse = serde_json::from_str(
+            r#"{"routing_fee_msat": "0", "time_lock_delay": "0", "failure_reason": "FAILURE_REASON_NO_ROUTE"}"#,
+        )
+        .unwrap();
+        let estimate = res.to_cluster("02ab");
+        assert!(!estimate.reachable());
+        assert_eq!(estimate.error.as_deref(), Some("FAILURE_REASON_NO_ROUTE"));
+    }
+
+    #[test]
+    fn test_add_hold_invoice_request() {
+        let req = ClusterAddHoldInvoice {
+            hash: "ab".repeat(32),
+            value_msat: Some(1500),
+            description_hash: Some("cd".repeat(32)),
+            cltv_expiry: Some(144),
+            private: true,
+            ..Default::default()
+        };
+        let body =
+            serde_json::to_value(AddHoldInvoiceLndRequest::from_cluster(req).unwrap()).unwrap();
+        assert_eq!(body["hash"], base64::encode([0xab; 32]));
+        assert_eq!(body["value_msat"], "1500");
+        assert_eq!(body["description_hash"], base64::encode([0xcd; 32]));
+        assert_eq!(body["cltv_expiry"], "144");
+        assert_eq!(body["private"], true);
+
+        let req = ClusterAddHoldInvoice {
+            hash: "ab".repeat(32),
+            value: 10,
+            ..Default::default()
+        };
+        let body =
+            serde_json::to_value(AddHoldInvoiceLndRequest::from_cluster(req).unwrap()).unwrap();
+        assert_eq!(body["value"], 10);
+        assert!(body.get("value_msat").is_none());
+        assert!(body.get("description_hash").is_none());
+        assert!(body.get("cltv_expiry").is_none());
+    }
 }
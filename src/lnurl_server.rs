@@ -0,0 +1,275 @@
+use crate::cluster::{Cluster, ClusterAddInvoice};
+use crate::lnurl::{metadata_hash, LnurlPayInvoice, LnurlPayParams, LnurlStatus};
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// A Lightning Address hosted by `LnurlPayServer`.
+#[derive(Debug, Clone)]
+pub struct LnurlPayUser {
+    pub username: String,
+    pub description: String,
+    pub min_sendable: u64,
+    pub max_sendable: u64,
+    pub comment_allowed: usize,
+    /// Pin the user's invoices to one node instead of load balancing.
+    pub pubkey: Option<String>,
+}
+
+/// Serves LNURL-pay responses for hosted Lightning Addresses, creating the
+/// invoices through `Cluster::add_invoice`. Responses are returned as JSON
+/// so any HTTP framework can expose them.
+pub struct LnurlPayServer {
+    pub domain: String,
+    /// Public base URL the callbacks are served from, e.g. `https://example.com`.
+    pub base_url: String,
+    pub invoice_expiry: i64,
+    pub users: HashMap<String, LnurlPayUser>,
+}
+
+/// The two LNURL-pay routes served for each user.
+#[derive(Debug, PartialEq)]
+pub enum LnurlPayRoute {
+    /// `/.well-known/lnurlp/<user>`
+    Params(String),
+    /// `/lnurlp/<user>/callback?amount=<msat>&comment=<text>`
+    Callback {
+        username: String,
+        amount_msat: u64,
+        comment: Option<String>,
+    },
+}
+
+impl LnurlPayServer {
+    pub fn new(domain: String, base_url: String) -> LnurlPayServer {
+        Self {
+            domain,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            invoice_expiry: 600,
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn add_user(&mut self, user: LnurlPayUser) {
+        self.users.insert(user.username.to_lowercase(), user);
+    }
+
+    /// The metadata string whose hash is committed to by every invoice.
+    pub fn metadata(&self, user: &LnurlPayUser) -> String {
+        let identifier = format!("{}@{}", user.username, self.domain);
+        serde_json::json!([
+            ["text/plain", user.description],
+            ["text/identifier", identifier],
+        ])
+        .to_string()
+    }
+
+    pub fn params(&self, username: &str) -> Result<LnurlPayParams> {
+        let user = self.user(username)?;
+
+        Ok(LnurlPayParams {
+            callback: format!("{}/lnurlp/{}/callback", self.base_url, user.username),
+            min_sendable: user.min_sendable,
+            max_sendable: user.max_sendable,
+            metadata: self.metadata(user),
+            tag: "payRequest".to_string(),
+            comment_allowed: Some(user.comment_allowed),
+        })
+    }
+
+    /// Creates a `description_hash` invoice for the user on the cluster.
+    /// The payer's comment is not part of the invoice, it is stored for
+    /// `Cluster::invoice_comment` instead.
+    pub async fn callback(
+        &self,
+        cluster: &mut Cluster,
+        username: &str,
+        amount_msat: u64,
+        comment: Option<&str>,
+    ) -> Result<LnurlPayInvoice> {
+        let user = self.user(username)?;
+        let req = self.invoice_request(username, amount_msat, comment)?;
+        let invoice = cluster.add_invoice(req, user.pubkey.clone()).await?;
+
+        if let Some(comment) = comment.filter(|comment| !comment.is_empty()) {
+            // the invoice exists already, losing the comment must not fail
+            // the request
+            let _ = cluster
+                .set_invoice_comment(&invoice.r_hash, comment, self.invoice_expiry)
+                .await;
+        }
+
+        Ok(LnurlPayInvoice {
+            pr: invoice.payment_request,
+            routes: vec![],
+        })
+    }
+
+    /// The invoice `callback` creates, after checking the amount and the
+    /// comment against the user's limits.
+    pub fn invoice_request(
+        &self,
+        username: &str,
+        amount_msat: u64,
+        comment: Option<&str>,
+    ) -> Result<ClusterAddInvoice> {
+        let user = self.user(username)?;
+
+        if amount_msat < user.min_sendable || amount_msat > user.max_sendable {
+            return Err(anyhow::anyhow!(
+                "Amount must be between {} and {} msat",
+                user.min_sendable,
+                user.max_sendable
+            ));
+        }
+
+        let comment = comment.unwrap_or_default();
+        if comment.chars().count() > user.comment_allowed {
+            return Err(anyhow::anyhow!(
+                "Comment can be at most {} characters",
+                user.comment_allowed
+            ));
+        }
+
+        Ok(ClusterAddInvoice {
+            pubkey: user.pubkey.clone(),
+            value_msat: Some(amount_msat as i64),
+            expiry: self.invoice_expiry,
+            description_hash: Some(metadata_hash(&self.metadata(user))),
+            ..Default::default()
+        })
+    }
+
+    /// Answers a request for `path_and_query`, reporting failures as LNURL
+    /// `ERROR` responses.
+    pub async fn handle(&self, cluster: &mut Cluster, path_and_query: &str) -> serde_json::Value {
+        let result = match parse_route(path_and_query) {
+            Ok(LnurlPayRoute::Params(username)) => self
+                .params(&username)
+                .map(|params| serde_json::to_value(params).unwrap()),
+            Ok(LnurlPayRoute::Callback {
+                username,
+                amount_msat,
+                comment,
+            }) => self
+                .callback(cluster, &username, amount_msat, comment.as_deref())
+                .await
+                .map(|invoice| serde_json::to_value(invoice).unwrap()),
+            Err(e) => Err(e),
+        };
+
+        result.unwrap_or_else(|e| serde_json::to_value(LnurlStatus::error(&e.to_string())).unwrap())
+    }
+
+    fn user(&self, username: &str) -> Result<&LnurlPayUser> {
+        self.users
+            .get(&username.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("Unknown user {}", username))
+    }
+}
+
+pub fn parse_route(path_and_query: &str) -> Result<LnurlPayRoute> {
+    let url = reqwest::Url::parse("http://localhost")?.join(path_and_query)?;
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+
+    match segments.as_slice() {
+        [".well-known", "lnurlp", username] => Ok(LnurlPayRoute::Params(username.to_string())),
+        ["lnurlp", username, "callback"] => {
+            let mut amount_msat = None;
+            let mut comment = None;
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "amount" => amount_msat = Some(value.parse::<u64>()?),
+                    "comment" => comment = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+
+            Ok(LnurlPayRoute::Callback {
+                username: username.to_string(),
+                amount_msat: amount_msat.ok_or_else(|| anyhow::anyhow!("Missing amount"))?,
+                comment,
+            })
+        }
+        _ => Err(anyhow::anyhow!("Not found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_route, LnurlPayRoute, LnurlPayServer, LnurlPayUser};
+    use crate::lnurl::metadata_hash;
+
+    #[test]
+    fn test_params_and_routes() {
+        let mut server = LnurlPayServer::new(
+            String::from("example.com"),
+            String::from("https://example.com/"),
+        );
+        server.add_user(LnurlPayUser {
+            username: String::from("Alice"),
+            description: String::from("Pay Alice"),
+            min_sendable: 1000,
+            max_sendable: 1_000_000,
+            comment_allowed: 140,
+            pubkey: None,
+        });
+
+        let params = server.params("alice").unwrap();
+        assert_eq!(params.callback, "https://example.com/lnurlp/Alice/callback");
+        assert_eq!(
+            params.metadata,
+            r#"[["text/plain","Pay Alice"],["text/identifier","Alice@example.com"]]"#
+        );
+        assert!(server.params("bob").is_err());
+
+        assert_eq!(
+            parse_route("/.well-known/lnurlp/alice").unwrap(),
+            LnurlPayRoute::Params(String::from("alice"))
+        );
+        assert_eq!(
+            parse_route("/lnurlp/alice/callback?amount=2000&comment=hi%20there").unwrap(),
+            LnurlPayRoute::Callback {
+                username: String::from("alice"),
+                amount_msat: 2000,
+                comment: Some(String::from("hi there")),
+            }
+        );
+        assert!(parse_route("/lnurlp/alice/callback").is_err());
+    }
+
+    #[test]
+    fn test_invoice_request() {
+        let mut server = LnurlPayServer::new(
+            String::from("example.com"),
+            String::from("https://example.com"),
+        );
+        server.add_user(LnurlPayUser {
+            username: String::from("alice"),
+            description: String::from("Pay Alice"),
+            min_sendable: 1000,
+            max_sendable: 1_000_000,
+            comment_allowed: 5,
+            pubkey: Some(String::from("02ab")),
+        });
+
+        let req = server
+            .invoice_request("alice", 2000, Some("hello"))
+            .unwrap();
+        assert!(req.memo.is_empty());
+        assert_eq!(
+            req.description_hash,
+            Some(metadata_hash(&server.metadata(&server.users["alice"])))
+        );
+        assert_eq!(req.value_msat, Some(2000));
+        assert_eq!(req.pubkey.as_deref(), Some("02ab"));
+        assert!(req.validate().is_ok());
+
+        assert!(server
+            .invoice_request("alice", 2000, Some("hello!"))
+            .is_err());
+        assert!(server.invoice_request("alice", 500, None).is_err());
+    }
+}
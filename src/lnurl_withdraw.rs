@@ -0,0 +1,358 @@
+use crate::cluster::{Cluster, ClusterPayPaymentRequestRes, PaymentNotSent};
+use crate::fees::FeePolicy;
+use crate::lnurl::{encode_lnurl, LnurlStatus};
+use anyhow::Result;
+use lightning_invoice::Bolt11Invoice;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// First response of an LNURL-withdraw endpoint (LUD-03).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LnurlWithdrawParams {
+    pub tag: String,
+    pub callback: String,
+    pub k1: String,
+    pub default_description: String,
+    pub min_withdrawable: u64,
+    pub max_withdrawable: u64,
+}
+
+/// A one-time withdraw link, stored in the cache under its `k1` secret
+/// until it is redeemed or expires.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LnurlWithdrawLink {
+    pub k1: String,
+    pub description: String,
+    pub min_withdrawable: u64,
+    pub max_withdrawable: u64,
+    /// Overrides the cluster's default fee policy.
+    pub fee_policy: Option<FeePolicy>,
+    /// Pay from this node instead of a random one.
+    pub pubkey: Option<String>,
+    /// Budget in `Cluster::spend_limits` the withdrawal counts against.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub expires_at: u64,
+}
+
+/// Options for `LnurlWithdrawService::create`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LnurlWithdrawRequest {
+    pub description: String,
+    pub min_withdrawable: u64,
+    pub max_withdrawable: u64,
+    pub fee_policy: Option<FeePolicy>,
+    pub expiry_sec: u64,
+    pub pubkey: Option<String>,
+    pub tenant: Option<String>,
+}
+
+/// The two LNURL-withdraw routes served for each link.
+#[derive(Debug, PartialEq)]
+pub enum LnurlWithdrawRoute {
+    /// `/lnurlw/<k1>`
+    Params(String),
+    /// `/lnurlw/callback?k1=<k1>&pr=<invoice>`
+    Callback { k1: String, pr: String },
+}
+
+/// Issues LNURL-withdraw links and pays the submitted invoices through
+/// `Cluster::pay_invoice`.
+pub struct LnurlWithdrawService {
+    /// Public base URL the links are served from, e.g. `https://example.com`.
+    pub base_url: String,
+}
+
+impl LnurlWithdrawService {
+    pub fn new(base_url: String) -> LnurlWithdrawService {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Creates a link and returns it with its bech32 `LNURL...` encoding.
+    pub async fn create(
+        &self,
+        cluster: &mut Cluster,
+        req: LnurlWithdrawRequest,
+    ) -> Result<(LnurlWithdrawLink, String)> {
+        if req.min_withdrawable > req.max_withdrawable {
+            return Err(anyhow::anyhow!("min_withdrawable exceeds max_withdrawable"));
+        }
+
+        let k1 = hex::encode(rand::random::<[u8; 32]>());
+        let link = LnurlWithdrawLink {
+            k1: k1.clone(),
+            description: req.description,
+            min_withdrawable: req.min_withdrawable,
+            max_withdrawable: req.max_withdrawable,
+            fee_policy: req.fee_policy,
+            pubkey: req.pubkey,
+            tenant: req.tenant,
+            expires_at: now() + req.expiry_sec,
+        };
+
+        let json_link = serde_json::to_string(&link)?;
+        let _: () = cluster
+            .cache
+            .set_ex(withdraw_key(&k1), json_link, req.expiry_sec as usize)
+            .await?;
+
+        let lnurl = encode_lnurl(&format!("{}/lnurlw/{}", self.base_url, k1))?;
+        Ok((link, lnurl))
+    }
+
+    pub async fn params(&self, cluster: &mut Cluster, k1: &str) -> Result<LnurlWithdrawParams> {
+        let json_link: Option<String> = cluster.cache.get(withdraw_key(k1)).await?;
+        let link: LnurlWithdrawLink = match json_link {
+            Some(json_link) => serde_json::from_str(&json_link)?,
+            None => return Err(anyhow::anyhow!("Withdraw link is used or expired")),
+        };
+
+        Ok(LnurlWithdrawParams {
+            tag: "withdrawRequest".to_string(),
+            callback: format!("{}/lnurlw/callback", self.base_url),
+            k1: link.k1,
+            default_description: link.description,
+            min_withdrawable: link.min_withdrawable,
+            max_withdrawable: link.max_withdrawable,
+        })
+    }
+
+    /// Redeems the link by paying `pr`. The link is removed from the cache
+    /// with `GETDEL` before paying, so concurrent callbacks can not both
+    /// redeem it. It is restored only when nothing was sent or the payment
+    /// definitely failed, as a payment in flight may still settle.
+    pub async fn callback(
+        &self,
+        cluster: &mut Cluster,
+        k1: &str,
+        pr: &str,
+    ) -> Result<ClusterPayPaymentRequestRes> {
+        let invoice = pr
+            .parse::<Bolt11Invoice>()
+            .map_err(|e| anyhow::anyhow!("Invalid invoice: {}", e))?;
+        let amount_msat = invoice
+            .amount_milli_satoshis()
+            .ok_or_else(|| anyhow::anyhow!("Invoice must have an amount"))?;
+
+        let json_link: Option<String> = cluster.cache.get_del(withdraw_key(k1)).await?;
+        let link: LnurlWithdrawLink = match json_link {
+            Some(json_link) => serde_json::from_str(&json_link)?,
+            None => return Err(anyhow::anyhow!("Withdraw link is used or expired")),
+        };
+
+        if amount_msat < link.min_withdrawable || amount_msat > link.max_withdrawable {
+            self.restore(cluster, &link).await;
+            return Err(anyhow::anyhow!(
+                "Amount must be between {} and {} msat",
+                link.min_withdrawable,
+                link.max_withdrawable
+            ));
+        }
+
+        let payment = cluster
+            .pay_invoice(
+                0,
+                pr.to_string(),
+                link.fee_policy.clone(),
+                link.pubkey.clone(),
+                link.tenant.clone(),
+            )
+            .await;
+
+        let payment = match payment {
+            Ok(payment) => payment,
+            Err(e) => {
+                if PaymentNotSent::is(&e) {
+                    self.restore(cluster, &link).await;
+                }
+                return Err(e);
+            }
+        };
+
+        if cluster.payment_failed(&payment) {
+            self.restore(cluster, &link).await;
+        }
+
+        Ok(payment)
+    }
+
+    /// Answers a request for `path_and_query`, reporting failures as LNURL
+    /// `ERROR` responses.
+    pub async fn handle(&self, cluster: &mut Cluster, path_and_query: &str) -> serde_json::Value {
+        let result = match parse_route(path_and_query) {
+            Ok(LnurlWithdrawRoute::Params(k1)) => self
+                .params(cluster, &k1)
+                .await
+                .map(|params| serde_json::to_value(params).unwrap()),
+            Ok(LnurlWithdrawRoute::Callback { k1, pr }) => {
+                match self.callback(cluster, &k1, &pr).await {
+                    Ok(payment) => match (payment.payment_error, payment.payment_preimage) {
+                        (Some(error), _) => {
+                            Ok(serde_json::to_value(LnurlStatus::error(&error)).unwrap())
+                        }
+                        (None, Some(_)) => Ok(serde_json::to_value(LnurlStatus::ok()).unwrap()),
+                        (None, None) => Ok(serde_json::to_value(LnurlStatus::error(
+                            "Payment is pending",
+                        ))
+                        .unwrap()),
+                    },
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        result.unwrap_or_else(|e| serde_json::to_value(LnurlStatus::error(&e.to_string())).unwrap())
+    }
+
+    /// Puts the link back for another attempt. A failure is only logged,
+    /// so it never hides why the withdrawal failed.
+    async fn restore(&self, cluster: &mut Cluster, link: &LnurlWithdrawLink) {
+        let remaining = link.expires_at.saturating_sub(now());
+        if remaining == 0 {
+            return;
+        }
+
+        let json_link = serde_json::to_string(link).unwrap();
+        let result: redis::RedisResult<()> = cluster
+            .cache
+            .set_ex(withdraw_key(&link.k1), json_link, remaining as usize)
+            .await;
+        if let Err(e) = result {
+            eprintln!("failed to restore withdraw link {}: {}", link.k1, e);
+        }
+    }
+}
+
+pub fn parse_route(path_and_query: &str) -> Result<LnurlWithdrawRoute> {
+    let url = reqwest::Url::parse("http://localhost")?.join(path_and_query)?;
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+
+    match segments.as_slice() {
+        ["lnurlw", "callback"] => {
+            let query = |name: &str| {
+                url.query_pairs()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.to_string())
+                    .ok_or_else(|| anyhow::anyhow!("Missing {}", name))
+            };
+
+            Ok(LnurlWithdrawRoute::Callback {
+                k1: query("k1")?,
+                pr: query("pr")?,
+            })
+        }
+        ["lnurlw", k1] => Ok(LnurlWithdrawRoute::Params(k1.to_string())),
+        _ => Err(anyhow::anyhow!("Not found")),
+    }
+}
+
+fn withdraw_key(k1: &str) -> String {
+    format!("lnurlw:{}", k1)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_route, LnurlWithdrawRequest, LnurlWithdrawRoute, LnurlWithdrawService};
+    use crate::cln::ClnClient;
+    use crate::cluster::{Node, NodeClient, NodeLightningImpl, NodeNetwork, PaymentNotSent};
+    use crate::testing::{test_cluster, test_invoice};
+
+    fn request() -> LnurlWithdrawRequest {
+        LnurlWithdrawRequest {
+            description: String::from("test"),
+            min_withdrawable: 1000,
+            max_withdrawable: 10_000,
+            expiry_sec: 600,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(
+            parse_route("/lnurlw/abcd").unwrap(),
+            LnurlWithdrawRoute::Params(String::from("abcd"))
+        );
+        assert_eq!(
+            parse_route("/lnurlw/callback?k1=abcd&pr=lnbc1").unwrap(),
+            LnurlWithdrawRoute::Callback {
+                k1: String::from("abcd"),
+                pr: String::from("lnbc1"),
+            }
+        );
+        assert!(parse_route("/lnurlw/callback?k1=abcd").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_callback_consumes_link() {
+        // a node the payment is handed to but never reaches, so its outcome
+        // is unknown
+        let node = Node::new(
+            "02cc".to_string(),
+            "127.0.0.1".to_string(),
+            "3010".to_string(),
+            NodeNetwork::Testnet,
+            NodeLightningImpl::CLightning,
+            NodeClient::CLightning(ClnClient::new(
+                "https://127.0.0.1:1".to_string(),
+                "/nonexistent/ca.pem".to_string(),
+                String::new(),
+            )),
+        );
+        let mut cluster = test_cluster(vec![node]).await;
+        let service = LnurlWithdrawService::new(String::from("https://example.com/"));
+        let (link, _) = service.create(&mut cluster, request()).await.unwrap();
+
+        let err = service
+            .callback(&mut cluster, &link.k1, &test_invoice(Some(20_000)))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Amount must be between 1000 and 10000 msat"
+        );
+        assert!(service.params(&mut cluster, &link.k1).await.is_ok());
+
+        let err = service
+            .callback(&mut cluster, &link.k1, &test_invoice(Some(5000)))
+            .await
+            .unwrap_err();
+        assert!(!PaymentNotSent::is(&err));
+
+        let err = service
+            .callback(&mut cluster, &link.k1, &test_invoice(Some(5000)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Withdraw link is used or expired");
+    }
+
+    #[tokio::test]
+    async fn test_callback_restores_unsent_payment() {
+        // no node can pay, so nothing is sent
+        let mut cluster = test_cluster(vec![]).await;
+        let service = LnurlWithdrawService::new(String::from("https://example.com"));
+        let (link, _) = service.create(&mut cluster, request()).await.unwrap();
+
+        let err = service
+            .callback(&mut cluster, &link.k1, &test_invoice(Some(5000)))
+            .await
+            .unwrap_err();
+        assert!(PaymentNotSent::is(&err));
+        assert!(service.params(&mut cluster, &link.k1).await.is_ok());
+    }
+}
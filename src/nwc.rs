@@ -0,0 +1,1006 @@
+use crate::cluster::{
+    Cluster, ClusterAddInvoice, ClusterInvoiceState, ClusterLookupInvoice, PaymentNotSent,
+};
+use crate::fees::FeePolicy;
+use crate::lnd::Route;
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use lightning_invoice::Bolt11Invoice;
+use redis::AsyncCommands;
+use secp256k1::schnorr::Signature;
+use secp256k1::{ecdh, Keypair, Message, Parity, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub const INFO_KIND: u64 = 13194;
+pub const REQUEST_KIND: u64 = 23194;
+pub const RESPONSE_KIND: u64 = 23195;
+
+const SUPPORTED_METHODS: [&str; 4] = [
+    "pay_invoice",
+    "make_invoice",
+    "lookup_invoice",
+    "get_balance",
+];
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// A nostr keypair. `pubkey` is the hex encoded x-only public key.
+#[derive(Clone)]
+pub struct NostrKeys {
+    keypair: Keypair,
+    pub pubkey: String,
+}
+
+impl NostrKeys {
+    pub fn generate() -> NostrKeys {
+        loop {
+            if let Ok(secret_key) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+                return NostrKeys::from_secret_key(secret_key);
+            }
+        }
+    }
+
+    pub fn from_secret_hex(secret: &str) -> Result<NostrKeys> {
+        let secret_key = SecretKey::from_slice(&hex::decode(secret)?)?;
+        Ok(NostrKeys::from_secret_key(secret_key))
+    }
+
+    fn from_secret_key(secret_key: SecretKey) -> NostrKeys {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &secret_key);
+        let (xonly, _) = keypair.x_only_public_key();
+
+        Self {
+            keypair,
+            pubkey: xonly.to_string(),
+        }
+    }
+
+    pub fn secret_hex(&self) -> String {
+        hex::encode(self.keypair.secret_bytes())
+    }
+}
+
+/// A signed nostr event (NIP-01).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NostrEvent {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u64,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+impl NostrEvent {
+    pub fn new(keys: &NostrKeys, kind: u64, tags: Vec<Vec<String>>, content: String) -> NostrEvent {
+        let created_at = now();
+        let id = event_id(&keys.pubkey, created_at, kind, &tags, &content);
+
+        let secp = Secp256k1::new();
+        let sig = secp.sign_schnorr_no_aux_rand(&Message::from_digest(id), &keys.keypair);
+
+        Self {
+            id: hex::encode(id),
+            pubkey: keys.pubkey.clone(),
+            created_at,
+            kind,
+            tags,
+            content,
+            sig: sig.to_string(),
+        }
+    }
+
+    /// Checks the event id and signature.
+    pub fn verify(&self) -> Result<()> {
+        let id = event_id(
+            &self.pubkey,
+            self.created_at,
+            self.kind,
+            &self.tags,
+            &self.content,
+        );
+        if hex::encode(id) != self.id {
+            return Err(anyhow::anyhow!("Event id does not match its content"));
+        }
+
+        let secp = Secp256k1::verification_only();
+        let pubkey = XOnlyPublicKey::from_str(&self.pubkey)?;
+        let sig = Signature::from_str(&self.sig)?;
+        secp.verify_schnorr(&sig, &Message::from_digest(id), &pubkey)
+            .context("Invalid event signature")
+    }
+
+    /// The first value of the first tag with the given name.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+}
+
+fn event_id(
+    pubkey: &str,
+    created_at: u64,
+    kind: u64,
+    tags: &[Vec<String>],
+    content: &str,
+) -> [u8; 32] {
+    let serialized = json!([0, pubkey, created_at, kind, tags, content]).to_string();
+    Sha256::digest(serialized.as_bytes()).into()
+}
+
+fn shared_secret(keys: &NostrKeys, pubkey: &str) -> Result<[u8; 32]> {
+    let xonly = XOnlyPublicKey::from_str(pubkey)?;
+    let pubkey = PublicKey::from_x_only_public_key(xonly, Parity::Even);
+    let point = ecdh::shared_secret_point(&pubkey, &keys.keypair.secret_key());
+
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&point[..32]);
+    Ok(secret)
+}
+
+/// Encrypts a direct message for `pubkey` (NIP-04).
+pub fn nip04_encrypt(keys: &NostrKeys, pubkey: &str, plaintext: &str) -> Result<String> {
+    let key = shared_secret(keys, pubkey)?;
+    let iv: [u8; 16] = rand::random();
+
+    let ciphertext = Aes256CbcEnc::new(&key.into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+
+    Ok(format!(
+        "{}?iv={}",
+        base64::encode(ciphertext),
+        base64::encode(iv)
+    ))
+}
+
+/// Decrypts a direct message from `pubkey` (NIP-04).
+pub fn nip04_decrypt(keys: &NostrKeys, pubkey: &str, content: &str) -> Result<String> {
+    let (ciphertext, iv) = content
+        .split_once("?iv=")
+        .ok_or_else(|| anyhow::anyhow!("Missing NIP-04 iv"))?;
+    let ciphertext = base64::decode(ciphertext)?;
+    let iv: [u8; 16] = base64::decode(iv)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid NIP-04 iv"))?;
+
+    let key = shared_secret(keys, pubkey)?;
+    let plaintext = Aes256CbcDec::new(&key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt NIP-04 content"))?;
+
+    Ok(String::from_utf8(plaintext)?)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NwcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NwcResponse {
+    pub result_type: String,
+    pub error: Option<NwcError>,
+    pub result: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NwcError {
+    pub code: String,
+    pub message: String,
+}
+
+impl NwcResponse {
+    pub fn ok(method: &str, result: serde_json::Value) -> NwcResponse {
+        Self {
+            result_type: method.to_string(),
+            error: None,
+            result: Some(result),
+        }
+    }
+
+    pub fn error(method: &str, code: &str, message: &str) -> NwcResponse {
+        Self {
+            result_type: method.to_string(),
+            error: Some(NwcError {
+                code: code.to_string(),
+                message: message.to_string(),
+            }),
+            result: None,
+        }
+    }
+}
+
+/// A client app allowed to use the wallet, with an optional spending budget
+/// that resets every `budget_renewal_sec`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NwcConnection {
+    pub client_pubkey: String,
+    pub budget_msat: Option<u64>,
+    pub budget_renewal_sec: Option<u64>,
+}
+
+/// Answers NIP-47 requests by routing them to the cluster. Connections are
+/// kept in the cluster's cache, so they survive restarts and are shared by
+/// every service running with the same wallet keys.
+pub struct NwcService {
+    pub keys: NostrKeys,
+    pub relay_url: String,
+    /// Overrides the cluster's default fee policy for wallet payments.
+    pub fee_policy: Option<FeePolicy>,
+}
+
+impl NwcService {
+    pub fn new(keys: NostrKeys, relay_url: String) -> NwcService {
+        Self {
+            keys,
+            relay_url,
+            fee_policy: None,
+        }
+    }
+
+    /// Creates a connection with fresh client keys and returns it with its
+    /// `nostr+walletconnect://` URI.
+    pub async fn add_connection(
+        &self,
+        cluster: &mut Cluster,
+        budget_msat: Option<u64>,
+        budget_renewal_sec: Option<u64>,
+    ) -> Result<(NwcConnection, String)> {
+        let client_keys = NostrKeys::generate();
+        let connection = NwcConnection {
+            client_pubkey: client_keys.pubkey.clone(),
+            budget_msat,
+            budget_renewal_sec,
+        };
+
+        let json_connection = serde_json::to_string(&connection)?;
+        let _: () = cluster
+            .cache
+            .hset(
+                connections_key(&self.keys.pubkey),
+                &connection.client_pubkey,
+                json_connection,
+            )
+            .await?;
+
+        let uri = format!(
+            "nostr+walletconnect://{}?relay={}&secret={}",
+            self.keys.pubkey,
+            url_encode(&self.relay_url),
+            client_keys.secret_hex()
+        );
+        Ok((connection, uri))
+    }
+
+    /// Revokes a connection. Its requests are answered with `UNAUTHORIZED`
+    /// from then on.
+    pub async fn remove_connection(
+        &self,
+        cluster: &mut Cluster,
+        client_pubkey: &str,
+    ) -> Result<()> {
+        let _: () = cluster
+            .cache
+            .hdel(connections_key(&self.keys.pubkey), client_pubkey)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn connection(
+        &self,
+        cluster: &mut Cluster,
+        client_pubkey: &str,
+    ) -> Result<Option<NwcConnection>> {
+        let json_connection: Option<String> = cluster
+            .cache
+            .hget(connections_key(&self.keys.pubkey), client_pubkey)
+            .await?;
+
+        json_connection
+            .map(|json_connection| serde_json::from_str(&json_connection))
+            .transpose()
+            .map_err(anyhow::Error::from)
+    }
+
+    /// The replaceable info event advertising the supported methods.
+    pub fn info_event(&self) -> NostrEvent {
+        NostrEvent::new(&self.keys, INFO_KIND, vec![], SUPPORTED_METHODS.join(" "))
+    }
+
+    /// Connects to the relay and answers requests until the connection drops.
+    pub async fn run(&self, cluster: &mut Cluster) -> Result<()> {
+        let mut relay = NwcRelay::connect(&self.relay_url).await?;
+        relay.publish(&self.info_event()).await?;
+        relay
+            .subscribe(
+                "nwc",
+                json!({ "kinds": [REQUEST_KIND], "#p": [self.keys.pubkey], "since": now() }),
+            )
+            .await?;
+
+        while let Some(event) = relay.next_event().await? {
+            match self.handle_event(cluster, &event).await {
+                Ok(Some(response)) => relay.publish(&response).await?,
+                Ok(None) => {}
+                Err(e) => eprintln!("failed to handle NWC request {}: {}", event.id, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a request event and returns the signed response event, or
+    /// `None` for events that are not requests addressed to this wallet.
+    pub async fn handle_event(
+        &self,
+        cluster: &mut Cluster,
+        event: &NostrEvent,
+    ) -> Result<Option<NostrEvent>> {
+        if event.kind != REQUEST_KIND || event.tag("p") != Some(self.keys.pubkey.as_str()) {
+            return Ok(None);
+        }
+        event.verify()?;
+
+        let connection = match self.connection(cluster, &event.pubkey).await? {
+            Some(connection) => connection,
+            None => {
+                let response = NwcResponse::error("", "UNAUTHORIZED", "Unknown connection");
+                return Ok(Some(self.response_event(event, &response)?));
+            }
+        };
+
+        let plaintext = nip04_decrypt(&self.keys, &event.pubkey, &event.content)?;
+        let response = match serde_json::from_str::<NwcRequest>(&plaintext) {
+            Ok(request) => self.handle_request(cluster, &connection, &request).await,
+            Err(_) => NwcResponse::error("", "OTHER", "Invalid request"),
+        };
+
+        Ok(Some(self.response_event(event, &response)?))
+    }
+
+    /// Encrypts and signs the response to `request`.
+    pub fn response_event(
+        &self,
+        request: &NostrEvent,
+        response: &NwcResponse,
+    ) -> Result<NostrEvent> {
+        let content = nip04_encrypt(
+            &self.keys,
+            &request.pubkey,
+            &serde_json::to_string(response)?,
+        )?;
+        let tags = vec![
+            vec!["p".to_string(), request.pubkey.clone()],
+            vec!["e".to_string(), request.id.clone()],
+        ];
+
+        Ok(NostrEvent::new(&self.keys, RESPONSE_KIND, tags, content))
+    }
+
+    pub async fn handle_request(
+        &self,
+        cluster: &mut Cluster,
+        connection: &NwcConnection,
+        request: &NwcRequest,
+    ) -> NwcResponse {
+        let method = request.method.as_str();
+        let result = match method {
+            "pay_invoice" => self.pay_invoice(cluster, connection, &request.params).await,
+            "make_invoice" => self.make_invoice(cluster, &request.params).await,
+            "lookup_invoice" => self.lookup_invoice(cluster, &request.params).await,
+            "get_balance" => self.get_balance(cluster, connection).await,
+            _ => {
+                return NwcResponse::error(method, "NOT_IMPLEMENTED", "Method not supported");
+            }
+        };
+
+        match result {
+            Ok(result) => NwcResponse::ok(method, result),
+            Err(response) => response,
+        }
+    }
+
+    async fn pay_invoice(
+        &self,
+        cluster: &mut Cluster,
+        connection: &NwcConnection,
+        params: &serde_json::Value,
+    ) -> std::result::Result<serde_json::Value, NwcResponse> {
+        let method = "pay_invoice";
+        let invoice = params["invoice"]
+            .as_str()
+            .ok_or_else(|| NwcResponse::error(method, "OTHER", "Missing invoice"))?;
+        let decoded = invoice
+            .parse::<Bolt11Invoice>()
+            .map_err(|_| NwcResponse::error(method, "OTHER", "Invalid invoice"))?;
+
+        let (amount_msat, amount) = match decoded.amount_milli_satoshis() {
+            Some(amount_msat) => (amount_msat, 0),
+            None => match params["amount"].as_u64() {
+                // payments are sent in whole satoshis
+                Some(amount_msat) if amount_msat % 1000 != 0 => {
+                    return Err(NwcResponse::error(
+                        method,
+                        "OTHER",
+                        "Amount must be a whole number of satoshis",
+                    ));
+                }
+                Some(amount_msat) => (amount_msat, amount_msat / 1000),
+                None => return Err(NwcResponse::error(method, "OTHER", "Missing amount")),
+            },
+        };
+
+        let fee_policy = self
+            .fee_policy
+            .clone()
+            .unwrap_or_else(|| cluster.fee_policy.clone());
+        let max_fee_msat = fee_policy.max_fee_msat(amount_msat);
+        let reserved = amount_msat + max_fee_msat;
+        let internal = |e: anyhow::Error| NwcResponse::error(method, "INTERNAL", &e.to_string());
+
+        if !reserve_budget(cluster, connection, reserved)
+            .await
+            .map_err(internal)?
+        {
+            return Err(NwcResponse::error(
+                method,
+                "QUOTA_EXCEEDED",
+                "Spending budget exceeded",
+            ));
+        }
+
+        let payment = match cluster
+            .pay_invoice(amount, invoice.to_string(), Some(fee_policy), None, None)
+            .await
+        {
+            Ok(payment) => payment,
+            Err(e) => {
+                // the budget stays reserved unless nothing was sent, as the
+                // payment may still settle
+                if PaymentNotSent::is(&e) {
+                    let _ = refund_budget(cluster, connection, reserved).await;
+                }
+                return Err(internal(e));
+            }
+        };
+
+        match (payment.payment_error, payment.payment_preimage) {
+            (Some(error), _) => {
+                let _ = refund_budget(cluster, connection, reserved).await;
+                Err(NwcResponse::error(method, "PAYMENT_FAILED", &error))
+            }
+            (None, Some(preimage)) => {
+                let fee_msat = fee_paid_msat(payment.payment_route.as_ref(), max_fee_msat);
+                let unused_fee = max_fee_msat.saturating_sub(fee_msat);
+                let _ = refund_budget(cluster, connection, unused_fee).await;
+
+                Ok(json!({ "preimage": preimage }))
+            }
+            (None, None) => Err(NwcResponse::error(method, "INTERNAL", "Payment is pending")),
+        }
+    }
+
+    async fn make_invoice(
+        &self,
+        cluster: &mut Cluster,
+        params: &serde_json::Value,
+    ) -> std::result::Result<serde_json::Value, NwcResponse> {
+        let method = "make_invoice";
+        let amount_msat = params["amount"]
+            .as_u64()
+            .ok_or_else(|| NwcResponse::error(method, "OTHER", "Missing amount"))?;
+        let expiry = params["expiry"].as_i64().unwrap_or(3600);
+
+        // the invoice commits to either the description or its hash
+        let description_hash = params["description_hash"].as_str().map(String::from);
+        let memo = match description_hash {
+            Some(_) => String::new(),
+            None => params["description"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        };
+        let req = ClusterAddInvoice {
+            memo,
+            value_msat: Some(amount_msat as i64),
+            expiry,
+            description_hash,
+            ..Default::default()
+        };
+        let invoice = cluster
+            .add_invoice(req, None)
+            .await
+            .map_err(|e| NwcResponse::error(method, "INTERNAL", &e.to_string()))?;
+
+        let created_at = now();
+        Ok(json!({
+            "type": "incoming",
+            "invoice": invoice.payment_request,
+            "description": params["description"],
+            "description_hash": params["description_hash"],
+            "payment_hash": invoice.r_hash,
+            "amount": amount_msat,
+            "fees_paid": 0,
+            "created_at": created_at,
+            "expires_at": created_at + expiry as u64,
+        }))
+    }
+
+    async fn lookup_invoice(
+        &self,
+        cluster: &mut Cluster,
+        params: &serde_json::Value,
+    ) -> std::result::Result<serde_json::Value, NwcResponse> {
+        let method = "lookup_invoice";
+        let payment_hash = match (params["payment_hash"].as_str(), params["invoice"].as_str()) {
+            (Some(payment_hash), _) => payment_hash.to_string(),
+            (None, Some(invoice)) => invoice
+                .parse::<Bolt11Invoice>()
+                .map(|invoice| invoice.payment_hash().to_string())
+                .map_err(|_| NwcResponse::error(method, "OTHER", "Invalid invoice"))?,
+            (None, None) => {
+                return Err(NwcResponse::error(
+                    method,
+                    "OTHER",
+                    "Missing payment_hash or invoice",
+                ));
+            }
+        };
+
+        let invoice = cluster
+            .lookup_invoice(&payment_hash, None)
+            .await
+            .map_err(|_| NwcResponse::error(method, "NOT_FOUND", "Invoice not found"))?;
+
+        Ok(lookup_result(&invoice))
+    }
+
+    async fn get_balance(
+        &self,
+        cluster: &mut Cluster,
+        connection: &NwcConnection,
+    ) -> std::result::Result<serde_json::Value, NwcResponse> {
+        let method = "get_balance";
+        let internal = |e: anyhow::Error| NwcResponse::error(method, "INTERNAL", &e.to_string());
+
+        let mut balance = cluster.channel_balance(None).await.map_err(internal)?;
+        if let Some(budget_msat) = connection.budget_msat {
+            let spent: Option<u64> = cluster
+                .cache
+                .get(budget_key(connection))
+                .await
+                .map_err(|e| internal(e.into()))?;
+            balance = balance.min(budget_msat.saturating_sub(spent.unwrap_or(0)));
+        }
+
+        Ok(json!({ "balance": balance }))
+    }
+}
+
+/// The routing fee of a successful payment, `max_fee_msat` when the route
+/// is unknown.
+fn fee_paid_msat(route: Option<&Route>, max_fee_msat: u64) -> u64 {
+    route
+        .and_then(|route| route.total_fees_msat.parse::<u64>().ok())
+        .unwrap_or(max_fee_msat)
+}
+
+fn lookup_result(invoice: &ClusterLookupInvoice) -> serde_json::Value {
+    let amount_msat = invoice
+        .value_msat
+        .parse::<u64>()
+        .unwrap_or_else(|_| invoice.value.parse::<u64>().unwrap_or(0) * 1000);
+    let created_at = invoice.creation_date.parse::<u64>().unwrap_or(0);
+    let expiry = invoice.expiry.parse::<u64>().unwrap_or(0);
+
+    let mut result = json!({
+        "type": "incoming",
+        "invoice": invoice.payment_request,
+        "description": invoice.memo,
+        "payment_hash": invoice.r_hash,
+        "amount": amount_msat,
+        "fees_paid": 0,
+        "created_at": created_at,
+        "expires_at": created_at + expiry,
+    });
+
+    if invoice.state == ClusterInvoiceState::Settled {
+        result["preimage"] = json!(invoice.r_preimage);
+        result["settled_at"] = json!(invoice.settle_date.parse::<u64>().unwrap_or(0));
+    }
+
+    result
+}
+
+/// Adds `amount_msat` to the connection's spend counter, undoing it and
+/// returning false when that would exceed the budget.
+async fn reserve_budget(
+    cluster: &mut Cluster,
+    connection: &NwcConnection,
+    amount_msat: u64,
+) -> Result<bool> {
+    let budget_msat = match connection.budget_msat {
+        Some(budget_msat) => budget_msat,
+        None => return Ok(true),
+    };
+
+    let key = budget_key(connection);
+    let spent: u64 = cluster.cache.incr(&key, amount_msat).await?;
+
+    if let Some(renewal) = connection.budget_renewal_sec {
+        let ttl: i64 = cluster.cache.ttl(&key).await?;
+        if ttl < 0 {
+            let _: () = cluster.cache.expire(&key, renewal as usize).await?;
+        }
+    }
+
+    if spent > budget_msat {
+        let _: () = cluster.cache.decr(&key, amount_msat).await?;
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+async fn refund_budget(
+    cluster: &mut Cluster,
+    connection: &NwcConnection,
+    amount_msat: u64,
+) -> Result<()> {
+    if connection.budget_msat.is_none() || amount_msat == 0 {
+        return Ok(());
+    }
+
+    let _: () = cluster
+        .cache
+        .decr(budget_key(connection), amount_msat)
+        .await?;
+    Ok(())
+}
+
+fn budget_key(connection: &NwcConnection) -> String {
+    format!("nwc:spent:{}", connection.client_pubkey)
+}
+
+fn connections_key(wallet_pubkey: &str) -> String {
+    format!("nwc:connections:{}", wallet_pubkey)
+}
+
+/// A minimal relay client: one subscription, publishing and reading events.
+pub struct NwcRelay {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl NwcRelay {
+    pub async fn connect(url: &str) -> Result<NwcRelay> {
+        let (ws, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .context("Failed to connect to relay")?;
+
+        Ok(Self { ws })
+    }
+
+    pub async fn subscribe(
+        &mut self,
+        subscription_id: &str,
+        filter: serde_json::Value,
+    ) -> Result<()> {
+        let message = json!(["REQ", subscription_id, filter]).to_string();
+        self.ws.send(WsMessage::Text(message)).await?;
+        Ok(())
+    }
+
+    pub async fn publish(&mut self, event: &NostrEvent) -> Result<()> {
+        let message = json!(["EVENT", event]).to_string();
+        self.ws.send(WsMessage::Text(message)).await?;
+        Ok(())
+    }
+
+    /// The next event delivered on a subscription, skipping relay notices.
+    /// Returns `None` once the relay closes the connection.
+    pub async fn next_event(&mut self) -> Result<Option<NostrEvent>> {
+        while let Some(message) = self.ws.next().await {
+            let text = match message? {
+                WsMessage::Text(text) => text,
+                WsMessage::Close(_) => return Ok(None),
+                _ => continue,
+            };
+
+            let message: Vec<serde_json::Value> = match serde_json::from_str(&text) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+
+            if message.first().and_then(|t| t.as_str()) == Some("EVENT") && message.len() == 3 {
+                if let Ok(event) = serde_json::from_value::<NostrEvent>(message[2].clone()) {
+                    return Ok(Some(event));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn url_encode(value: &str) -> String {
+    reqwest::Url::parse_with_params("http://localhost", &[("v", value)])
+        .map(|url| {
+            url.query()
+                .unwrap_or_default()
+                .trim_start_matches("v=")
+                .to_string()
+        })
+        .unwrap_or_else(|_| value.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fee_paid_msat, lookup_result, nip04_decrypt, nip04_encrypt, NostrEvent, NostrKeys,
+        NwcResponse, NwcService, INFO_KIND, REQUEST_KIND, RESPONSE_KIND,
+    };
+    use crate::cluster::{Cluster, ClusterInvoiceState, ClusterLookupInvoice};
+    use crate::lnd::Route;
+    use crate::testing::{test_cluster, test_invoice};
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn request_event(
+        client_keys: &NostrKeys,
+        wallet_pubkey: &str,
+        request: serde_json::Value,
+    ) -> NostrEvent {
+        let content = nip04_encrypt(client_keys, wallet_pubkey, &request.to_string()).unwrap();
+        NostrEvent::new(
+            client_keys,
+            REQUEST_KIND,
+            vec![vec![String::from("p"), wallet_pubkey.to_string()]],
+            content,
+        )
+    }
+
+    async fn send(
+        service: &NwcService,
+        cluster: &mut Cluster,
+        client_keys: &NostrKeys,
+        request: serde_json::Value,
+    ) -> NwcResponse {
+        let event = request_event(client_keys, &service.keys.pubkey, request);
+        let response = service
+            .handle_event(cluster, &event)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.tag("e"), Some(event.id.as_str()));
+
+        let plaintext = nip04_decrypt(client_keys, &response.pubkey, &response.content).unwrap();
+        serde_json::from_str(&plaintext).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handle_event() {
+        let mut cluster = test_cluster(vec![]).await;
+        let service = NwcService::new(NostrKeys::generate(), String::new());
+        let (connection, uri) = service
+            .add_connection(&mut cluster, Some(10_000), None)
+            .await
+            .unwrap();
+        let client_keys = NostrKeys::from_secret_hex(uri.split("secret=").nth(1).unwrap()).unwrap();
+
+        let stored = service
+            .connection(&mut cluster, &connection.client_pubkey)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.budget_msat, Some(10_000));
+
+        // not addressed to this wallet
+        let other = request_event(
+            &client_keys,
+            &NostrKeys::generate().pubkey,
+            json!({ "method": "get_info" }),
+        );
+        assert!(service
+            .handle_event(&mut cluster, &other)
+            .await
+            .unwrap()
+            .is_none());
+
+        let response = send(
+            &service,
+            &mut cluster,
+            &NostrKeys::generate(),
+            json!({ "method": "get_info" }),
+        )
+        .await;
+        assert_eq!(response.error.unwrap().code, "UNAUTHORIZED");
+
+        let response = send(
+            &service,
+            &mut cluster,
+            &client_keys,
+            json!({ "method": "get_info" }),
+        )
+        .await;
+        assert_eq!(response.result_type, "get_info");
+        assert_eq!(response.error.unwrap().code, "NOT_IMPLEMENTED");
+
+        let request = json!({ "method": "pay_invoice", "params": { "invoice": test_invoice(None), "amount": 1500 } });
+        let response = send(&service, &mut cluster, &client_keys, request).await;
+        assert_eq!(
+            response.error.unwrap().message,
+            "Amount must be a whole number of satoshis"
+        );
+
+        let request = json!({ "method": "pay_invoice", "params": { "invoice": test_invoice(Some(2_000_000)) } });
+        let response = send(&service, &mut cluster, &client_keys, request).await;
+        assert_eq!(response.error.unwrap().code, "QUOTA_EXCEEDED");
+
+        let request = json!({ "method": "lookup_invoice", "params": {} });
+        let response = send(&service, &mut cluster, &client_keys, request).await;
+        assert_eq!(
+            response.error.unwrap().message,
+            "Missing payment_hash or invoice"
+        );
+
+        service
+            .remove_connection(&mut cluster, &connection.client_pubkey)
+            .await
+            .unwrap();
+        let response = send(
+            &service,
+            &mut cluster,
+            &client_keys,
+            json!({ "method": "get_info" }),
+        )
+        .await;
+        assert_eq!(response.error.unwrap().code, "UNAUTHORIZED");
+    }
+
+    #[test]
+    fn test_lookup_result() {
+        let mut invoice = ClusterLookupInvoice {
+            pubkey: "02ab".to_string(),
+            memo: "test".to_string(),
+            r_preimage: "07".repeat(32),
+            r_hash: "ab".repeat(32),
+            value: "1".to_string(),
+            settle_date: "1700000100".to_string(),
+            payment_request: "lnbcrt1".to_string(),
+            description_hash: "".to_string(),
+            expiry: "3600".to_string(),
+            amt_paid_sat: "1".to_string(),
+            state: ClusterInvoiceState::Settled,
+            creation_date: "1700000000".to_string(),
+            add_index: "1".to_string(),
+            value_msat: "1500".to_string(),
+        };
+        let result = lookup_result(&invoice);
+        assert_eq!(result["amount"], 1500);
+        assert_eq!(result["expires_at"], 1700003600);
+        assert_eq!(result["settled_at"], 1700000100);
+        assert_eq!(result["preimage"], "07".repeat(32));
+
+        // cached before value_msat was stored
+        invoice.value_msat = String::new();
+        assert_eq!(lookup_result(&invoice)["amount"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_run_over_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let mut cluster = test_cluster(vec![]).await;
+        let service = NwcService::new(NostrKeys::generate(), relay_url.clone());
+        let wallet_pubkey = service.keys.pubkey.clone();
+        let (connection, uri) = service
+            .add_connection(&mut cluster, Some(100_000), None)
+            .await
+            .unwrap();
+        assert!(uri.starts_with(&format!(
+            "nostr+walletconnect://{}?relay=ws%3A%2F%2F127.0.0.1",
+            wallet_pubkey
+        )));
+
+        let client_keys = NostrKeys::from_secret_hex(uri.split("secret=").nth(1).unwrap()).unwrap();
+        assert_eq!(client_keys.pubkey, connection.client_pubkey);
+        let request_event = request_event(
+            &client_keys,
+            &wallet_pubkey,
+            json!({ "method": "get_info" }),
+        );
+
+        // relay stand-in: answers the subscription with the request, hands
+        // back whatever the wallet publishes and closes after the response
+        let (published_tx, mut published_rx) = mpsc::unbounded_channel::<NostrEvent>();
+        let relay_event = request_event.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                let message: Vec<serde_json::Value> = serde_json::from_str(&text).unwrap();
+                match message[0].as_str().unwrap() {
+                    "REQ" => {
+                        let event = json!(["EVENT", message[1], relay_event]).to_string();
+                        ws.send(Message::Text(event)).await.unwrap();
+                    }
+                    "EVENT" => {
+                        let event: NostrEvent = serde_json::from_value(message[1].clone()).unwrap();
+                        let kind = event.kind;
+                        published_tx.send(event).unwrap();
+                        if kind == RESPONSE_KIND {
+                            ws.close(None).await.unwrap();
+                            return;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let run = tokio::spawn(async move { service.run(&mut cluster).await });
+
+        let info = published_rx.recv().await.unwrap();
+        info.verify().unwrap();
+        assert_eq!(info.kind, INFO_KIND);
+        assert!(info
+            .content
+            .split(' ')
+            .any(|method| method == "pay_invoice"));
+
+        let published = published_rx.recv().await.unwrap();
+        published.verify().unwrap();
+        assert_eq!(published.kind, RESPONSE_KIND);
+        assert_eq!(published.pubkey, wallet_pubkey);
+        assert_eq!(published.tag("e"), Some(request_event.id.as_str()));
+
+        let plaintext = nip04_decrypt(&client_keys, &published.pubkey, &published.content).unwrap();
+        let response: NwcResponse = serde_json::from_str(&plaintext).unwrap();
+        assert_eq!(response.result_type, "get_info");
+        assert_eq!(response.error.unwrap().code, "NOT_IMPLEMENTED");
+
+        run.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_fee_paid_msat() {
+        let route: Route = serde_json::from_value(json!({
+            "total_time_lock": 100,
+            "total_fees": "1",
+            "total_fees_msat": "1999",
+            "total_amt": "101",
+            "total_amt_msat": "101999",
+            "hops": []
+        }))
+        .unwrap();
+        assert_eq!(fee_paid_msat(Some(&route), 5000), 1999);
+        assert_eq!(fee_paid_msat(None, 5000), 5000);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::cluster::{ClusterOnchainFee, ClusterOnchainTx, Node};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// A withdrawal waiting for the next batch.
+struct QueuedSend {
+    addr: String,
+    amount: i64,
+    reply: oneshot::Sender<std::result::Result<ClusterOnchainTx, String>>,
+}
+
+/// Queues on-chain sends from one node and pays them together. The first
+/// send opens a batch, every send queued within `window` joins it, and the
+/// batch is paid with one `/v1/transactions/many` call whose txid is
+/// returned to each queued send.
+pub struct OnchainBatcher {
+    queue: mpsc::UnboundedSender<QueuedSend>,
+    handle: JoinHandle<()>,
+}
+
+impl OnchainBatcher {
+    /// Starts the batcher, failing up front on an invalid `fee` or a node
+    /// whose wallet can't be used rather than on the first batch.
+    pub fn spawn(node: Node, window: Duration, fee: ClusterOnchainFee) -> Result<OnchainBatcher> {
+        fee.validate()?;
+        if !node.supports_wallet() {
+            return Err(anyhow::anyhow!(
+                "Node {} does not support on-chain wallets",
+                node.pubkey
+            ));
+        }
+
+        let (queue, mut rx) = mpsc::unbounded_channel::<QueuedSend>();
+
+        let handle = tokio::spawn(async move {
+            while let Some(first) = rx.recv().await {
+                let mut batch = vec![first];
+                let deadline = tokio::time::Instant::now() + window;
+
+                while let Ok(Some(send)) = tokio::time::timeout_at(deadline, rx.recv()).await {
+                    batch.push(send);
+                }
+
+                let outputs =
+                    batch_outputs(batch.iter().map(|send| (send.addr.as_str(), send.amount)));
+                let result = node
+                    .send_onchain_many(&outputs, fee, None)
+                    .await
+                    .map_err(|e| e.to_string());
+
+                if let Err(e) = &result {
+                    eprintln!("on-chain batch of {} sends failed: {}", batch.len(), e);
+                }
+
+                for send in batch {
+                    let _ = send.reply.send(result.clone());
+                }
+            }
+        });
+
+        Ok(Self { queue, handle })
+    }
+
+    /// Queues `amount` sats to `addr` and waits for the batch to be sent.
+    pub async fn send(&self, addr: String, amount: i64) -> Result<ClusterOnchainTx> {
+        if addr.is_empty() || amount <= 0 {
+            return Err(anyhow::anyhow!("addr and a positive amount are required"));
+        }
+
+        let (reply, rx) = oneshot::channel();
+        self.queue
+            .send(QueuedSend {
+                addr,
+                amount,
+                reply,
+            })
+            .map_err(|_| anyhow::anyhow!("On-chain batcher has stopped"))?;
+
+        rx.await
+            .map_err(|_| anyhow::anyhow!("On-chain batcher has stopped"))?
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+
+    /// Stops accepting sends. Queued sends are still paid.
+    pub async fn shutdown(self) {
+        drop(self.queue);
+        let _ = self.handle.await;
+    }
+}
+
+/// Outputs for a batch. Sends to the same address are combined, as a
+/// transaction can only pay each address once.
+fn batch_outputs<'a>(sends: impl Iterator<Item = (&'a str, i64)>) -> BTreeMap<String, i64> {
+    let mut outputs = BTreeMap::new();
+    for (addr, amount) in sends {
+        *outputs.entry(addr.to_string()).or_insert(0) += amount;
+    }
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_outputs, OnchainBatcher};
+    use crate::cln::ClnClient;
+    use crate::cluster::{ClusterOnchainFee, Node, NodeClient, NodeLightningImpl, NodeNetwork};
+    use std::time::Duration;
+
+    #[test]
+    fn test_spawn_validates() {
+        let node = Node::new(
+            "02cc".to_string(),
+            "127.0.0.1".to_string(),
+            "3010".to_string(),
+            NodeNetwork::Testnet,
+            NodeLightningImpl::CLightning,
+            NodeClient::CLightning(ClnClient::new(String::new(), String::new(), String::new())),
+        );
+        let window = Duration::from_secs(1);
+
+        let err = OnchainBatcher::spawn(node.clone(), window, ClusterOnchainFee::SatPerVbyte(0))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "sat_per_vbyte must be greater than 0");
+
+        let err = OnchainBatcher::spawn(node, window, ClusterOnchainFee::TargetConf(6))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Node 02cc does not support on-chain wallets"
+        );
+    }
+
+    #[test]
+    fn test_batch_outputs() {
+        let sends = vec![("bc1qa", 1000), ("bc1qb", 2000), ("bc1qa", 500)];
+        let outputs = batch_outputs(sends.into_iter());
+
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs.get("bc1qa"), Some(&1500));
+        assert_eq!(outputs.get("bc1qb"), Some(&2000));
+    }
+}
@@ -0,0 +1,511 @@
+use crate::cluster::{Cluster, ClusterChannel, ClusterLiquidityReport, ClusterRebalance};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Duration;
+
+/// Bounds for a node's share of its active channel balance on its side.
+/// Nodes outside the band are brought back to its midpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RebalanceBand {
+    pub min_local_ratio: f64,
+    pub max_local_ratio: f64,
+}
+
+impl Default for RebalanceBand {
+    fn default() -> Self {
+        Self {
+            min_local_ratio: 0.2,
+            max_local_ratio: 0.8,
+        }
+    }
+}
+
+impl RebalanceBand {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.min_local_ratio)
+            || !(0.0..=1.0).contains(&self.max_local_ratio)
+            || self.min_local_ratio >= self.max_local_ratio
+        {
+            return Err(anyhow::anyhow!(
+                "Rebalance bands need 0 <= min_local_ratio < max_local_ratio <= 1"
+            ));
+        }
+        Ok(())
+    }
+
+    fn target(&self) -> f64 {
+        (self.min_local_ratio + self.max_local_ratio) / 2.0
+    }
+}
+
+/// How `Cluster::auto_rebalance` moves liquidity between nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AutoRebalancePolicy {
+    pub band: RebalanceBand,
+    /// Bands for specific nodes, by pubkey, instead of `band`.
+    pub node_bands: HashMap<String, RebalanceBand>,
+    pub max_fee_ppm: u64,
+    /// Smaller moves aren't worth a payment.
+    pub min_amount_sat: i64,
+    /// Larger moves are split into payments of this size.
+    pub max_amount_sat: i64,
+}
+
+impl Default for AutoRebalancePolicy {
+    fn default() -> Self {
+        Self {
+            band: RebalanceBand::default(),
+            node_bands: HashMap::new(),
+            max_fee_ppm: 500,
+            min_amount_sat: 50_000,
+            max_amount_sat: 1_000_000,
+        }
+    }
+}
+
+impl AutoRebalancePolicy {
+    pub fn validate(&self) -> Result<()> {
+        self.band.validate()?;
+        for band in self.node_bands.values() {
+            band.validate()?;
+        }
+
+        if self.min_amount_sat <= 0 || self.min_amount_sat > self.max_amount_sat {
+            return Err(anyhow::anyhow!(
+                "Rebalance amounts need 0 < min_amount_sat <= max_amount_sat"
+            ));
+        }
+        Ok(())
+    }
+
+    fn band(&self, pubkey: &str) -> &RebalanceBand {
+        self.node_bands.get(pubkey).unwrap_or(&self.band)
+    }
+}
+
+/// A payment of `amount_sat` from `from` to `to`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RebalancePlan {
+    pub from: String,
+    pub to: String,
+    pub amount_sat: i64,
+    pub max_fee_sat: u64,
+    /// Set once the rebalance succeeded.
+    pub rebalance: Option<ClusterRebalance>,
+    /// Set when the rebalance failed.
+    pub error: Option<String>,
+}
+
+/// A rebalance payment that did not verifiably settle. Returned wrapped in
+/// `anyhow::Error` by `Cluster::rebalance` and `Cluster::circular_rebalance`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RebalanceError {
+    /// The invoice was canceled unpaid, so no liquidity moved and no fee
+    /// was paid.
+    Failed(String),
+    /// The invoice could not be canceled and is not settled. The payment
+    /// may still be in flight and settle later.
+    Unknown(String),
+}
+
+impl fmt::Display for RebalanceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RebalanceError::Failed(error) => write!(f, "{}", error),
+            RebalanceError::Unknown(error) => write!(f, "{}, the payment may still settle", error),
+        }
+    }
+}
+
+impl std::error::Error for RebalanceError {}
+
+/// How `Cluster::circular_rebalance_channels` moves liquidity between the
+/// channels of each node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CircularRebalancePolicy {
+    /// Channels below the band are depleted, channels above it overfull.
+    pub band: RebalanceBand,
+    pub max_fee_ppm: u64,
+    pub min_amount_sat: i64,
+    pub max_amount_sat: i64,
+    /// Fees all circular rebalances may spend per UTC day.
+    pub daily_fee_budget_sat: u64,
+}
+
+impl Default for CircularRebalancePolicy {
+    fn default() -> Self {
+        Self {
+            band: RebalanceBand::default(),
+            max_fee_ppm: 500,
+            min_amount_sat: 50_000,
+            max_amount_sat: 1_000_000,
+            daily_fee_budget_sat: 10_000,
+        }
+    }
+}
+
+impl CircularRebalancePolicy {
+    pub fn validate(&self) -> Result<()> {
+        self.band.validate()?;
+
+        if self.max_fee_ppm == 0 {
+            return Err(anyhow::anyhow!("max_fee_ppm must be greater than 0"));
+        }
+        if self.min_amount_sat <= 0 || self.min_amount_sat > self.max_amount_sat {
+            return Err(anyhow::anyhow!(
+                "Rebalance amounts need 0 < min_amount_sat <= max_amount_sat"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A payment of `amount_sat` from node `pubkey` to itself, out through the
+/// overfull `outgoing_chan_id` and in from the peer of a depleted channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CircularRebalancePlan {
+    pub pubkey: String,
+    pub outgoing_chan_id: String,
+    pub last_hop_pubkey: String,
+    pub amount_sat: u64,
+    pub max_fee_sat: u64,
+    /// Set once the rebalance succeeded.
+    pub rebalance: Option<ClusterRebalance>,
+    /// Set when the rebalance failed or was skipped.
+    pub error: Option<String>,
+}
+
+/// Runs `Cluster::circular_rebalance_channels` every `interval` on a cluster
+/// of its own, logging failures. Spawn `run` and abort the task to stop it.
+pub struct RebalanceScheduler {
+    pub policy: CircularRebalancePolicy,
+    pub interval: Duration,
+}
+
+impl RebalanceScheduler {
+    pub fn new(policy: CircularRebalancePolicy) -> Result<RebalanceScheduler> {
+        policy.validate()?;
+
+        Ok(Self {
+            policy,
+            interval: Duration::from_secs(3600),
+        })
+    }
+
+    pub async fn run(self, mut cluster: Cluster) {
+        loop {
+            match cluster
+                .circular_rebalance_channels(&self.policy, false)
+                .await
+            {
+                Ok(plans) => {
+                    for plan in plans {
+                        if let Some(error) = plan.error {
+                            eprintln!(
+                                "circular rebalance of {} via {} failed: {}",
+                                plan.pubkey, plan.outgoing_chan_id, error
+                            );
+                        }
+                    }
+                }
+                Err(e) => eprintln!("circular rebalance run failed: {}", e),
+            }
+
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+/// Counter of the fees circular rebalances reserved on the UTC day of
+/// `timestamp`.
+pub fn fee_budget_key(timestamp: u64) -> String {
+    format!("rebalance:fees:{}", timestamp / 86400)
+}
+
+/// How much of a `max_fee_sat` budget reservation to give back after a
+/// circular rebalance. A rebalance that settled keeps the fee it paid, or
+/// the whole reservation when the fee is unknown. A failure keeps the whole
+/// reservation too, unless the invoice was canceled unpaid or the rebalance
+/// failed before paying, as only then is it certain no fee was spent.
+pub fn budget_refund_sat(max_fee_sat: u64, result: &Result<ClusterRebalance>) -> u64 {
+    match result {
+        Ok(rebalance) => match rebalance.fee_sat {
+            Some(fee_sat) => max_fee_sat.saturating_sub(fee_sat),
+            None => 0,
+        },
+        Err(e) => match e.downcast_ref::<RebalanceError>() {
+            Some(RebalanceError::Unknown(_)) => 0,
+            Some(RebalanceError::Failed(_)) | None => max_fee_sat,
+        },
+    }
+}
+
+/// Pairs nodes with too much outbound liquidity with nodes with too little,
+/// largest first, moving each towards the middle of its band.
+pub fn plan_rebalances(
+    report: &ClusterLiquidityReport,
+    policy: &AutoRebalancePolicy,
+) -> Vec<RebalancePlan> {
+    let mut sources = vec![];
+    let mut sinks = vec![];
+    for (pubkey, liquidity) in &report.nodes {
+        let band = policy.band(pubkey);
+        let ratio = match liquidity.local_ratio() {
+            Some(ratio) => ratio,
+            None => continue,
+        };
+
+        let target = (band.target() * (liquidity.outbound + liquidity.inbound) as f64) as i64;
+        if ratio > band.max_local_ratio {
+            sources.push((pubkey.clone(), liquidity.outbound - target));
+        } else if ratio < band.min_local_ratio {
+            sinks.push((pubkey.clone(), target - liquidity.outbound));
+        }
+    }
+
+    match_amounts(
+        sources,
+        sinks,
+        policy.min_amount_sat,
+        policy.max_amount_sat,
+        |_, _| true,
+    )
+    .into_iter()
+    .map(|(from, to, amount_sat)| RebalancePlan {
+        from,
+        to,
+        amount_sat,
+        max_fee_sat: max_fee_sat(amount_sat, policy.max_fee_ppm),
+        rebalance: None,
+        error: None,
+    })
+    .collect()
+}
+
+/// Pairs each node's active channels above the band with its channels
+/// below it, largest first, moving each towards the middle of the band.
+pub fn plan_circular_rebalances(
+    channels: &[ClusterChannel],
+    policy: &CircularRebalancePolicy,
+) -> Vec<CircularRebalancePlan> {
+    let mut by_node: BTreeMap<&str, Vec<&ClusterChannel>> = BTreeMap::new();
+    for channel in channels.iter().filter(|channel| channel.active) {
+        by_node.entry(&channel.pubkey).or_default().push(channel);
+    }
+
+    let mut plans = vec![];
+    for (pubkey, node_channels) in by_node {
+        let mut sources = vec![];
+        let mut sinks = vec![];
+        for channel in node_channels {
+            let ratio = match channel.local_ratio() {
+                Some(ratio) => ratio,
+                None => continue,
+            };
+
+            let balance = channel.local_balance + channel.remote_balance;
+            let target = (policy.band.target() * balance as f64) as i64;
+            if ratio > policy.band.max_local_ratio {
+                sources.push((
+                    (&channel.chan_id, &channel.peer),
+                    channel.local_balance - target,
+                ));
+            } else if ratio < policy.band.min_local_ratio {
+                sinks.push((&channel.peer, target - channel.local_balance));
+            }
+        }
+
+        // paying out and back in through the same peer moves nothing
+        let matched = match_amounts(
+            sources,
+            sinks,
+            policy.min_amount_sat,
+            policy.max_amount_sat,
+            |(_, outgoing_peer), last_hop| outgoing_peer != last_hop,
+        );
+        for ((chan_id, _), last_hop, amount_sat) in matched {
+            plans.push(CircularRebalancePlan {
+                pubkey: pubkey.to_string(),
+                outgoing_chan_id: chan_id.clone(),
+                last_hop_pubkey: last_hop.clone(),
+                amount_sat: amount_sat as u64,
+                max_fee_sat: max_fee_sat(amount_sat, policy.max_fee_ppm),
+                rebalance: None,
+                error: None,
+            });
+        }
+    }
+
+    plans
+}
+
+fn max_fee_sat(amount_sat: i64, max_fee_ppm: u64) -> u64 {
+    (amount_sat as u64 * max_fee_ppm).div_ceil(1_000_000)
+}
+
+/// Greedily moves amounts from the largest sources to the largest sinks
+/// they are `compatible` with, in chunks of at most `max_amount` and
+/// skipping moves below `min_amount`.
+fn match_amounts<S: Clone, T: Clone>(
+    mut sources: Vec<(S, i64)>,
+    mut sinks: Vec<(T, i64)>,
+    min_amount: i64,
+    max_amount: i64,
+    compatible: impl Fn(&S, &T) -> bool,
+) -> Vec<(S, T, i64)> {
+    sources.sort_by_key(|source| std::cmp::Reverse(source.1));
+    sinks.sort_by_key(|sink| std::cmp::Reverse(sink.1));
+
+    let mut matched = vec![];
+    for source in &mut sources {
+        for sink in sinks
+            .iter_mut()
+            .filter(|sink| compatible(&source.0, &sink.0))
+        {
+            loop {
+                let amount = source.1.min(sink.1).min(max_amount);
+                if amount < min_amount {
+                    break;
+                }
+
+                matched.push((source.0.clone(), sink.0.clone(), amount));
+                source.1 -= amount;
+                sink.1 -= amount;
+            }
+        }
+    }
+
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        budget_refund_sat, plan_circular_rebalances, plan_rebalances, AutoRebalancePolicy,
+        CircularRebalancePolicy, RebalanceBand, RebalanceError,
+    };
+    use crate::cluster::{
+        ClusterChannel, ClusterLiquidity, ClusterLiquidityReport, ClusterRebalance,
+    };
+
+    fn liquidity(outbound: i64, inbound: i64) -> ClusterLiquidity {
+        ClusterLiquidity {
+            channels: 1,
+            active_channels: 1,
+            capacity: outbound + inbound,
+            outbound,
+            inbound,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_rebalances() {
+        let mut report = ClusterLiquidityReport::default();
+        report
+            .nodes
+            .insert("a".to_string(), liquidity(9_000_000, 1_000_000));
+        report
+            .nodes
+            .insert("b".to_string(), liquidity(500_000, 9_500_000));
+        report
+            .nodes
+            .insert("c".to_string(), liquidity(5_000_000, 5_000_000));
+
+        let policy = AutoRebalancePolicy {
+            max_amount_sat: 3_000_000,
+            ..Default::default()
+        };
+        let plans = plan_rebalances(&report, &policy);
+
+        // a has 4M above the midpoint and b is 4.5M below it
+        let amounts: Vec<i64> = plans.iter().map(|plan| plan.amount_sat).collect();
+        assert_eq!(amounts, vec![3_000_000, 1_000_000]);
+        assert!(plans.iter().all(|plan| plan.from == "a" && plan.to == "b"));
+        assert_eq!(plans[0].max_fee_sat, 1500);
+
+        // a wider band for b leaves it alone
+        let mut policy = policy;
+        policy.node_bands.insert(
+            "b".to_string(),
+            RebalanceBand {
+                min_local_ratio: 0.0,
+                max_local_ratio: 1.0,
+            },
+        );
+        assert!(plan_rebalances(&report, &policy).is_empty());
+    }
+
+    fn channel(
+        chan_id: &str,
+        peer: &str,
+        local_balance: i64,
+        remote_balance: i64,
+    ) -> ClusterChannel {
+        ClusterChannel {
+            pubkey: "a".to_string(),
+            peer: peer.to_string(),
+            chan_id: chan_id.to_string(),
+            channel_point: format!("ab01:{}", chan_id),
+            capacity: local_balance + remote_balance,
+            local_balance,
+            remote_balance,
+            active: true,
+            private: false,
+            fee_policy: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_circular_rebalances() {
+        let channels = vec![
+            channel("1", "02aa", 1_900_000, 100_000),
+            channel("2", "02bb", 100_000, 1_900_000),
+            channel("3", "02cc", 1_000_000, 1_000_000),
+            channel("4", "02bb", 2_000_000, 0),
+        ];
+        let plans = plan_circular_rebalances(&channels, &CircularRebalancePolicy::default());
+
+        // channel 4 has more to move but is itself a channel with 02bb
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].outgoing_chan_id, "1");
+        assert_eq!(plans[0].last_hop_pubkey, "02bb");
+        assert_eq!(plans[0].amount_sat, 900_000);
+        assert_eq!(plans[0].max_fee_sat, 450);
+    }
+
+    #[test]
+    fn test_budget_refund() {
+        let rebalance = |fee_sat| {
+            Ok(ClusterRebalance {
+                from: "a".to_string(),
+                to: "a".to_string(),
+                amount_sat: 100_000,
+                fee_sat,
+                payment_hash: "ab".to_string(),
+                preimage: "cd".to_string(),
+            })
+        };
+
+        assert_eq!(budget_refund_sat(50, &rebalance(Some(20))), 30);
+        assert_eq!(budget_refund_sat(50, &rebalance(None)), 0);
+
+        let failed = Err(
+            anyhow::Error::from(RebalanceError::Failed("no route".to_string()))
+                .context("Circular rebalance of a failed"),
+        );
+        assert_eq!(budget_refund_sat(50, &failed), 50);
+
+        let unknown = Err(anyhow::Error::from(RebalanceError::Unknown(
+            "timeout".to_string(),
+        )));
+        assert_eq!(budget_refund_sat(50, &unknown), 0);
+
+        let before_paying = Err(anyhow::anyhow!("Node has no channel 1"));
+        assert_eq!(budget_refund_sat(50, &before_paying), 50);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::cluster::{Cluster, Node};
+use bitcoin_hashes::{sha256, Hash};
+use lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+use secp256k1::{Secp256k1, SecretKey};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// Serves the handful of Redis commands the services send from memory.
+async fn serve_redis(listener: TcpListener) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut strings: HashMap<String, String> = HashMap::new();
+    let mut hashes: HashMap<(String, String), String> = HashMap::new();
+
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.unwrap() == 0 {
+            return;
+        }
+        let argc: usize = line.trim_end()[1..].parse().unwrap();
+        let mut args = vec![];
+        for _ in 0..argc {
+            line.clear();
+            stream.read_line(&mut line).await.unwrap();
+            let len: usize = line.trim_end()[1..].parse().unwrap();
+            let mut buf = vec![0; len + 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            args.push(String::from_utf8_lossy(&buf[..len]).to_string());
+        }
+
+        let bulk = |value: Option<String>| match value {
+            Some(value) => format!("${}\r\n{}\r\n", value.len(), value),
+            None => "$-1\r\n".to_string(),
+        };
+        let reply = match args[0].to_uppercase().as_str() {
+            "HSET" => {
+                hashes.insert((args[1].clone(), args[2].clone()), args[3].clone());
+                ":1\r\n".to_string()
+            }
+            "HGET" => bulk(hashes.get(&(args[1].clone(), args[2].clone())).cloned()),
+            "HDEL" => {
+                let removed = hashes.remove(&(args[1].clone(), args[2].clone()));
+                format!(":{}\r\n", removed.is_some() as i64)
+            }
+            "SET" => {
+                strings.insert(args[1].clone(), args[2].clone());
+                "+OK\r\n".to_string()
+            }
+            "SETEX" => {
+                strings.insert(args[1].clone(), args[3].clone());
+                "+OK\r\n".to_string()
+            }
+            "GET" => bulk(strings.get(&args[1]).cloned()),
+            "GETDEL" => bulk(strings.remove(&args[1])),
+            "INCRBY" | "DECRBY" => {
+                let delta: i64 = args[2].parse().unwrap();
+                let value = strings
+                    .entry(args[1].clone())
+                    .or_insert_with(|| "0".to_string());
+                let next = value.parse::<i64>().unwrap()
+                    + if args[0].eq_ignore_ascii_case("INCRBY") {
+                        delta
+                    } else {
+                        -delta
+                    };
+                *value = next.to_string();
+                format!(":{}\r\n", next)
+            }
+            "TTL" => ":-1\r\n".to_string(),
+            _ => "+OK\r\n".to_string(),
+        };
+        stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+    }
+}
+
+/// A cluster of `nodes` backed by `serve_redis`.
+pub async fn test_cluster(nodes: Vec<Node>) -> Cluster {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("redis://{}/", listener.local_addr().unwrap());
+    tokio::spawn(serve_redis(listener));
+
+    let cache = redis::Client::open(url)
+        .unwrap()
+        .get_async_connection()
+        .await
+        .unwrap();
+    Cluster::new(nodes, cache, 60, 60, 60)
+}
+
+pub fn test_invoice(amount_msat: Option<u64>) -> String {
+    let secp = Secp256k1::new();
+    let key = SecretKey::from_slice(&[42; 32]).unwrap();
+
+    let builder = InvoiceBuilder::new(Currency::Regtest)
+        .description(String::from("test"))
+        .payment_hash(sha256::Hash::from_byte_array([1; 32]))
+        .payment_secret(PaymentSecret([2; 32]))
+        .duration_since_epoch(Duration::from_secs(1_700_000_000))
+        .min_final_cltv_expiry_delta(144);
+    match amount_msat {
+        Some(amount_msat) => builder
+            .amount_milli_satoshis(amount_msat)
+            .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &key)),
+        None => builder.build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &key)),
+    }
+    .unwrap()
+    .to_string()
+}
@@ -0,0 +1,256 @@
+use crate::cluster::ClusterEvent;
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use redis::aio::Connection;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+pub const SIGNATURE_HEADER: &str = "X-Cluster-Signature";
+pub const DEAD_LETTER_KEY: &str = "webhook:dead_letter";
+
+/// Posts signed `ClusterEvent` payloads to the registered webhook URLs.
+pub struct WebhookDispatcher {
+    pub cache: Connection,
+    pub secret: String,
+    pub global_urls: Vec<String>,
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookDeadLetter {
+    pub url: String,
+    pub body: String,
+    pub attempts: u32,
+    pub error: String,
+}
+
+impl WebhookDispatcher {
+    pub fn new(cache: Connection, secret: String, global_urls: Vec<String>) -> WebhookDispatcher {
+        Self {
+            cache,
+            secret,
+            global_urls,
+            max_attempts: 5,
+            base_delay_ms: 500,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Dispatches every event received until the sender is dropped.
+    pub async fn run(mut self, mut events: UnboundedReceiver<ClusterEvent>) {
+        while let Some(event) = events.recv().await {
+            if let Err(e) = self.dispatch(&event).await {
+                eprintln!("failed to dispatch webhook: {}", e);
+            }
+        }
+    }
+
+    /// Delivers the event to the global URLs and to the URL registered for
+    /// its payment hash. Deliveries that exhaust their retries are pushed to
+    /// the dead-letter list. Every URL is tried even if recording a dead
+    /// letter fails, the first such error is returned.
+    pub async fn dispatch(&mut self, event: &ClusterEvent) -> Result<()> {
+        let mut urls = self.global_urls.clone();
+        if let Some(hash) = event.payment_hash() {
+            let url: Option<String> = self.cache.get(callback_key(hash)).await?;
+            urls.extend(url);
+        }
+
+        let body = serde_json::to_string(event)?;
+
+        let mut result = Ok(());
+        for url in urls {
+            if let Some(dead_letter) = self.deliver(&url, &body).await {
+                let pushed = self.push_dead_letter(&dead_letter).await;
+                if result.is_ok() {
+                    result = pushed;
+                }
+            }
+        }
+
+        result
+    }
+
+    async fn push_dead_letter(&mut self, dead_letter: &WebhookDeadLetter) -> Result<()> {
+        let json = serde_json::to_string(dead_letter)?;
+        let _: () = self.cache.lpush(DEAD_LETTER_KEY, json).await?;
+        Ok(())
+    }
+
+    pub async fn dead_letters(&mut self) -> Result<Vec<WebhookDeadLetter>> {
+        let entries: Vec<String> = self.cache.lrange(DEAD_LETTER_KEY, 0, -1).await?;
+
+        entries
+            .iter()
+            .map(|entry| serde_json::from_str(entry).map_err(anyhow::Error::from))
+            .collect()
+    }
+
+    /// Posts `body` to `url`, retrying with backoff. Returns the dead letter
+    /// to record when every attempt failed.
+    async fn deliver(&self, url: &str, body: &str) -> Option<WebhookDeadLetter> {
+        deliver(
+            &self.client,
+            &self.secret,
+            url,
+            body,
+            self.max_attempts,
+            self.base_delay_ms,
+        )
+        .await
+    }
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    secret: &str,
+    url: &str,
+    body: &str,
+    max_attempts: u32,
+    base_delay_ms: u64,
+) -> Option<WebhookDeadLetter> {
+    let signature = sign(secret, body);
+    let mut attempt = 0;
+
+    loop {
+        let result = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+            .body(body.to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        attempt += 1;
+        match result {
+            Ok(_) => return None,
+            Err(e) if attempt >= max_attempts => {
+                return Some(WebhookDeadLetter {
+                    url: url.to_string(),
+                    body: body.to_string(),
+                    attempts: attempt,
+                    error: e.to_string(),
+                })
+            }
+            Err(e) => {
+                eprintln!(
+                    "webhook delivery to {} failed (attempt {}): {}",
+                    url, attempt, e
+                );
+                tokio::time::sleep(backoff(base_delay_ms, attempt)).await;
+            }
+        }
+    }
+}
+
+/// Registers a callback URL for a single invoice or payment hash.
+pub async fn register_callback(
+    cache: &mut Connection,
+    hash: &str,
+    url: &str,
+    exp_sec: usize,
+) -> Result<()> {
+    let _: () = cache.set_ex(callback_key(hash), url, exp_sec).await?;
+    Ok(())
+}
+
+/// Hex encoded HMAC-SHA256 of the request body.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(body.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn backoff(base_delay_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(base_delay_ms.saturating_mul(1 << (attempt - 1).min(16)))
+}
+
+fn callback_key(hash: &str) -> String {
+    format!("webhook:{}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, deliver, sign, SIGNATURE_HEADER};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves one HTTP status per request from `statuses`, repeating the
+    /// last one, and counts the requests that carried a signature.
+    async fn serve(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let signed = Arc::new(AtomicUsize::new(0));
+
+        let counter = signed.clone();
+        tokio::spawn(async move {
+            let mut served = 0;
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                if request.contains(&format!("{}: sha256=", SIGNATURE_HEADER.to_lowercase())) {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+
+                let status = statuses[served.min(statuses.len() - 1)];
+                served += 1;
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (url, signed)
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries() {
+        let client = reqwest::Client::new();
+        let (url, signed) = serve(vec![500, 503, 200]).await;
+
+        let dead_letter = deliver(&client, "secret", &url, "{}", 5, 1).await;
+        assert!(dead_letter.is_none());
+        assert_eq!(signed.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_dead_letter() {
+        let client = reqwest::Client::new();
+        let (url, signed) = serve(vec![500]).await;
+
+        let dead_letter = deliver(&client, "secret", &url, r#"{"type":"x"}"#, 3, 1)
+            .await
+            .unwrap();
+        assert_eq!(signed.load(Ordering::SeqCst), 3);
+        assert_eq!(dead_letter.url, url);
+        assert_eq!(dead_letter.body, r#"{"type":"x"}"#);
+        assert_eq!(dead_letter.attempts, 3);
+        assert!(dead_letter.error.contains("500"));
+    }
+
+    #[test]
+    fn test_sign_and_backoff() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        assert_eq!(backoff(500, 1), Duration::from_millis(500));
+        assert_eq!(backoff(500, 3), Duration::from_millis(2000));
+    }
+}
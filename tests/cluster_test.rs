@@ -1,6 +1,10 @@
 mod tests {
     use lightning_cluster::{
-        cluster::{Cluster, ClusterAddInvoice, Node, NodeClient, NodeLightningImpl, NodeNetwork},
+        cluster::{
+            Cluster, ClusterAddInvoice, ClusterNewAddress, Node, NodeClient, NodeLightningImpl,
+            NodeNetwork,
+        },
+        fees::FeePolicy,
         lnd::LndClient,
     };
 
@@ -30,6 +34,7 @@ mod tests {
             memo: String::from("test"),
             value: 1000,
             expiry: 1000,
+            ..Default::default()
         };
 
         let invoice = cluster.add_invoice(req, None).await.unwrap();
@@ -41,7 +46,7 @@ mod tests {
 
         println!("2nd get from cache: {:?}", get_invoice); // from cache
 
-        let next_addr = cluster.next_address(None).await.unwrap();
+        let next_addr = cluster.next_address(None, ClusterNewAddress::default()).await.unwrap();
 
         println!("{:?}", next_addr);
 
@@ -54,7 +59,7 @@ mod tests {
         let payment_request = String::from("lntb10u1pjva6sepp5lqz5lysxd7vu7h3nqzj3lem544uqmvec5k53cp2msm2lvnw0s9zqdqqcqzzsxqr23ssp5dysff7u8n2w7f0x5gysmlze7zw3fg05f2e2q24tzh8vanfnt5nss9qyyssqtcashms9q6dmt4ywja8jrtkztzr5kr5k24wa8mdxs00fgxq76d9zvs6styvhuxc5pvdcrs4m89r4rmvkp6lvc7tr959cds7na7k63vcplqfzxx");
 
         let _ = cluster
-            .pay_invoice(1000, payment_request, 100, None)
+            .pay_invoice(1000, payment_request, Some(FeePolicy::fixed(100)), None, None)
             .await
             .unwrap();
 